@@ -0,0 +1,75 @@
+use std::{hint::black_box, path::PathBuf};
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use image::{ImageEncoder, RgbImage, codecs::jpeg::JpegEncoder};
+
+use crate::{jpeg::ScaledJpeg, quality::ANALYSIS_SIZE};
+
+// The server is a binary crate, so the modules are compiled into the bench
+// with the same paths as in the server
+#[allow(dead_code)]
+#[path = "../src/thumbnail/jpeg.rs"]
+mod jpeg;
+#[allow(dead_code)]
+#[path = "../src/thumbnail/quality.rs"]
+mod quality;
+
+/// Compares the full decoding with the DCT scaled one. A real photo can be
+/// given in `MOSAIC_BENCH_JPEG`, otherwise a 24 MP image is generated.
+fn thumbnail_benchmark(c: &mut Criterion) {
+    let plain = test_image();
+    let mut group = c.benchmark_group("thumbnail");
+
+    group.sample_size(10);
+
+    group.bench_function("full_decode", |b| {
+        b.iter(|| image::open(black_box(&plain)).unwrap().thumbnail(256, 256))
+    });
+
+    group.bench_function("dct_scaled_analysis_size", |b| {
+        b.iter(|| {
+            ScaledJpeg::from_path(black_box(&plain), ANALYSIS_SIZE)
+                .unwrap()
+                .image
+                .thumbnail(256, 256)
+        })
+    });
+
+    group.bench_function("dct_scaled_thumbnail_size", |b| {
+        b.iter(|| {
+            ScaledJpeg::from_path(black_box(&plain), 256)
+                .unwrap()
+                .image
+                .thumbnail(256, 256)
+        })
+    });
+
+    group.finish();
+}
+
+fn test_image() -> PathBuf {
+    if let Some(path) = std::env::var_os("MOSAIC_BENCH_JPEG") {
+        return PathBuf::from(path);
+    }
+
+    let path = std::env::temp_dir().join("mosaic_bench.jpg");
+    let img = RgbImage::from_fn(6000, 4000, |x, y| {
+        image::Rgb([(x / 24) as u8, (y / 16) as u8, ((x ^ y) & 0xFF) as u8])
+    });
+
+    std::fs::write(&path, encode_jpeg(&img)).unwrap();
+    path
+}
+
+fn encode_jpeg(img: &RgbImage) -> Vec<u8> {
+    let mut jpeg = vec![];
+
+    JpegEncoder::new_with_quality(&mut jpeg, 85)
+        .write_image(img, img.width(), img.height(), image::ExtendedColorType::Rgb8)
+        .unwrap();
+
+    jpeg
+}
+
+criterion_group!(benches, thumbnail_benchmark);
+criterion_main!(benches);
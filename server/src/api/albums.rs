@@ -0,0 +1,428 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, MutexGuard},
+};
+
+use axum::{Json, body::Body, extract, response::Response};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AppState,
+    api::{
+        collect_thumbnails, error_response, escape_html, escape_url, json_response,
+        serve_gallery_page,
+    },
+    auth::CurrentUser,
+    catalog::store::{Album, Catalog},
+    config::Role,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct NewAlbum {
+    pub name: String,
+    #[serde(default)]
+    pub images: Vec<String>,
+}
+
+/// Changes an album. The image list is replaced if `images` is given, then
+/// the `add` images are appended and the `remove` images are taken out.
+#[derive(Debug, Deserialize)]
+pub struct AlbumUpdate {
+    pub name: Option<String>,
+    pub images: Option<Vec<String>>,
+    #[serde(default)]
+    pub add: Vec<String>,
+    #[serde(default)]
+    pub remove: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AlbumSummary {
+    pub name: String,
+    pub count: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AlbumDetails {
+    pub name: String,
+    pub images: Vec<String>,
+}
+
+pub async fn list_albums(state: Arc<AppState>) -> Response<Body> {
+    json_response(200, &album_summaries(&state))
+}
+
+pub async fn create_album(
+    extract::Extension(user): extract::Extension<CurrentUser>,
+    state: Arc<AppState>,
+    Json(request): Json<NewAlbum>,
+) -> Response<Body> {
+    if let Some(response) = forbidden_album_change(&state, &user) {
+        return response;
+    }
+
+    let name = request.name.trim().to_owned();
+
+    if !is_valid_name(&name) {
+        return error_response(400, format!("Invalid album name: {name}"));
+    }
+
+    let images = match to_paths(&state, &request.images) {
+        Ok(images) => images,
+        Err((status, message)) => return error_response(status, message),
+    };
+
+    let mut catalogs = lock_catalogs(&state);
+
+    if catalogs.iter().any(|c| c.albums.contains_key(&name)) {
+        return error_response(409, format!("Album already exists: {name}"));
+    }
+
+    info!("Creating album {name} with {} images", images.len());
+
+    if let Err(e) = store_album(&state, &mut catalogs, &name, &images) {
+        return error_response(500, e);
+    }
+
+    json_response(201, &AlbumDetails { name, images })
+}
+
+pub async fn get_album(
+    extract::Path(name): extract::Path<String>,
+    state: Arc<AppState>,
+) -> Response<Body> {
+    let catalogs = lock_catalogs(&state);
+
+    match album_paths(&state, &catalogs, &name) {
+        Some(images) => json_response(200, &AlbumDetails { name, images }),
+        None => error_response(404, format!("Album not found: {name}")),
+    }
+}
+
+pub async fn update_album(
+    extract::Path(name): extract::Path<String>,
+    extract::Extension(user): extract::Extension<CurrentUser>,
+    state: Arc<AppState>,
+    Json(update): Json<AlbumUpdate>,
+) -> Response<Body> {
+    if let Some(response) = forbidden_album_change(&state, &user) {
+        return response;
+    }
+
+    let images = match &update.images {
+        Some(images) => match to_paths(&state, images) {
+            Ok(images) => Some(images),
+            Err((status, message)) => return error_response(status, message),
+        },
+        None => None,
+    };
+
+    let add = match to_paths(&state, &update.add) {
+        Ok(add) => add,
+        Err((status, message)) => return error_response(status, message),
+    };
+
+    // Images to remove don't have to exist any more
+    let remove: Vec<String> = update
+        .remove
+        .iter()
+        .filter_map(|i| {
+            let (library, path) = state.resolve(i)?;
+
+            Some(library.server_path(&library.context().to_relative_key(path)?))
+        })
+        .collect();
+
+    let mut catalogs = lock_catalogs(&state);
+
+    let Some(mut album_images) = album_paths(&state, &catalogs, &name) else {
+        return error_response(404, format!("Album not found: {name}"));
+    };
+
+    let new_name = update
+        .name
+        .as_deref()
+        .map(str::trim)
+        .unwrap_or(&name)
+        .to_owned();
+
+    if new_name != name
+        && (!is_valid_name(&new_name) || catalogs.iter().any(|c| c.albums.contains_key(&new_name)))
+    {
+        return error_response(409, format!("Cannot rename album to {new_name}"));
+    }
+
+    if let Some(images) = images {
+        album_images = images;
+    }
+
+    for image in add {
+        if !album_images.contains(&image) {
+            album_images.push(image);
+        }
+    }
+
+    album_images.retain(|i| !remove.contains(i));
+
+    debug!("Album {new_name} updated: {album_images:?}");
+
+    if new_name != name
+        && let Err(e) = remove_album(&mut catalogs, &name)
+    {
+        return error_response(500, e);
+    }
+
+    if let Err(e) = store_album(&state, &mut catalogs, &new_name, &album_images) {
+        return error_response(500, e);
+    }
+
+    json_response(
+        200,
+        &AlbumDetails {
+            name: new_name,
+            images: album_images,
+        },
+    )
+}
+
+pub async fn delete_album(
+    extract::Path(name): extract::Path<String>,
+    extract::Extension(user): extract::Extension<CurrentUser>,
+    state: Arc<AppState>,
+) -> Response<Body> {
+    if let Some(response) = forbidden_album_change(&state, &user) {
+        return response;
+    }
+
+    let mut catalogs = lock_catalogs(&state);
+
+    match remove_album(&mut catalogs, &name) {
+        Ok(true) => {
+            info!("Deleted album {name}");
+
+            json_response(200, &name)
+        }
+        Ok(false) => error_response(404, format!("Album not found: {name}")),
+        Err(e) => error_response(500, e),
+    }
+}
+
+/// Serves an album as a virtual folder: `/albums/` lists the albums,
+/// `/albums/{name}/` is the gallery page which loads
+/// `/albums/{name}/bundles.json`.
+pub async fn serve_album_view(
+    extract::Path(path): extract::Path<String>,
+    state: Arc<AppState>,
+) -> Response<Body> {
+    let path = path.trim_start_matches('/');
+
+    if path.is_empty() {
+        return list_album_links(&state);
+    }
+
+    match path.strip_suffix("/bundles.json") {
+        Some(name) => {
+            let Some(images) = album_images(&state, name) else {
+                return error_response(404, format!("Album not found: {name}"));
+            };
+
+            json_response(
+                200,
+                &collect_thumbnails(&state, images.iter().map(String::as_str)),
+            )
+        }
+        None => serve_gallery_page(&state),
+    }
+}
+
+/// The images of the album which still exist. The ones removed outside of the
+/// server are pruned from the album.
+pub fn album_images(state: &AppState, name: &str) -> Option<Vec<String>> {
+    let mut catalogs = lock_catalogs(state);
+
+    for (library, catalog) in state.libraries.iter().zip(catalogs.iter_mut()) {
+        let Some(album) = catalog.albums.get_mut(name) else {
+            continue;
+        };
+        let count = album.images.len();
+
+        album.images.retain(|i| library.root.join(i).is_file());
+
+        if album.images.len() != count {
+            info!(
+                "Pruned {} missing images from album {name} in library {}",
+                count - album.images.len(),
+                library.name
+            );
+
+            if let Err(e) = catalog.save() {
+                error!("{e}");
+            }
+        }
+    }
+
+    album_paths(state, &catalogs, name)
+}
+
+/// Every catalog of the libraries, locked in the order of the libraries so
+/// that concurrent requests cannot deadlock.
+fn lock_catalogs(state: &AppState) -> Vec<MutexGuard<'_, Catalog>> {
+    state
+        .libraries
+        .iter()
+        .map(|l| l.catalog.lock().unwrap())
+        .collect()
+}
+
+/// An album is kept in the catalogs of the libraries of its images, each
+/// part with the keys of its library. The parts are joined in the order of
+/// the libraries into paths starting with the library name.
+fn album_paths(
+    state: &AppState,
+    catalogs: &[MutexGuard<'_, Catalog>],
+    name: &str,
+) -> Option<Vec<String>> {
+    let mut found = false;
+    let mut images = vec![];
+
+    for (library, catalog) in state.libraries.iter().zip(catalogs) {
+        if let Some(album) = catalog.albums.get(name) {
+            found = true;
+            images.extend(album.images.iter().map(|i| library.server_path(i)));
+        }
+    }
+
+    found.then_some(images)
+}
+
+/// Splits the album into the catalogs of the libraries of its images. An
+/// empty album is kept in the first library.
+fn store_album(
+    state: &AppState,
+    catalogs: &mut [MutexGuard<'_, Catalog>],
+    name: &str,
+    images: &[String],
+) -> Result<(), String> {
+    let mut stored = false;
+
+    for (library, catalog) in state.libraries.iter().zip(catalogs.iter_mut()) {
+        let keys: Vec<String> = images
+            .iter()
+            .filter_map(|i| i.strip_prefix(&format!("{}/", library.name)))
+            .map(str::to_owned)
+            .collect();
+
+        if !keys.is_empty() {
+            catalog
+                .albums
+                .insert(name.to_owned(), Album { images: keys });
+            catalog.save()?;
+
+            stored = true;
+        } else if catalog.albums.remove(name).is_some() {
+            catalog.save()?;
+        }
+    }
+
+    if !stored && let Some(catalog) = catalogs.first_mut() {
+        catalog
+            .albums
+            .insert(name.to_owned(), Album { images: vec![] });
+        catalog.save()?;
+    }
+
+    Ok(())
+}
+
+/// Removes the parts of the album from all the catalogs, returns false if
+/// there was no such album.
+fn remove_album(catalogs: &mut [MutexGuard<'_, Catalog>], name: &str) -> Result<bool, String> {
+    let mut found = false;
+
+    for catalog in catalogs {
+        if catalog.albums.remove(name).is_some() {
+            catalog.save()?;
+
+            found = true;
+        }
+    }
+
+    Ok(found)
+}
+
+fn list_album_links(state: &AppState) -> Response<Body> {
+    let mut html = String::from("<html><body><a href=\"/serve/\">Library</a><br/><br/>");
+
+    for AlbumSummary { name, count } in album_summaries(state) {
+        html.push_str(&format!(
+            "<a href=\"/albums/{}/\">{}</a> ({count})<br/>",
+            escape_url(&name),
+            escape_html(&name)
+        ));
+    }
+
+    html.push_str("</body></html>");
+
+    Response::builder()
+        .header("Content-Type", "text/html")
+        .body(Body::from(html))
+        .unwrap()
+}
+
+fn album_summaries(state: &AppState) -> Vec<AlbumSummary> {
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+
+    for catalog in lock_catalogs(state) {
+        for (name, album) in &catalog.albums {
+            *counts.entry(name.clone()).or_default() += album.images.len();
+        }
+    }
+
+    counts
+        .into_iter()
+        .map(|(name, count)| AlbumSummary { name, count })
+        .collect()
+}
+
+/// Normalizes the image paths, all of them have to be existing files of a
+/// library. Returns the status and the message of the error response
+/// otherwise.
+fn to_paths(state: &AppState, images: &[String]) -> Result<Vec<String>, (u16, String)> {
+    let mut paths = vec![];
+
+    for image in images {
+        let Some((library, path)) = state.resolve(image) else {
+            return Err((404, format!("Not found: {image}")));
+        };
+
+        match library.context().to_relative_key(path) {
+            Some(key) if library.root.join(&key).is_file() => {
+                let path = library.server_path(&key);
+
+                if !paths.contains(&path) {
+                    paths.push(path);
+                }
+            }
+            Some(_) => return Err((404, format!("File not found: {image}"))),
+            None => return Err((400, format!("Invalid path: {image}"))),
+        }
+    }
+
+    Ok(paths)
+}
+
+/// The albums are shared by the libraries, so the rules of the libraries
+/// don't apply to them, only the role of the user.
+pub fn forbidden_album_change(state: &AppState, user: &CurrentUser) -> Option<Response<Body>> {
+    if state.role(user, "") >= Role::Editor {
+        None
+    } else {
+        Some(error_response(403, "Permission denied".to_owned()))
+    }
+}
+
+/// Album names are used in the URL of the album view as one path segment.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains('/')
+}
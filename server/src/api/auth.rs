@@ -0,0 +1,693 @@
+use std::sync::Arc;
+
+use axum::{
+    Form,
+    body::Body,
+    extract::{self, Request},
+    middleware::Next,
+    response::Response,
+};
+use http::{HeaderMap, Method, header};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AppState,
+    api::{cookie, error_response, escape_html, escape_url, json_response},
+    auth::CurrentUser,
+    config::Role,
+};
+
+/// The name of the cookie with the session token.
+const SESSION_COOKIE: &str = "mosaic_session";
+
+#[derive(Debug, Deserialize)]
+pub struct LoginParams {
+    /// Where to go after the login.
+    #[serde(default)]
+    pub next: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PermissionParams {
+    /// A path starting with the name of the library, the virtual folders
+    /// like the search results get the role of the user outside of the rules.
+    #[serde(default)]
+    pub path: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Permissions {
+    pub user: Option<String>,
+    pub role: Role,
+    /// Nothing can be changed in a read-only library, whatever the role.
+    pub read_only: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginForm {
+    pub username: String,
+    pub password: String,
+    #[serde(default)]
+    pub next: String,
+}
+
+pub async fn login_page(
+    extract::Query(params): extract::Query<LoginParams>,
+    state: Arc<AppState>,
+) -> Response<Body> {
+    if state.auth.is_none() {
+        return redirect(&safe_next(&params.next), None);
+    }
+
+    login_form(200, &params.next, None)
+}
+
+pub async fn login(state: Arc<AppState>, Form(form): Form<LoginForm>) -> Response<Body> {
+    let Some(auth) = &state.auth else {
+        return redirect(&safe_next(&form.next), None);
+    };
+
+    let authenticated = tokio::task::spawn_blocking({
+        let task_state = Arc::clone(&state);
+        let (username, password) = (form.username.clone(), form.password.clone());
+
+        move || {
+            task_state
+                .auth
+                .as_ref()
+                .is_some_and(|auth| auth.authenticate(&username, &password))
+        }
+    })
+    .await
+    .unwrap_or(false);
+
+    if !authenticated {
+        warn!("Failed login of user {}", form.username);
+
+        return login_form(401, &form.next, Some("Wrong user name or password"));
+    }
+
+    let token = auth.login(&form.username);
+    let cookie = format!(
+        "{SESSION_COOKIE}={token}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}",
+        auth.session_ttl().as_secs()
+    );
+
+    redirect(&safe_next(&form.next), Some(cookie))
+}
+
+pub async fn logout(headers: HeaderMap, state: Arc<AppState>) -> Response<Body> {
+    if let Some(auth) = &state.auth
+        && let Some(token) = session_token(&headers)
+        && let Some(session) = auth.logout(token)
+    {
+        info!("User {} logged out", session.user);
+    }
+
+    let cookie = format!("{SESSION_COOKIE}=; Path=/; HttpOnly; SameSite=Lax; Max-Age=0");
+
+    redirect("/login", Some(cookie))
+}
+
+/// Lets through the requests with a valid session and adds their user to
+/// them. Without one the pages redirect to the login page, the API calls and
+/// the changes just fail. The changes only come from the pages of this
+/// server, so another site cannot make the browser send them with the
+/// session cookie.
+pub async fn require_login(
+    mut request: Request,
+    next: Next,
+    state: Arc<AppState>,
+) -> Response<Body> {
+    if !is_safe_method(request.method()) && is_cross_site(request.headers()) {
+        warn!(
+            "Refused cross-site {} {}",
+            request.method(),
+            request.uri().path()
+        );
+
+        return error_response(403, "Cross-site request".to_owned());
+    }
+
+    let Some(auth) = &state.auth else {
+        request.extensions_mut().insert(CurrentUser::default());
+
+        return next.run(request).await;
+    };
+
+    let path = request.uri().path();
+
+    // The share links check their own token
+    if path == "/login" || path.starts_with("/s/") {
+        return next.run(request).await;
+    }
+
+    let session = session_token(request.headers()).and_then(|token| auth.session(token));
+
+    if let Some(session) = session {
+        request.extensions_mut().insert(CurrentUser {
+            name: Some(session.user),
+        });
+
+        return next.run(request).await;
+    }
+
+    if request.method() == Method::GET && !path.starts_with("/api/") {
+        let target = request.uri().path_and_query().map_or(path, |p| p.as_str());
+
+        return redirect(&format!("/login?next={}", escape_url(target)), None);
+    }
+
+    error_response(401, "Login required".to_owned())
+}
+
+/// The role of the user in a directory, the gallery hides the controls the
+/// user cannot use.
+pub async fn permissions(
+    extract::Query(params): extract::Query<PermissionParams>,
+    extract::Extension(user): extract::Extension<CurrentUser>,
+    state: Arc<AppState>,
+) -> Response<Body> {
+    let path = params.path.trim_matches('/');
+    let library = state.resolve(path).map(|(library, _)| library);
+
+    json_response(
+        200,
+        &Permissions {
+            role: state.role(&user, path),
+            read_only: library.is_some_and(|l| l.read_only),
+            user: user.name,
+        },
+    )
+}
+
+fn login_form(status: u16, next: &str, error: Option<&str>) -> Response<Body> {
+    let mut html = String::from("<html><body><form method=\"post\" action=\"/login\">");
+
+    if let Some(error) = error {
+        html.push_str(&format!("<p>{}</p>", escape_html(error)));
+    }
+
+    html.push_str(&format!(
+        "<input type=\"hidden\" name=\"next\" value=\"{}\"/>",
+        escape_html(next)
+    ));
+    html.push_str("<label>User <input name=\"username\" autofocus/></label><br/>");
+    html.push_str("<label>Password <input name=\"password\" type=\"password\"/></label><br/>");
+    html.push_str("<button type=\"submit\">Log in</button></form></body></html>");
+
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "text/html")
+        .body(Body::from(html))
+        .unwrap()
+}
+
+fn redirect(location: &str, cookie: Option<String>) -> Response<Body> {
+    let mut response = Response::builder()
+        .status(303)
+        .header(header::LOCATION, location);
+
+    if let Some(cookie) = cookie {
+        response = response.header(header::SET_COOKIE, cookie);
+    }
+
+    response.body(Body::empty()).unwrap()
+}
+
+/// Only the paths of this server are followed after the login, anything else
+/// goes to the start page.
+fn safe_next(next: &str) -> String {
+    if next.starts_with('/') && !next.starts_with("//") && !next.contains('\\') {
+        next.to_owned()
+    } else {
+        "/".to_owned()
+    }
+}
+
+fn is_safe_method(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+/// True if the browser says the request comes from a page of another origin.
+/// Clients like curl send no `Origin`, they are not at risk.
+fn is_cross_site(headers: &HeaderMap) -> bool {
+    let Some(origin) = headers.get(header::ORIGIN) else {
+        return false;
+    };
+
+    let origin_host = origin
+        .to_str()
+        .ok()
+        .and_then(|o| o.split_once("://"))
+        .map(|(_, host)| host);
+    let host = headers.get(header::HOST).and_then(|h| h.to_str().ok());
+
+    origin_host.is_none() || origin_host != host
+}
+
+fn session_token(headers: &HeaderMap) -> Option<&str> {
+    cookie(headers, SESSION_COOKIE)
+}
+
+#[cfg(test)]
+mod tests {
+    use argon2::{Algorithm, Argon2, Params, PasswordHasher, Version, password_hash::SaltString};
+    use axum::{Router, body::to_bytes};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        api::test_helpers::{TempDir, get, json_request, post},
+        auth,
+        config::{AuthConfig, Config, RuleConfig, ShareConfig, UserConfig},
+    };
+
+    /// A cheap hash, the default parameters are slow in the debug build.
+    fn test_hash(password: &str) -> String {
+        let params = Params::new(Params::MIN_M_COST, 1, 1, None).unwrap();
+        let salt = SaltString::from_b64("c2FsdHNhbHRzYWx0").unwrap();
+
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password(password.as_bytes(), &salt)
+            .unwrap()
+            .to_string()
+    }
+
+    fn app(dir: &TempDir, auth: Option<AuthConfig>) -> Router {
+        let config = Config {
+            root_directory: dir.path().to_string_lossy().into_owned(),
+            auth,
+            ..Config::default()
+        };
+        let (state, _) = AppState::new(config).unwrap();
+
+        crate::app(Arc::new(state))
+    }
+
+    /// An editor, a viewer and an admin, all with the password `secret`.
+    /// Nobody can change `main/locked`, the viewer can change `main/shared`.
+    fn auth_config() -> AuthConfig {
+        let user = |name: &str, role| UserConfig {
+            name: name.to_owned(),
+            password_hash: test_hash("secret"),
+            role,
+        };
+
+        AuthConfig {
+            users: vec![
+                user("alice", Role::Editor),
+                user("victor", Role::Viewer),
+                user("ada", Role::Admin),
+            ],
+            users_file: None,
+            session_hours: 1,
+            rules: vec![
+                RuleConfig {
+                    path: "main/locked".to_owned(),
+                    role: Role::Viewer,
+                    users: vec![],
+                },
+                RuleConfig {
+                    path: "main/shared".to_owned(),
+                    role: Role::Editor,
+                    users: vec!["victor".to_owned()],
+                },
+            ],
+        }
+    }
+
+    /// Logs the user in, returns the session cookie.
+    async fn login_cookie(app: &Router, username: &str) -> String {
+        let response = app
+            .clone()
+            .oneshot(login_request(username, "secret", "/"))
+            .await
+            .unwrap();
+
+        session_cookie(&response)
+    }
+
+    fn login_request(username: &str, password: &str, next: &str) -> Request {
+        Request::builder()
+            .method(Method::POST)
+            .uri("/login")
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(format!(
+                "username={username}&password={password}&next={}",
+                escape_url(next)
+            )))
+            .unwrap()
+    }
+
+    fn location(response: &Response<Body>) -> &str {
+        response.headers()[header::LOCATION].to_str().unwrap()
+    }
+
+    /// The `name=value` part of the Set-Cookie header.
+    fn session_cookie(response: &Response<Body>) -> String {
+        let cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+
+        cookie.split(';').next().unwrap().to_owned()
+    }
+
+    #[tokio::test]
+    async fn everything_is_open_without_auth() {
+        let dir = TempDir::new("open");
+        let app = app(&dir, None);
+
+        let response = app.clone().oneshot(get("/serve/", None)).await.unwrap();
+        assert_eq!(response.status(), 200);
+
+        let response = app.oneshot(get("/api/tags", None)).await.unwrap();
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn pages_redirect_to_login() {
+        let dir = TempDir::new("redirect");
+        let app = app(&dir, Some(auth_config()));
+
+        let response = app.clone().oneshot(get("/serve/", None)).await.unwrap();
+        assert_eq!(response.status(), 303);
+        assert_eq!(location(&response), "/login?next=%2Fserve%2F");
+
+        let response = app.oneshot(get("/login", None)).await.unwrap();
+        assert_eq!(response.status(), 200);
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("name=\"password\""));
+    }
+
+    #[tokio::test]
+    async fn api_requires_session() {
+        let dir = TempDir::new("api");
+        let app = app(&dir, Some(auth_config()));
+
+        let response = app.clone().oneshot(get("/api/tags", None)).await.unwrap();
+        assert_eq!(response.status(), 401);
+
+        let response = app
+            .oneshot(get("/api/tags", Some("mosaic_session=forged")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 401);
+    }
+
+    #[tokio::test]
+    async fn wrong_password_is_rejected() {
+        let dir = TempDir::new("wrong");
+        let app = app(&dir, Some(auth_config()));
+
+        for (username, password) in [("alice", "guess"), ("bob", "secret")] {
+            let response = app
+                .clone()
+                .oneshot(login_request(username, password, "/"))
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), 401);
+            assert!(!response.headers().contains_key(header::SET_COOKIE));
+        }
+    }
+
+    #[tokio::test]
+    async fn login_and_logout() {
+        let dir = TempDir::new("login");
+        let app = app(&dir, Some(auth_config()));
+
+        let response = app
+            .clone()
+            .oneshot(login_request("alice", "secret", "/api/tags"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 303);
+        assert_eq!(location(&response), "/api/tags");
+
+        let cookie = session_cookie(&response);
+
+        let response = app
+            .clone()
+            .oneshot(get("/api/tags", Some(&cookie)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+
+        let response = app
+            .clone()
+            .oneshot(get("/logout", Some(&cookie)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 303);
+        assert_eq!(session_cookie(&response), "mosaic_session=");
+
+        let response = app.oneshot(get("/api/tags", Some(&cookie))).await.unwrap();
+        assert_eq!(response.status(), 401);
+    }
+
+    #[tokio::test]
+    async fn login_only_redirects_to_own_pages() {
+        let dir = TempDir::new("next");
+        let app = app(&dir, Some(auth_config()));
+
+        let response = app
+            .oneshot(login_request("alice", "secret", "//example.com/"))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 303);
+        assert_eq!(location(&response), "/");
+    }
+
+    #[tokio::test]
+    async fn users_file_users_can_log_in() {
+        let dir = TempDir::new("users");
+        let users_file = dir.join("users.toml");
+
+        auth::set_password(&users_file, "bob", "hunter2", None).unwrap();
+
+        let config = AuthConfig {
+            users_file: Some(users_file.to_string_lossy().into_owned()),
+            ..auth_config()
+        };
+
+        assert!(auth::check_config(&config, &["main".to_owned()], true).is_empty());
+
+        let response = app(&dir, Some(config))
+            .oneshot(login_request("bob", "hunter2", "/"))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 303);
+    }
+
+    #[tokio::test]
+    async fn viewers_cannot_change_anything() {
+        let dir = TempDir::new("viewer");
+        let app = app(&dir, Some(auth_config()));
+        let cookie = login_cookie(&app, "victor").await;
+        let file = dir.image("a/one.jpg");
+
+        let response = app
+            .clone()
+            .oneshot(post(&format!("/delete/{file}"), Some(&cookie)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 403);
+        assert!(dir.join("a/one.jpg").exists());
+
+        let response = app
+            .clone()
+            .oneshot(post("/sync/main/a", Some(&cookie)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 403);
+
+        let response = app
+            .oneshot(get("/serve/main/", Some(&cookie)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(!String::from_utf8_lossy(&body).contains("/sync/"));
+    }
+
+    #[tokio::test]
+    async fn rules_change_the_role_under_their_path() {
+        let dir = TempDir::new("rules");
+        let app = app(&dir, Some(auth_config()));
+        let alice = login_cookie(&app, "alice").await;
+        let victor = login_cookie(&app, "victor").await;
+        let ada = login_cookie(&app, "ada").await;
+
+        // Also through a path which only matches the rule once normalized
+        let locked = dir.image("locked/one.jpg");
+        let response = app
+            .clone()
+            .oneshot(post("/delete/main//locked/one.jpg", Some(&alice)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 403);
+
+        let response = app
+            .clone()
+            .oneshot(post(&format!("/delete/{locked}"), Some(&ada)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+
+        let shared = dir.image("shared/one.jpg");
+        let response = app
+            .clone()
+            .oneshot(post(&format!("/delete/{shared}"), Some(&victor)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn batch_delete_reports_forbidden_files() {
+        let dir = TempDir::new("batch");
+        let app = app(&dir, Some(auth_config()));
+        let cookie = login_cookie(&app, "alice").await;
+        let allowed = dir.image("a/one.jpg");
+        let locked = dir.image("locked/one.jpg");
+
+        let request = json_request(
+            Method::POST,
+            "/delete",
+            Some(&cookie),
+            &format!("[\"{allowed}\", \"{locked}\"]"),
+        );
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), 200);
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let result: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(result["deleted"], serde_json::json!([allowed]));
+        assert_eq!(
+            result["failed"],
+            serde_json::json!([[locked, "Permission denied"]])
+        );
+
+        assert!(!dir.join("a/one.jpg").exists());
+        assert!(dir.join("locked/one.jpg").exists());
+    }
+
+    #[tokio::test]
+    async fn permissions_follow_the_rules() {
+        let dir = TempDir::new("permissions");
+        let app = app(&dir, Some(auth_config()));
+        let cookie = login_cookie(&app, "victor").await;
+
+        for (path, role) in [("main/a", "viewer"), ("main/shared/2024", "editor")] {
+            let response = app
+                .clone()
+                .oneshot(get(
+                    &format!("/api/permissions?path={}", escape_url(path)),
+                    Some(&cookie),
+                ))
+                .await
+                .unwrap();
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let permissions: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+            assert_eq!(permissions["user"], "victor");
+            assert_eq!(permissions["role"], role);
+        }
+    }
+
+    #[tokio::test]
+    async fn share_links_need_no_login() {
+        let dir = TempDir::new("share");
+        let config = Config {
+            root_directory: dir.path().to_string_lossy().into_owned(),
+            auth: Some(auth_config()),
+            shares: Some(ShareConfig {
+                secret_file: Some(dir.join(".secret").to_string_lossy().into_owned()),
+                revoked_file: Some(dir.join(".revoked").to_string_lossy().into_owned()),
+                max_hours: 1,
+            }),
+            ..Config::default()
+        };
+        let app = crate::app(Arc::new(AppState::new(config).unwrap().0));
+        let file = dir.image("album/one.jpg");
+        let share = |cookie: &str| {
+            Request::builder()
+                .method(Method::POST)
+                .uri("/api/shares")
+                .header(header::COOKIE, cookie)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(r#"{"path": "main/album"}"#))
+                .unwrap()
+        };
+
+        // Sharing is an edit, viewers cannot
+        let cookie = login_cookie(&app, "victor").await;
+        let response = app.clone().oneshot(share(&cookie)).await.unwrap();
+        assert_eq!(response.status(), 403);
+
+        let cookie = login_cookie(&app, "alice").await;
+        let response = app.clone().oneshot(share(&cookie)).await.unwrap();
+        assert_eq!(response.status(), 201);
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let created: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let url = created["url"].as_str().unwrap();
+
+        let response = app
+            .clone()
+            .oneshot(get(&format!("{url}/serve/one.jpg"), None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+
+        // The link does not open the rest of the server
+        let response = app
+            .oneshot(get(&format!("/serve/{file}"), None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 303);
+    }
+
+    #[tokio::test]
+    async fn changes_from_other_sites_are_refused() {
+        let dir = TempDir::new("cross-site");
+        let app = app(&dir, Some(auth_config()));
+        let cookie = login_cookie(&app, "alice").await;
+        let file = dir.image("a/one.jpg");
+        let from = |origin: &str| {
+            let mut request = post(&format!("/delete/{file}"), Some(&cookie));
+
+            request
+                .headers_mut()
+                .insert(header::HOST, "photos:3000".parse().unwrap());
+            request
+                .headers_mut()
+                .insert(header::ORIGIN, origin.parse().unwrap());
+            request
+        };
+
+        let response = app
+            .clone()
+            .oneshot(from("https://evil.example"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 403);
+
+        // A link cannot change anything either
+        let response = app
+            .clone()
+            .oneshot(get(&format!("/delete/{file}"), Some(&cookie)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 405);
+        assert!(dir.join("a/one.jpg").exists());
+
+        let response = app.oneshot(from("http://photos:3000")).await.unwrap();
+        assert_eq!(response.status(), 200);
+    }
+}
@@ -0,0 +1,313 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use axum::{
+    Json,
+    body::{Body, Bytes},
+    extract,
+    response::Response,
+};
+use log::{debug, error, info};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use zip::{CompressionMethod, ZipWriter, write::SimpleFileOptions};
+
+use crate::{
+    AppState,
+    api::{error_response, not_found},
+    scanner::directory::{Directory, MediaType},
+};
+
+/// A file to be put into the archive with its name inside the archive.
+struct ArchiveEntry {
+    path: PathBuf,
+    name: String,
+    size: u64,
+}
+
+/// Downloads the files of the request body as a ZIP archive. The body has the
+/// same shape as the `/delete` body: a list of paths starting with the name of
+/// their library. The RAW siblings of the JPEGs are put into the archive too.
+pub async fn download_files(
+    state: Arc<AppState>,
+    Json(files): Json<Vec<String>>,
+) -> Response<Body> {
+    let mut entries = vec![];
+
+    for file in files {
+        let Some((library, path)) = state.resolve(&file) else {
+            return not_found(&file);
+        };
+
+        let context = library.context();
+
+        let Some(path) = context.to_sandboxed_path(path) else {
+            return error_response(400, format!("Invalid path: {file}"));
+        };
+
+        let Ok(metadata) = path.metadata() else {
+            return error_response(404, format!("File not found: {file}"));
+        };
+
+        if !metadata.is_file() {
+            return error_response(400, format!("Not a file: {file}"));
+        }
+
+        if MediaType::from_path(&path).is_none() {
+            return error_response(400, format!("Not a media file: {file}"));
+        }
+
+        // The RAW sibling is downloaded with its JPEG
+        let raw = Directory::raw_sibling(&path);
+
+        for path in std::iter::once(path).chain(raw) {
+            if entries.iter().any(|e: &ArchiveEntry| e.path == path) {
+                continue;
+            }
+
+            let Ok(metadata) = path.metadata() else {
+                continue;
+            };
+
+            entries.push(ArchiveEntry {
+                name: library.server_path(&context.to_relative_path(&path).to_string_lossy()),
+                path,
+                size: metadata.len(),
+            });
+        }
+    }
+
+    stream_archive(&state, entries, "download.zip")
+}
+
+/// Downloads the images of a directory as a ZIP archive, the path is the
+/// path of the directory with a `.zip` extension.
+pub async fn download_directory(
+    extract::Path(dir): extract::Path<String>,
+    state: Arc<AppState>,
+) -> Response<Body> {
+    let Some(dir) = dir.strip_suffix(".zip") else {
+        return error_response(404, format!("Not an archive: {dir}"));
+    };
+
+    let Some((library, path)) = state.resolve(dir) else {
+        return not_found(dir);
+    };
+
+    let full_path = match library.context().to_sandboxed_path(path) {
+        Some(path) if path.is_dir() => path,
+        Some(_) => return error_response(404, format!("Directory not found: {dir}")),
+        None => return error_response(400, format!("Invalid path: {dir}")),
+    };
+
+    let read_dir = match full_path.read_dir() {
+        Ok(read_dir) => read_dir,
+        Err(e) => return error_response(500, format!("Cannot read directory {dir}: {e}")),
+    };
+
+    // The entries which cannot be read are left out
+    let mut dir_entries: Vec<_> = read_dir
+        .flatten()
+        .filter(|e| Directory::is_media(e) && !Directory::is_generated_file(&e.file_name()))
+        .collect();
+
+    dir_entries.sort_by_key(|e| e.file_name());
+
+    let entries = dir_entries
+        .iter()
+        .filter_map(|e| {
+            Some(ArchiveEntry {
+                path: e.path(),
+                name: e.file_name().to_string_lossy().into_owned(),
+                size: e.metadata().ok()?.len(),
+            })
+        })
+        .collect();
+
+    let archive_name = match full_path.file_name() {
+        Some(name) => format!("{}.zip", name.to_string_lossy()),
+        None => "download.zip".to_owned(),
+    };
+
+    stream_archive(&state, entries, &archive_name)
+}
+
+/// Builds the archive on a blocking thread and streams it to the client while
+/// it is being written. The files are stored without compression, images are
+/// compressed anyway.
+fn stream_archive(state: &AppState, entries: Vec<ArchiveEntry>, name: &str) -> Response<Body> {
+    let total_size: u64 = entries.iter().map(|e| e.size).sum();
+
+    if total_size > state.config.max_download_size {
+        return error_response(
+            413,
+            format!(
+                "Download size {total_size} is larger than {} bytes",
+                state.config.max_download_size
+            ),
+        );
+    }
+
+    info!(
+        "Streaming {} files in {name} ({total_size} bytes)",
+        entries.len()
+    );
+
+    let (tx, rx) = mpsc::channel(8);
+
+    tokio::task::spawn_blocking(move || {
+        let error_tx = tx.clone();
+        let writer = BufWriter::with_capacity(64 * 1024, ChannelWriter { tx });
+
+        // The error ends the body, so the client sees a broken download
+        // instead of a truncated archive
+        if let Err(e) = write_archive(writer, &entries) {
+            error!("Failed to write archive: {e}");
+
+            let _ = error_tx.blocking_send(Err(io::Error::other(e)));
+        }
+    });
+
+    Response::builder()
+        .header("Content-Type", "application/zip")
+        .header(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}\"", name.replace('"', "")),
+        )
+        .body(Body::from_stream(ReceiverStream::new(rx)))
+        .unwrap()
+}
+
+fn write_archive(writer: impl Write, entries: &[ArchiveEntry]) -> zip::result::ZipResult<()> {
+    let mut zip = ZipWriter::new_stream(writer);
+
+    for entry in entries {
+        debug!("  Adding {:?} as {}", entry.path, entry.name);
+
+        let options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Stored)
+            .large_file(entry.size > u32::MAX as u64);
+
+        zip.start_file(&entry.name, options)?;
+
+        let mut file = File::open(&entry.path)?;
+
+        io::copy(&mut file, &mut zip)?;
+    }
+
+    zip.finish()?.flush()?;
+
+    Ok(())
+}
+
+/// Sends everything written into it as body chunks of the response.
+struct ChannelWriter {
+    tx: mpsc::Sender<io::Result<Bytes>>,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tx
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Client disconnected"))?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::to_bytes;
+    use http::Method;
+
+    use super::*;
+    use crate::{
+        api::test_helpers::{TempDir, get, json_request, status},
+        config::{Config, LibraryConfig},
+    };
+
+    /// A server whose library keeps its cache in the `cache` directory of its
+    /// root, which is not hidden.
+    fn state(dir: &TempDir) -> Arc<AppState> {
+        let library = LibraryConfig {
+            name: "main".to_owned(),
+            root_directory: dir.path().to_string_lossy().into_owned(),
+            cache_directory: Some(dir.join("cache").to_string_lossy().into_owned()),
+            read_only: false,
+        };
+        let config = Config {
+            libraries: vec![library],
+            ..Config::default()
+        };
+
+        Arc::new(AppState::new(config).unwrap().0)
+    }
+
+    fn app(dir: &TempDir) -> axum::Router {
+        crate::app(state(dir))
+    }
+
+    #[tokio::test]
+    async fn only_media_files_are_downloaded() {
+        let dir = TempDir::new("download-media");
+        let app = app(&dir);
+        let image = dir.image("a/one.jpg");
+
+        std::fs::write(dir.join("a/notes.txt"), "private").unwrap();
+
+        let download = |files: &str| json_request(Method::POST, "/api/download", None, files);
+
+        assert_eq!(status(&app, download(&format!("[\"{image}\"]"))).await, 200);
+        assert_eq!(status(&app, download(r#"["main/a/notes.txt"]"#)).await, 400);
+    }
+
+    #[tokio::test]
+    async fn the_cache_directory_is_not_served() {
+        let dir = TempDir::new("download-cache");
+        let app = app(&dir);
+
+        dir.image("cache/trash/a/one.jpg");
+        std::fs::write(dir.join("cache/catalog.json"), "{}").unwrap();
+
+        for uri in [
+            "/serve/main/cache/catalog.json",
+            "/serve/main/cache/trash/",
+            "/serve/main//cache/trash/a/one.jpg",
+            "/download/main/cache/trash/a.zip",
+        ] {
+            assert_eq!(status(&app, get(uri, None)).await, 404, "{uri}");
+        }
+
+        let download = json_request(
+            Method::POST,
+            "/api/download",
+            None,
+            r#"["main/cache/trash/a/one.jpg"]"#,
+        );
+
+        assert_eq!(status(&app, download).await, 404);
+    }
+
+    #[tokio::test]
+    async fn failed_archives_break_the_body() {
+        let dir = TempDir::new("download-broken");
+        let entries = vec![ArchiveEntry {
+            path: dir.join("gone.jpg"),
+            name: "gone.jpg".to_owned(),
+            size: 4,
+        }];
+
+        let response = stream_archive(&state(&dir), entries, "broken.zip");
+
+        assert_eq!(response.status(), 200);
+        assert!(to_bytes(response.into_body(), usize::MAX).await.is_err());
+    }
+}
@@ -0,0 +1,249 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use axum::{Json, body::Body, extract, response::Response};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AppState,
+    api::{error_response, json_response, trash_files},
+    auth::CurrentUser,
+    scanner::directory::Directory,
+    thumbnail::image::Image,
+};
+
+/// The reason for leaving a file alone whose content is not the one of the
+/// last sync.
+const CHANGED: &str = "Missing or changed since the last sync";
+
+/// Files with identical content, the paths start with the library name. A
+/// group can span several libraries.
+#[derive(Debug, Serialize)]
+pub struct DuplicateGroup {
+    pub hash: String,
+    pub size: u64,
+    pub files: Vec<String>,
+}
+
+/// The files to keep, all the other files of their duplicate groups are
+/// moved into the trash of their library. The RAW sibling of a trashed JPEG
+/// goes with it only if the kept file has an identical RAW sibling.
+#[derive(Debug, Deserialize)]
+pub struct ResolveRequest {
+    pub keep: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ResolveResult {
+    pub kept: Vec<String>,
+    pub trashed: Vec<String>,
+    pub failed: Vec<(String, String)>,
+}
+
+/// Lists the groups of identical files, the ones wasting the most space come
+/// first.
+pub async fn list_duplicates(state: Arc<AppState>) -> Response<Body> {
+    json_response(200, &duplicate_groups(&state))
+}
+
+pub async fn resolve_duplicates(
+    extract::Extension(user): extract::Extension<CurrentUser>,
+    state: Arc<AppState>,
+    Json(request): Json<ResolveRequest>,
+) -> Response<Body> {
+    // Hashing the files is blocking work
+    let result = tokio::task::spawn_blocking(move || resolve(&state, &user, request.keep)).await;
+
+    match result {
+        Ok(result) => json_response(200, &result),
+        Err(e) => error_response(500, e.to_string()),
+    }
+}
+
+/// Trashes the other files of the groups of the kept files. The hashes of the
+/// search index are only as fresh as the last sync, so the files are hashed
+/// again first: nothing is trashed for a kept file which is gone or changed,
+/// and a copy which changed is left alone.
+fn resolve(state: &AppState, user: &CurrentUser, keep: Vec<String>) -> ResolveResult {
+    let groups = duplicate_groups(state);
+    let mut result = ResolveResult::default();
+    let mut candidates: Vec<(String, &DuplicateGroup)> = vec![];
+
+    for file in keep {
+        let key = state.resolve(&file).and_then(|(library, path)| {
+            Some(library.server_path(&library.context().to_relative_key(path)?))
+        });
+
+        let Some(key) = key else {
+            result.failed.push((file, "Invalid path".to_owned()));
+            continue;
+        };
+
+        let Some(group) = groups.iter().find(|g| g.files.contains(&key)) else {
+            result.failed.push((file, "Not a duplicate".to_owned()));
+            continue;
+        };
+
+        if current_hash(state, &key).as_deref() != Some(group.hash.as_str()) {
+            result.failed.push((key, CHANGED.to_owned()));
+            continue;
+        }
+
+        for other in group.files.iter().filter(|f| **f != key) {
+            if !candidates.iter().any(|(f, _)| f == other) {
+                candidates.push((other.clone(), group));
+            }
+        }
+
+        result.kept.push(key);
+    }
+
+    // Keeping more than one file of a group keeps all of them
+    candidates.retain(|(f, _)| !result.kept.contains(f));
+
+    let mut to_trash = vec![];
+
+    for (file, group) in candidates {
+        if current_hash(state, &file).as_deref() != Some(group.hash.as_str()) {
+            result.failed.push((file, CHANGED.to_owned()));
+            continue;
+        }
+
+        let raw = raw_sibling(state, &file);
+
+        to_trash.push(file);
+
+        if let Some((raw, hash)) = raw {
+            let kept = group.files.iter().find(|f| result.kept.contains(f));
+
+            if kept
+                .and_then(|k| raw_sibling(state, k))
+                .is_some_and(|(_, kept_hash)| kept_hash == hash)
+            {
+                to_trash.push(raw);
+            }
+        }
+    }
+
+    info!("Resolving duplicates, trashing {to_trash:?}");
+
+    let (trashed, failed) = trash_files(state, user, &to_trash);
+
+    result.trashed = trashed;
+    result.failed.extend(failed);
+
+    result
+}
+
+/// The hash of the file as it is on the disk now, `None` if it is gone.
+fn current_hash(state: &AppState, server_path: &str) -> Option<String> {
+    let (library, path) = state.resolve(server_path)?;
+
+    Image::content_hash(&library.context().to_sandboxed_path(path)?)
+}
+
+/// The RAW sibling of a JPEG with the hash of its content.
+fn raw_sibling(state: &AppState, server_path: &str) -> Option<(String, String)> {
+    let (library, path) = state.resolve(server_path)?;
+    let context = library.context();
+    let raw = Directory::raw_sibling(&context.to_sandboxed_path(path)?)?;
+    let hash = Image::content_hash(&raw)?;
+    let key = context
+        .to_relative_path(&raw)
+        .to_string_lossy()
+        .into_owned();
+
+    Some((library.server_path(&key), hash))
+}
+
+fn duplicate_groups(state: &AppState) -> Vec<DuplicateGroup> {
+    let mut by_hash: BTreeMap<String, DuplicateGroup> = BTreeMap::new();
+
+    for library in &state.libraries {
+        let index = library.search_index.lock().unwrap();
+
+        for (key, entry) in &index.entries {
+            let Some(hash) = &entry.hash else {
+                continue;
+            };
+
+            by_hash
+                .entry(hash.clone())
+                .or_insert_with(|| DuplicateGroup {
+                    hash: hash.clone(),
+                    size: entry.size,
+                    files: vec![],
+                })
+                .files
+                .push(library.server_path(key));
+        }
+    }
+
+    let mut groups: Vec<DuplicateGroup> = by_hash
+        .into_values()
+        .filter(|g| g.files.len() > 1)
+        .collect();
+
+    groups.sort_by_key(|g| std::cmp::Reverse(g.size * (g.files.len() as u64 - 1)));
+
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::to_bytes;
+    use http::Method;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        api::test_helpers::{TempDir, json_request},
+        config::Config,
+    };
+
+    #[tokio::test]
+    async fn raw_siblings_are_trashed_only_if_identical() {
+        let dir = TempDir::new("duplicates");
+        let config = Config {
+            root_directory: dir.path().to_string_lossy().into_owned(),
+            ..Config::default()
+        };
+        let state = Arc::new(AppState::new(config).unwrap().0);
+
+        // The same JPEG in each directory, the RAW of `c` differs
+        for (name, raw) in [("a", "raw"), ("b", "raw"), ("c", "other")] {
+            let key = format!("{name}/one.jpg");
+
+            dir.image(&key);
+            std::fs::write(dir.join(format!("{name}/one.cr2")), raw).unwrap();
+
+            let hash = Image::content_hash(&dir.join(&key)).unwrap();
+            let entry = serde_json::json!({"size": 4, "width": 1, "height": 1, "hash": hash});
+            let mut index = state.libraries[0].search_index.lock().unwrap();
+
+            index
+                .entries
+                .insert(key, serde_json::from_value(entry).unwrap());
+        }
+
+        let request = json_request(
+            Method::POST,
+            "/api/duplicates/resolve",
+            None,
+            r#"{"keep": ["main/a/one.jpg"]}"#,
+        );
+        let response = crate::app(state).oneshot(request).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let result: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(
+            result["trashed"],
+            serde_json::json!(["main/b/one.jpg", "main/b/one.cr2", "main/c/one.jpg"])
+        );
+
+        // The RAW which differs from the kept one stays
+        assert!(dir.join("a/one.cr2").exists());
+        assert!(dir.join("c/one.cr2").exists());
+        assert!(dir.join(".mosaic/trash/b/one.cr2").exists());
+    }
+}
@@ -0,0 +1,556 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use axum::{Json, body::Body, extract, response::Response};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AppState,
+    api::{
+        SyncCommand, can_change, error_response, forbidden, json_response, not_found,
+        read_only_library, remove_generated_files,
+    },
+    auth::CurrentUser,
+    catalog::xmp,
+    library::move_file,
+    scanner::directory::{Directory, ScannerContext},
+    thumbnail::bundle::Thumbnail,
+};
+
+/// What to do when the target file already exists.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictStrategy {
+    /// Report the file as failed and leave both files untouched.
+    #[default]
+    Fail,
+    /// Silently leave the source file where it is.
+    Skip,
+    /// Append `_1`, `_2`, ... to the file name until it is unique.
+    Suffix,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operation {
+    Move,
+    Copy,
+}
+
+/// Request body of the move and copy endpoints. Paths start with the name of
+/// their library, files can only be moved or copied within their library.
+#[derive(Debug, Deserialize)]
+pub struct TransferRequest {
+    pub files: Vec<String>,
+    pub destination: String,
+    #[serde(default)]
+    pub conflict: ConflictStrategy,
+}
+
+/// Request body of the rename endpoint. The new name is a plain file name, the
+/// file stays in its directory.
+#[derive(Debug, Deserialize)]
+pub struct RenameRequest {
+    pub file: String,
+    pub new_name: String,
+    #[serde(default)]
+    pub conflict: ConflictStrategy,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct OperationResult {
+    /// Source and target paths of the files processed.
+    pub done: Vec<(String, String)>,
+    pub skipped: Vec<String>,
+    /// Source paths with the reason of the failure.
+    pub failed: Vec<(String, String)>,
+}
+
+pub async fn move_files(
+    extract::Extension(user): extract::Extension<CurrentUser>,
+    state: Arc<AppState>,
+    Json(request): Json<TransferRequest>,
+) -> Response<Body> {
+    transfer_files(state, &user, request, Operation::Move).await
+}
+
+pub async fn copy_files(
+    extract::Extension(user): extract::Extension<CurrentUser>,
+    state: Arc<AppState>,
+    Json(request): Json<TransferRequest>,
+) -> Response<Body> {
+    transfer_files(state, &user, request, Operation::Copy).await
+}
+
+pub async fn rename_file(
+    extract::Extension(user): extract::Extension<CurrentUser>,
+    state: Arc<AppState>,
+    Json(request): Json<RenameRequest>,
+) -> Response<Body> {
+    let Some((library, path)) = state.resolve(&request.file) else {
+        return not_found(&request.file);
+    };
+
+    if library.read_only {
+        return read_only_library(library);
+    }
+
+    if let Some(response) = forbidden(&state, &user, library, path) {
+        return response;
+    }
+
+    let context = library.context();
+
+    let Some(source) = context.to_sandboxed_path(path) else {
+        return error_response(400, format!("Invalid path: {}", request.file));
+    };
+
+    if !is_plain_file_name(&request.new_name) {
+        return error_response(400, format!("Invalid file name: {}", request.new_name));
+    }
+
+    if !source.is_file() {
+        return error_response(404, format!("File not found: {}", request.file));
+    }
+
+    let dir = source.parent().unwrap().to_path_buf();
+    let mut result = OperationResult::default();
+
+    // The RAW sibling gets the new name with its own extension
+    let sibling = Directory::raw_sibling(&source);
+    let new_stem = Path::new(&request.new_name)
+        .file_stem()
+        .unwrap()
+        .to_string_lossy()
+        .into_owned();
+    let mut targets = vec![dir.join(&request.new_name)];
+
+    targets.extend(sibling.iter().map(|raw| match raw.extension() {
+        Some(ext) => dir.join(format!("{new_stem}.{}", ext.to_string_lossy())),
+        None => dir.join(&new_stem),
+    }));
+
+    let Some(targets) = resolve_conflicts(&targets, request.conflict) else {
+        return handle_conflict(request, result);
+    };
+
+    let mut sources = vec![source];
+
+    sources.extend(sibling);
+
+    let mut keys = vec![];
+
+    for (source, target) in sources.iter().zip(&targets) {
+        info!("Renaming {source:?} to {target:?}");
+
+        if let Err(e) = std::fs::rename(source, target) {
+            result.failed.push((
+                library.server_path(&relative_key(&context, source)),
+                e.to_string(),
+            ));
+            break;
+        }
+
+        transfer_sidecar(source, target, Operation::Move);
+
+        keys.push((
+            relative_key(&context, source),
+            relative_key(&context, target),
+        ));
+    }
+
+    if keys.is_empty() {
+        return json_response(500, &result);
+    }
+
+    let old_name = file_name(&sources[0]);
+    let new_name = file_name(&targets[0]);
+    let raw_name = targets.get(1).filter(|t| t.is_file()).map(|t| file_name(t));
+
+    if let Some(mut thumbnails) = Thumbnail::read_bundles(&dir) {
+        for t in thumbnails
+            .iter_mut()
+            .filter(|t| t.original_name == old_name)
+        {
+            t.original_name = new_name.clone();
+            t.raw_name = raw_name.clone();
+        }
+
+        Thumbnail::write_bundles(&dir, &thumbnails);
+    }
+
+    let mut catalog = library.catalog.lock().unwrap();
+    let mut index = library.search_index.lock().unwrap();
+    let mut changed = false;
+    let mut index_changed = false;
+
+    for (old_key, new_key) in keys {
+        changed |= catalog.rename(&old_key, &new_key);
+        index_changed |= index.rename(&old_key, &new_key);
+
+        result
+            .done
+            .push((library.server_path(&old_key), library.server_path(&new_key)));
+    }
+
+    if changed && let Err(e) = catalog.save() {
+        return error_response(500, e);
+    }
+
+    // The index can be rebuilt by a sync, the files are done already
+    if index_changed && let Err(e) = index.save() {
+        error!("{e}");
+    }
+
+    json_response(200, &result)
+}
+
+async fn transfer_files(
+    state: Arc<AppState>,
+    user: &CurrentUser,
+    request: TransferRequest,
+    operation: Operation,
+) -> Response<Body> {
+    let Some((library, destination_path)) = state.resolve(&request.destination) else {
+        return not_found(&request.destination);
+    };
+
+    if library.read_only {
+        return read_only_library(library);
+    }
+
+    if let Some(response) = forbidden(&state, user, library, destination_path) {
+        return response;
+    }
+
+    let context = library.context();
+
+    let Some(destination) = context.to_sandboxed_path(destination_path) else {
+        return error_response(400, format!("Invalid path: {}", request.destination));
+    };
+
+    if !destination.is_dir() {
+        return error_response(404, format!("Directory not found: {}", request.destination));
+    }
+
+    let mut result = OperationResult::default();
+    // Source directories with the file names which left them
+    let mut moved_out: Vec<(PathBuf, Vec<String>)> = vec![];
+    // Source and target keys of the files processed
+    let mut keys: Vec<(String, String)> = vec![];
+    // The RAW siblings already processed with their JPEG
+    let mut handled: HashSet<PathBuf> = HashSet::new();
+
+    for file in request.files {
+        let path = match state.resolve(&file) {
+            Some((source_library, path)) if source_library.name == library.name => path,
+            Some(_) => {
+                result
+                    .failed
+                    .push((file, "Not in the destination library".to_owned()));
+                continue;
+            }
+            None => {
+                result.failed.push((file, "Not found".to_owned()));
+                continue;
+            }
+        };
+
+        // The copies only need to be readable, the moved files leave their
+        // directory
+        if operation == Operation::Move && !can_change(&state, user, library, path) {
+            result.failed.push((file, "Permission denied".to_owned()));
+            continue;
+        }
+
+        let Some(source) = context.to_sandboxed_path(path) else {
+            result.failed.push((file, "Invalid path".to_owned()));
+            continue;
+        };
+
+        if handled.contains(&source) {
+            continue;
+        }
+
+        if !source.is_file() {
+            result.failed.push((file, "File not found".to_owned()));
+            continue;
+        }
+
+        // A JPEG and its RAW sibling are one photo, they are moved together
+        // and get the same suffix on a conflict
+        let mut sources = vec![source];
+
+        sources.extend(Directory::raw_sibling(&sources[0]));
+
+        let targets: Vec<PathBuf> = sources
+            .iter()
+            .map(|s| destination.join(s.file_name().unwrap()))
+            .collect();
+
+        // A file moved into its own directory stays, a copy there is a
+        // conflict with the file itself
+        if operation == Operation::Move && targets[0] == sources[0] {
+            result.skipped.push(file);
+            continue;
+        }
+
+        let Some(targets) = resolve_conflicts(&targets, request.conflict) else {
+            match request.conflict {
+                ConflictStrategy::Skip => result.skipped.push(file),
+                _ => result.failed.push((file, "Target file exists".to_owned())),
+            }
+            continue;
+        };
+
+        for (source, target) in sources.into_iter().zip(targets) {
+            debug!("{operation:?} {source:?} to {target:?}");
+
+            let source_key = relative_key(&context, &source);
+
+            let outcome = match operation {
+                Operation::Move => move_file(&source, &target),
+                Operation::Copy => std::fs::copy(&source, &target).map(|_| ()),
+            };
+
+            match outcome {
+                Ok(_) => {
+                    transfer_sidecar(&source, &target, operation);
+
+                    if operation == Operation::Move {
+                        let dir = source.parent().unwrap().to_path_buf();
+                        let name = file_name(&source);
+
+                        match moved_out.iter_mut().find(|(d, _)| *d == dir) {
+                            Some((_, names)) => names.push(name),
+                            None => moved_out.push((dir, vec![name])),
+                        }
+                    }
+
+                    let target_key = relative_key(&context, &target);
+
+                    result.done.push((
+                        library.server_path(&source_key),
+                        library.server_path(&target_key),
+                    ));
+                    keys.push((source_key, target_key));
+                }
+                Err(e) => {
+                    // The RAW stays with the JPEG which could not be moved
+                    result
+                        .failed
+                        .push((library.server_path(&source_key), e.to_string()));
+                    break;
+                }
+            }
+
+            handled.insert(source);
+        }
+    }
+
+    // The sprites of the source directories can stay as they are, the
+    // thumbnails of the moved files just won't be referenced any more.
+    for (dir, names) in &moved_out {
+        if let Some(thumbnails) = Thumbnail::read_bundles(dir) {
+            let thumbnails: Vec<Thumbnail> = thumbnails
+                .into_iter()
+                .filter(|t| !names.contains(&t.original_name))
+                .collect();
+
+            Thumbnail::write_bundles(dir, &thumbnails);
+        }
+    }
+
+    // The metadata in the catalog and the search index follow the files
+    {
+        let mut catalog = library.catalog.lock().unwrap();
+        let mut index = library.search_index.lock().unwrap();
+        let mut changed = false;
+        let mut index_changed = false;
+
+        for (from, to) in &keys {
+            match operation {
+                Operation::Move => {
+                    changed |= catalog.rename(from, to);
+                    index_changed |= index.rename(from, to);
+                }
+                Operation::Copy => {
+                    changed |= catalog.copy(from, to);
+                    index_changed |= index.copy(from, to);
+                }
+            }
+        }
+
+        if changed && let Err(e) = catalog.save() {
+            return error_response(500, e);
+        }
+
+        // The index can be rebuilt by a sync, the files are done already
+        if index_changed && let Err(e) = index.save() {
+            error!("{e}");
+        }
+    }
+
+    // The destination needs new sprites, so it is resynced if it was indexed
+    if !result.done.is_empty() && destination.join("bundles.json").exists() {
+        remove_generated_files(&destination);
+
+        let relative_dir = context
+            .to_relative_path(&destination)
+            .to_string_lossy()
+            .into_owned();
+
+        state
+            .command_tx
+            .send(SyncCommand::SyncDirectory(
+                library.name.clone(),
+                relative_dir,
+            ))
+            .await
+            .expect("Failed to send internal command");
+    }
+
+    json_response(200, &result)
+}
+
+fn handle_conflict(request: RenameRequest, mut result: OperationResult) -> Response<Body> {
+    match request.conflict {
+        ConflictStrategy::Skip => {
+            result.skipped.push(request.file);
+
+            json_response(200, &result)
+        }
+        _ => {
+            result
+                .failed
+                .push((request.file, "Target file exists".to_owned()));
+
+            json_response(409, &result)
+        }
+    }
+}
+
+/// Returns the path where the file can be written, or `None` if the file needs
+/// to be skipped or failed.
+pub(super) fn resolve_conflict(target: &Path, strategy: ConflictStrategy) -> Option<PathBuf> {
+    resolve_conflicts(&[target.to_path_buf()], strategy).map(|mut targets| targets.remove(0))
+}
+
+/// Like `resolve_conflict` for files which belong together, all of them get
+/// the same suffix so a JPEG and its RAW sibling stay paired.
+fn resolve_conflicts(targets: &[PathBuf], strategy: ConflictStrategy) -> Option<Vec<PathBuf>> {
+    if targets.iter().all(|t| !t.exists()) {
+        return Some(targets.to_vec());
+    }
+
+    match strategy {
+        ConflictStrategy::Fail | ConflictStrategy::Skip => None,
+        ConflictStrategy::Suffix => (1..)
+            .map(|i| {
+                targets
+                    .iter()
+                    .map(|t| with_suffix(t, i))
+                    .collect::<Vec<_>>()
+            })
+            .find(|candidates| candidates.iter().all(|c| !c.exists())),
+    }
+}
+
+fn with_suffix(target: &Path, i: usize) -> PathBuf {
+    let stem = target.file_stem().unwrap().to_string_lossy();
+
+    match target.extension() {
+        Some(ext) => target.with_file_name(format!("{stem}_{i}.{}", ext.to_string_lossy())),
+        None => target.with_file_name(format!("{stem}_{i}")),
+    }
+}
+
+/// The darktable style sidecar belongs only to the file, so it follows the
+/// file when it is moved or copied.
+fn transfer_sidecar(source: &Path, target: &Path, operation: Operation) {
+    let sidecar = xmp::own_sidecar_path(source);
+
+    if !sidecar.is_file() {
+        return;
+    }
+
+    let target_sidecar = xmp::own_sidecar_path(target);
+
+    let outcome = match operation {
+        Operation::Move => move_file(&sidecar, &target_sidecar),
+        Operation::Copy => std::fs::copy(&sidecar, &target_sidecar).map(|_| ()),
+    };
+
+    if let Err(e) = outcome {
+        warn!("Cannot {operation:?} sidecar {sidecar:?}: {e}");
+    }
+}
+
+pub(super) fn is_plain_file_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\'])
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().unwrap().to_string_lossy().into_owned()
+}
+
+fn relative_key(context: &ScannerContext, path: &Path) -> String {
+    context
+        .to_relative_path(path)
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::to_bytes;
+    use http::Method;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        api::test_helpers::{TempDir, json_request},
+        config::Config,
+    };
+
+    #[tokio::test]
+    async fn copies_into_the_same_directory_get_a_suffix() {
+        let dir = TempDir::new("same-directory");
+        let config = Config {
+            root_directory: dir.path().to_string_lossy().into_owned(),
+            ..Config::default()
+        };
+        let app = crate::app(Arc::new(AppState::new(config).unwrap().0));
+        let image = dir.image("a/one.jpg");
+
+        std::fs::write(dir.join("a/one.cr2"), "raw").unwrap();
+
+        let transfer = |operation: &str| {
+            let body = format!(
+                r#"{{"files": ["{image}"], "destination": "main/a", "conflict": "suffix"}}"#
+            );
+
+            json_request(
+                Method::POST,
+                &format!("/api/files/{operation}"),
+                None,
+                &body,
+            )
+        };
+
+        let response = app.clone().oneshot(transfer("move")).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let result: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(result["skipped"], serde_json::json!([image]));
+
+        app.oneshot(transfer("copy")).await.unwrap();
+
+        assert!(dir.join("a/one_1.jpg").is_file());
+        assert!(dir.join("a/one_1.cr2").is_file());
+        assert!(dir.join("a/one.jpg").is_file());
+    }
+}
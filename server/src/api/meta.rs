@@ -0,0 +1,97 @@
+use std::sync::Arc;
+
+use axum::{Json, body::Body, extract, response::Response};
+use log::{debug, warn};
+
+use crate::{
+    AppState,
+    api::{error_response, forbidden, json_response, not_found, read_only_library},
+    auth::CurrentUser,
+    catalog::{
+        metadata::{ImageMeta, MetaUpdate},
+        xmp,
+    },
+    scanner::directory::ScannerContext,
+};
+
+pub async fn get_meta(
+    extract::Path(path): extract::Path<String>,
+    state: Arc<AppState>,
+) -> Response<Body> {
+    let Some((library, file)) = state.resolve(&path) else {
+        return not_found(&path);
+    };
+
+    let Some(key) = library.context().to_relative_key(file) else {
+        return error_response(400, format!("Invalid path: {path}"));
+    };
+
+    let meta = library.catalog.lock().unwrap().meta(&key);
+
+    json_response(200, &meta)
+}
+
+/// Updates the rating, flag or label of an image and returns the new metadata.
+/// The changes are written into the XMP sidecar of the image too.
+pub async fn update_meta(
+    extract::Path(path): extract::Path<String>,
+    extract::Extension(user): extract::Extension<CurrentUser>,
+    state: Arc<AppState>,
+    Json(update): Json<MetaUpdate>,
+) -> Response<Body> {
+    let Some((library, file)) = state.resolve(&path) else {
+        return not_found(&path);
+    };
+
+    if library.read_only {
+        return read_only_library(library);
+    }
+
+    if let Some(response) = forbidden(&state, &user, library, file) {
+        return response;
+    }
+
+    let context = library.context();
+
+    let Some(key) = context.to_relative_key(file) else {
+        return error_response(400, format!("Invalid path: {path}"));
+    };
+
+    if !context.base_dir.join(&key).is_file() {
+        return error_response(404, format!("File not found: {path}"));
+    }
+
+    debug!("Updating metadata of {key}: {update:?}");
+
+    let mut catalog = library.catalog.lock().unwrap();
+    let mut meta = catalog.meta(&key);
+
+    if let Err(e) = meta.apply(&update) {
+        return error_response(400, e);
+    }
+
+    catalog.set_meta(&key, meta.clone());
+
+    if let Err(e) = catalog.save() {
+        return error_response(500, e);
+    }
+
+    write_sidecar(&state, &context, &key, &meta);
+
+    json_response(200, &meta)
+}
+
+/// Writes the metadata back into the XMP sidecar of the image if it is
+/// enabled.
+pub(super) fn write_sidecar(
+    state: &AppState,
+    context: &ScannerContext,
+    key: &str,
+    meta: &ImageMeta,
+) {
+    if state.config.xmp_sidecars
+        && let Err(e) = xmp::write_sidecar(&context.base_dir.join(key), meta)
+    {
+        warn!("Cannot write XMP sidecar of {key}: {e}");
+    }
+}
@@ -1,203 +1,618 @@
+pub mod albums;
+pub mod auth;
+pub mod download;
+pub mod duplicates;
+pub mod files;
+pub mod meta;
+pub mod search;
+pub mod shares;
+pub mod similar;
+pub mod stream;
+pub mod tags;
+pub mod upload;
+
+#[cfg(test)]
+pub(crate) mod test_helpers;
+
 use std::{
+    collections::{HashMap, HashSet},
     fs::{DirEntry, File},
     io::{BufWriter, Cursor, Write},
     path::{Path, PathBuf},
     sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use axum::{Json, body::Body, extract, response::Response};
-use http::{HeaderValue, header};
-use log::{debug, info};
+use http::{HeaderMap, HeaderValue, header};
+use log::{debug, error, info};
+use serde::Serialize;
 use tokio::sync::mpsc;
 
 use crate::{
     AppState,
-    scanner::directory::ScannerContext,
-    thumbnail::bundle::{ImageBundle, Thumbnail},
+    auth::CurrentUser,
+    catalog::{index::IndexEntry, metadata::MetaFilter, xmp},
+    config::Role,
+    library::Library,
+    scanner::{
+        directory::{Directory, ScannerContext},
+        pipeline::{ScanPipeline, panic_message},
+    },
+    thumbnail::{bundle::Thumbnail, capture::format_timestamp, quality::QualityFilter},
 };
 
 #[derive(Debug)]
 pub enum SyncCommand {
-    /// Sync the images in the directory. The first is the root path and the
-    /// second is the relative path inside the root.
-    SyncDirectory(PathBuf, String),
+    /// Sync the images in the directory. The first is the name of the library
+    /// and the second is the relative path inside its root.
+    SyncDirectory(String, String),
+}
+
+/// The outcome of the last sync of a directory, the files which could not be
+/// read are listed with their errors.
+#[derive(Clone, Debug, Serialize)]
+pub struct SyncReport {
+    pub directory: String,
+    pub files: usize,
+    pub errors: Vec<SyncError>,
+    pub finished_at: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SyncError {
+    /// The file name, empty if the whole directory failed.
+    pub file: String,
+    pub error: String,
 }
 
-pub async fn sync_directory(mut commands: mpsc::Receiver<SyncCommand>) {
+impl SyncReport {
+    pub fn new(directory: &str, files: usize, errors: Vec<SyncError>) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64);
+
+        SyncReport {
+            directory: directory.to_owned(),
+            files,
+            errors,
+            finished_at: format_timestamp(now),
+        }
+    }
+
+    pub fn failed(directory: &str, error: String) -> Self {
+        let errors = vec![SyncError {
+            file: String::new(),
+            error,
+        }];
+
+        SyncReport::new(directory, 0, errors)
+    }
+}
+
+/// The outcome of a batch delete, the failed files come with the reason.
+#[derive(Debug, Serialize)]
+pub struct DeleteResult {
+    pub deleted: Vec<String>,
+    pub failed: Vec<(String, String)>,
+}
+
+/// Runs the sync commands one after the other. The scanning is blocking
+/// work, it runs on the blocking threads of the runtime and on the pool of
+/// the scanner, so the HTTP requests are served in the meantime. A failing
+/// or panicking sync is recorded in its report, the worker goes on with the
+/// next command.
+pub async fn sync_directory(mut commands: mpsc::Receiver<SyncCommand>, state: Arc<AppState>) {
+    let pipeline = Arc::new(ScanPipeline::new(state.config.scan_workers));
+
     while let Some(command) = commands.recv().await {
         debug!("Sync command: {command:?}");
 
-        let SyncCommand::SyncDirectory(base_dir, relative_dir) = command;
-        let context = ScannerContext::new(&base_dir);
+        let SyncCommand::SyncDirectory(library_name, relative_dir) = command;
 
-        let directory = context.scan(relative_dir);
-        let bundles = ImageBundle::from_directory(&directory);
+        let Some(library) = state.library(&library_name) else {
+            error!("Sync of unknown library {library_name}");
+            continue;
+        };
 
-        debug!("{} bundles created", bundles.len());
+        let key = relative_dir.trim_matches('/').to_owned();
+        let directory = library.server_path(&key);
+        let pipeline = Arc::clone(&pipeline);
+        let task_state = Arc::clone(&state);
 
-        directory.save(&bundles);
+        let result = tokio::task::spawn_blocking(move || {
+            let library = task_state.library(&library_name).unwrap();
+
+            scan_directory(library, &pipeline, relative_dir)
+        })
+        .await;
+
+        let report = match result {
+            Ok(report) => report,
+            Err(e) => {
+                let message = match e.try_into_panic() {
+                    Ok(panic) => panic_message(panic.as_ref()),
+                    Err(e) => e.to_string(),
+                };
+
+                SyncReport::failed(&directory, message)
+            }
+        };
+
+        if report.errors.is_empty() {
+            info!("Sync of {directory:?} finished: {} files", report.files);
+        } else {
+            error!(
+                "Sync of {directory:?} finished: {} files, {} errors",
+                report.files,
+                report.errors.len()
+            );
+        }
+
+        library.sync_reports.lock().unwrap().insert(key, report);
     }
 }
 
+/// Scans a directory of the library and takes over its images into the
+/// catalog and the search index. The sync worker and the command line run
+/// this.
+pub fn scan_directory(
+    library: &Library,
+    pipeline: &ScanPipeline,
+    relative_dir: String,
+) -> SyncReport {
+    let key = library.server_path(relative_dir.trim_matches('/'));
+    let context = library.context();
+    let directory = match context.scan(pipeline, relative_dir) {
+        Ok(directory) => directory,
+        Err(e) => return SyncReport::failed(&key, e),
+    };
+
+    let mut errors: Vec<SyncError> = directory
+        .images
+        .iter()
+        .filter_map(|image| {
+            Some(SyncError {
+                file: image.file_path.file_name()?.to_string_lossy().into_owned(),
+                error: image.error.clone()?,
+            })
+        })
+        .collect();
+
+    // The catalog and the index are saved for the whole directory, not for
+    // a file
+    let saved = [
+        import_xmp(library, &context, &directory),
+        update_search_index(library, &context, &directory),
+    ];
+
+    for error in saved.into_iter().filter_map(Result::err) {
+        errors.push(SyncError {
+            file: String::new(),
+            error,
+        });
+    }
+
+    SyncReport::new(&key, directory.images.len(), errors)
+}
+
+/// Returns the report of the last sync of a directory.
+pub async fn sync_report(
+    extract::Path(dir): extract::Path<String>,
+    state: Arc<AppState>,
+) -> Response<Body> {
+    let Some((library, path)) = state.resolve(&dir) else {
+        return not_found(&dir);
+    };
+
+    let reports = library.sync_reports.lock().unwrap();
+
+    match reports.get(path.trim_matches('/')) {
+        Some(report) => json_response(200, report),
+        None => error_response(404, format!("No sync report for {dir}")),
+    }
+}
+
+/// Takes over the metadata found in the XMP data of the scanned images into
+/// the catalog.
+fn import_xmp(
+    library: &Library,
+    context: &ScannerContext,
+    directory: &Directory,
+) -> Result<(), String> {
+    let mut catalog = library.catalog.lock().unwrap();
+    let mut changed = false;
+
+    for image in &directory.images {
+        let Some(xmp) = &image.xmp else {
+            continue;
+        };
+
+        let key = context.to_relative_path(&image.file_path);
+        let key = key.to_string_lossy();
+        let mut meta = catalog.meta(&key);
+
+        meta.merge_xmp(xmp);
+
+        if meta != catalog.meta(&key) {
+            debug!("Metadata of {key} updated from XMP");
+
+            catalog.set_meta(&key, meta);
+            changed = true;
+        }
+    }
+
+    if changed {
+        catalog.save()?;
+    }
+
+    Ok(())
+}
+
+/// Replaces the entries of the scanned directory in the search index.
+fn update_search_index(
+    library: &Library,
+    context: &ScannerContext,
+    directory: &Directory,
+) -> Result<(), String> {
+    let entries = directory
+        .images
+        .iter()
+        .map(|image| {
+            let key = context.to_relative_path(&image.file_path);
+            let entry = IndexEntry {
+                size: image.size,
+                width: image.original_width,
+                height: image.original_height,
+                hash: image.hash.clone(),
+                perceptual: image.perceptual,
+                quality: image.quality,
+                palette: image.palette.clone(),
+                capture: image.capture.clone().unwrap_or_default(),
+            };
+
+            (key.to_string_lossy().into_owned(), entry)
+        })
+        .collect();
+
+    let relative_dir = context.to_relative_path(&directory.absolute_path);
+    let mut index = library.search_index.lock().unwrap();
+
+    index.replace_directory(&relative_dir, entries);
+    index.save()
+}
+
 pub async fn directory_sync_handler(
     extract::Path(dir): extract::Path<String>,
+    extract::Extension(user): extract::Extension<CurrentUser>,
     state: Arc<AppState>,
-) {
+) -> Response<Body> {
     debug!("Request to sync dir {dir}");
 
-    let context = ScannerContext::new(&state.config.root_directory);
-    let full_path = context.to_absolute_path(&dir);
+    let Some((library, path)) = state.resolve(&dir) else {
+        return not_found(&dir);
+    };
+
+    // The sprites and the bundles.json are written next to the images, a
+    // read-only library can only be indexed with `mosaic sync`
+    if library.read_only {
+        return read_only_library(library);
+    }
+
+    if let Some(response) = forbidden(&state, &user, library, path) {
+        return response;
+    }
+
+    let Some(full_path) = library.context().to_sandboxed_path(path) else {
+        return error_response(400, format!("Invalid path: {dir}"));
+    };
+
+    remove_generated_files(&full_path);
+
+    state
+        .command_tx
+        .send(SyncCommand::SyncDirectory(
+            library.name.clone(),
+            path.to_owned(),
+        ))
+        .await
+        .expect("Failed to send internal command");
+
+    Response::builder().body("".into()).unwrap()
+}
 
-    if full_path.join("bundles.json").exists() {
-        let entries = full_path.read_dir().unwrap();
+/// Removes the bundles.json and the thumbnail sprites from an indexed
+/// directory, so that the next scan starts from scratch.
+pub fn remove_generated_files(dir: &Path) {
+    if dir.join("bundles.json").exists() {
+        let entries = dir.read_dir().unwrap();
 
         for entry in entries {
             let entry2 = entry.unwrap();
             let name = entry2.file_name();
 
-            if name == "bundles.json" || name.to_str().unwrap().starts_with("thumbs") {
-                std::fs::remove_file(entry2.path()).expect("Cannot remove file");
+            if Directory::is_generated_file(&name)
+                && let Err(e) = std::fs::remove_file(entry2.path())
+            {
+                error!("Cannot remove {name:?}: {e}");
             }
         }
     }
-
-    state
-        .command_tx
-        .send(SyncCommand::SyncDirectory(context.base_dir, dir))
-        .await
-        .expect("Failed to send internal command");
 }
 
 pub async fn serve_content(
     extract::Path(dir): extract::Path<String>,
+    extract::Query(filter): extract::Query<MetaFilter>,
+    extract::Query(quality): extract::Query<QualityFilter>,
+    headers: HeaderMap,
+    extract::Extension(user): extract::Extension<CurrentUser>,
     state: Arc<AppState>,
 ) -> Response<Body> {
     debug!("Serving path: {dir}");
-    debug!("Root directory: {}", state.config.root_directory);
-
-    let base_path = Path::new(&state.config.root_directory);
-    let mut rel_path = Path::new(&dir);
 
-    if rel_path.is_absolute() {
-        rel_path = rel_path.strip_prefix("/").expect("Cannot join file paths");
+    if dir.trim_matches('/').is_empty() {
+        return list_libraries(&state, &user);
     }
 
-    let full_dir = base_path.join(rel_path);
+    let Some((library, path)) = state.resolve(&dir) else {
+        return not_found(&dir);
+    };
+
+    let Some(full_dir) = library.context().to_sandboxed_path(path) else {
+        return error_response(400, format!("Invalid path: {dir}"));
+    };
 
     debug!("  To filepath: {full_dir:?}");
     debug!("  Is dir?: {}", full_dir.is_dir());
 
     if full_dir.is_dir() {
         if full_dir.join("bundles.json").exists() {
-            let gallery_page = std::fs::read_to_string(&state.config.gallery_index).unwrap();
-            let body: Body = Body::new(gallery_page);
-            let mut response: Response<Body> = Response::builder().body(body).unwrap();
-
-            response
-                .headers_mut()
-                .insert(header::CONTENT_TYPE, HeaderValue::from_static("text/html"));
-
-            response
+            serve_gallery_page(&state)
         } else {
-            list_directory(base_path, &full_dir)
+            let can_sync = !library.read_only && can_change(&state, &user, library, path);
+
+            list_directory(library, &full_dir, can_sync)
         }
     } else {
         debug!("  Serving file: {full_dir:?}");
 
-        serve_file(&full_dir)
+        if full_dir.file_name().is_some_and(|n| n == "bundles.json") {
+            serve_bundles(&state, library, &full_dir, &filter, &quality)
+        } else {
+            stream::serve_file(&full_dir, &headers).await
+        }
     }
 }
 
 pub async fn delete_image(
     extract::Path(dir): extract::Path<String>,
+    extract::Extension(user): extract::Extension<CurrentUser>,
     state: Arc<AppState>,
 ) -> Response<Body> {
-    let base_path = Path::new(&state.config.root_directory);
-    let full_path = base_path.join(dir);
+    let Some((library, path)) = state.resolve(&dir) else {
+        return not_found(&dir);
+    };
+
+    if library.read_only {
+        return read_only_library(library);
+    }
+
+    if let Some(response) = forbidden(&state, &user, library, path) {
+        return response;
+    }
+
+    let Some(full_path) = library.context().to_sandboxed_path(path) else {
+        return error_response(400, format!("Invalid path: {dir}"));
+    };
 
     debug!("Deleting file: {full_path:?}");
 
-    if full_path.exists() && full_path.is_file() {
-        match std::fs::remove_file(&full_path) {
-            Ok(_) => {
-                let body = Body::from(format!("Deleted file: {}", full_path.to_string_lossy()));
-                Response::builder().status(200).body(body).unwrap()
-            }
-            Err(e) => {
-                let body = Body::from(format!(
-                    "Failed to delete file: {}. Error: {}",
-                    full_path.to_string_lossy(),
-                    e
-                ));
-                Response::builder().status(500).body(body).unwrap()
-            }
-        }
-    } else {
+    if !full_path.is_file() {
         let body = Body::from(format!("File not found: {}", full_path.to_string_lossy()));
-        Response::builder().status(404).body(body).unwrap()
+        return Response::builder().status(404).body(body).unwrap();
+    }
+
+    // The same as deleting a list of files, so the RAW sibling goes too
+    match delete_files(&state, &user, &[dir]).1.first() {
+        None => {
+            let body = Body::from(format!("Deleted file: {}", full_path.to_string_lossy()));
+            Response::builder().status(200).body(body).unwrap()
+        }
+        Some((_, e)) => {
+            let body = Body::from(format!(
+                "Failed to delete file: {}. Error: {}",
+                full_path.to_string_lossy(),
+                e
+            ));
+            Response::builder().status(500).body(body).unwrap()
+        }
     }
 }
 
 pub async fn delete_images(
+    extract::Extension(user): extract::Extension<CurrentUser>,
     state: Arc<AppState>,
-    Json(payload): Json<serde_json::Value>,
+    Json(files): Json<Vec<String>>,
 ) -> Response<Body> {
-    // TODO delete the files from bundles.json at least, so that the UI should
-    // not pick up those thumbnails
-    if let serde_json::Value::Array(files) = payload {
-        info!("Files to delete: {files:?}");
-
-        let base_path = Path::new(&state.config.root_directory);
-
-        let files_to_delete: Vec<String> = files
-            .iter()
-            .filter_map(|v| {
-                if let serde_json::Value::String(s) = v {
-                    Some(s.clone())
-                } else {
-                    None
-                }
-            })
-            .collect();
+    info!("Files to delete: {files:?}");
 
-        let mut current_dir = None;
+    let (deleted, failed) = delete_files(&state, &user, &files);
 
-        for file in &files_to_delete {
-            let full_path = base_path.join(file);
+    json_response(200, &DeleteResult { deleted, failed })
+}
 
-            if current_dir.is_none() {
-                current_dir = full_path.parent().map(|p| p.to_path_buf());
-            }
+/// Deletes the files, the paths start with the name of their library. The
+/// deleted files are forgotten and removed from the bundles.json of their
+/// directories. Returns the deleted files and the failed ones with the reason.
+/// The RAW siblings of the JPEGs are deleted with them.
+fn delete_files(
+    state: &AppState,
+    user: &CurrentUser,
+    files: &[String],
+) -> (Vec<String>, Vec<(String, String)>) {
+    remove_files(state, user, files, true, |_, _, path| {
+        std::fs::remove_file(path)
+    })
+}
+
+/// Moves the files into the trash of their library instead of deleting them,
+/// otherwise the same as `delete_files`. The RAW siblings are left alone, they
+/// are only trashed when they are in the files.
+fn trash_files(
+    state: &AppState,
+    user: &CurrentUser,
+    files: &[String],
+) -> (Vec<String>, Vec<(String, String)>) {
+    remove_files(state, user, files, false, |library, key, _| {
+        library.trash(key).map(|target| {
+            info!("Moved {} to {}", library.server_path(key), target.display());
+        })
+    })
+}
+
+/// Removes the files with the function, which gets the library, the key and
+/// the absolute path of each file. With `with_raw` the RAW siblings of the
+/// JPEGs are removed too.
+fn remove_files(
+    state: &AppState,
+    user: &CurrentUser,
+    files: &[String],
+    with_raw: bool,
+    remove: impl Fn(&Library, &str, &Path) -> std::io::Result<()>,
+) -> (Vec<String>, Vec<(String, String)>) {
+    let mut deleted = vec![];
+    let mut failed = vec![];
+    // The directories with the library and the keys of the deleted files
+    let mut directories: Vec<(PathBuf, &Library, Vec<String>)> = vec![];
+    let mut removed: HashSet<PathBuf> = HashSet::new();
+
+    for file in files {
+        let Some((library, path)) = state.resolve(file) else {
+            failed.push((file.clone(), "Not found".to_owned()));
+            continue;
+        };
+
+        if library.read_only {
+            failed.push((file.clone(), "Read-only library".to_owned()));
+            continue;
+        }
+
+        if !can_change(state, user, library, path) {
+            failed.push((file.clone(), "Permission denied".to_owned()));
+            continue;
+        }
+
+        let context = library.context();
+        let (Some(full_path), Some(key)) = (
+            context.to_sandboxed_path(path),
+            context.to_relative_key(path),
+        ) else {
+            failed.push((file.clone(), "Invalid path".to_owned()));
+            continue;
+        };
+
+        // Already removed as the RAW sibling of a JPEG
+        if removed.contains(&full_path) {
+            continue;
+        }
+
+        if !full_path.is_file() {
+            info!("File not found: {}", full_path.to_string_lossy());
+            failed.push((file.clone(), "File not found".to_owned()));
+            continue;
+        }
+
+        // The RAW sibling goes with its JPEG
+        let mut pair = vec![(file.clone(), full_path, key)];
+
+        if with_raw && let Some(raw) = Directory::raw_sibling(&pair[0].1) {
+            let raw_key = context
+                .to_relative_path(&raw)
+                .to_string_lossy()
+                .into_owned();
+
+            pair.push((library.server_path(&raw_key), raw, raw_key));
+        }
+
+        for (file, full_path, key) in pair {
+            match remove(library, &key, &full_path) {
+                Ok(_) => {
+                    info!("Deleted file: {}", full_path.to_string_lossy());
+                    deleted.push(file);
 
-            if full_path.exists() && full_path.is_file() {
-                match std::fs::remove_file(&full_path) {
-                    Ok(_) => info!("Deleted file: {}", full_path.to_string_lossy()),
-                    Err(e) => info!(
+                    let dir = full_path.parent().unwrap().to_path_buf();
+
+                    match directories.iter_mut().find(|(d, _, _)| *d == dir) {
+                        Some((_, _, keys)) => keys.push(key),
+                        None => directories.push((dir, library, vec![key])),
+                    }
+
+                    removed.insert(full_path);
+                }
+                Err(e) => {
+                    info!(
                         "Failed to delete file: {}. Error: {}",
                         full_path.to_string_lossy(),
                         e
-                    ),
+                    );
+                    failed.push((file, e.to_string()));
+                    break;
                 }
-            } else {
-                info!("File not found: {}", full_path.to_string_lossy());
             }
         }
+    }
+
+    for (dir, library, keys) in directories {
+        forget_files(library, &keys);
+
+        let bundles_path = dir.join("bundles.json");
 
-        if let Some(mut current_dir) = current_dir {
-            info!("Current dir: {current_dir:?}");
-            current_dir.push("bundles.json");
-            update_bundles_file(&current_dir, &files_to_delete);
+        if bundles_path.exists() {
+            update_bundles_file(&bundles_path, &keys);
         }
     }
 
-    Response::builder().body("".into()).unwrap()
+    (deleted, failed)
+}
+
+/// Removes the deleted files of the library from the catalog and the search
+/// index, and deletes their own XMP sidecars.
+fn forget_files(library: &Library, files: &[String]) {
+    let context = library.context();
+    let mut catalog = library.catalog.lock().unwrap();
+    let mut index = library.search_index.lock().unwrap();
+    let mut changed = false;
+    let mut index_changed = false;
+
+    for file in files {
+        if let Some(key) = context.to_relative_key(file) {
+            changed |= catalog.remove(&key);
+            index_changed |= index.remove(&key);
+
+            let sidecar = xmp::own_sidecar_path(&context.base_dir.join(&key));
+
+            if sidecar.is_file() {
+                let _ = std::fs::remove_file(sidecar);
+            }
+        }
+    }
+
+    // The files are gone already, the next save writes the catalog
+    if changed && let Err(e) = catalog.save() {
+        error!("{e}");
+    }
+
+    if index_changed && let Err(e) = index.save() {
+        error!("{e}");
+    }
 }
 
-fn update_bundles_file(bundles_path: &PathBuf, dirs_to_delete: &Vec<String>) {
+fn update_bundles_file(bundles_path: &Path, dirs_to_delete: &[String]) {
     info!("Updating bundles file: {bundles_path:?}");
 
-    let content = std::fs::read_to_string(bundles_path).unwrap();
-    let thumbnails: Vec<Thumbnail> = serde_json::from_str(&content).unwrap();
+    let Some(thumbnails) = Thumbnail::read_bundles(bundles_path.parent().unwrap()) else {
+        return;
+    };
 
     let mut new_thumbnails = vec![];
 
@@ -218,55 +633,152 @@ fn update_bundles_file(bundles_path: &PathBuf, dirs_to_delete: &Vec<String>) {
     serde_json::to_writer_pretty(writer, &new_thumbnails).unwrap();
 }
 
-fn list_directory(base: &std::path::Path, dir: &std::path::Path) -> Response<Body> {
+fn json_response<T: Serialize>(status: u16, value: &T) -> Response<Body> {
+    let mut response = Response::builder()
+        .status(status)
+        .body(Body::from(serde_json::to_vec(value).unwrap()))
+        .unwrap();
+
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+
+    response
+}
+
+fn error_response(status: u16, message: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(message))
+        .unwrap()
+}
+
+fn not_found(path: &str) -> Response<Body> {
+    error_response(404, format!("Not found: {path}"))
+}
+
+fn read_only_library(library: &Library) -> Response<Body> {
+    error_response(403, format!("Library {} is read-only", library.name))
+}
+
+/// The response for a change of the path by a user who is not an editor
+/// there.
+fn forbidden(
+    state: &AppState,
+    user: &CurrentUser,
+    library: &Library,
+    path: &str,
+) -> Option<Response<Body>> {
+    if can_change(state, user, library, path) {
+        None
+    } else {
+        Some(error_response(
+            403,
+            format!("Permission denied: {}", library.server_path(path)),
+        ))
+    }
+}
+
+/// True if the user is at least an editor of the path inside the library.
+/// The path is normalized first, so that it matches the rules.
+fn can_change(state: &AppState, user: &CurrentUser, library: &Library, path: &str) -> bool {
+    let key = library.context().to_relative_key(path).unwrap_or_default();
+
+    state.role(user, &library.server_path(&key)) >= Role::Editor
+}
+
+/// The start page with the links of the libraries and the virtual folders.
+fn list_libraries(state: &AppState, user: &CurrentUser) -> Response<Body> {
+    let mut html = String::from("<html><body>");
+
+    for library in &state.libraries {
+        html.push_str(&format!(
+            "<a href=\"/serve/{}/\">{}</a>{}<br/>",
+            escape_url(&library.name),
+            escape_html(&library.name),
+            if library.read_only {
+                " (read-only)"
+            } else {
+                ""
+            }
+        ));
+    }
+
+    html.push_str("<br/><a href=\"/tags/\">Tags</a><br/>");
+    html.push_str("<a href=\"/albums/\">Albums</a><br/>");
+
+    if let Some(name) = &user.name {
+        html.push_str(&format!(
+            "<br/><a href=\"/logout\">Log out {}</a>",
+            escape_html(name)
+        ));
+    }
+
+    html.push_str("</body></html>");
+
+    Response::builder()
+        .header("Content-Type", "text/html")
+        .body(Body::from(html))
+        .unwrap()
+}
+
+/// The subdirectories of a directory without `bundles.json`, with the link to
+/// index it if the user can.
+fn list_directory(library: &Library, dir: &Path, can_sync: bool) -> Response<Body> {
     let mut buffer = Cursor::new(Vec::new());
 
     let mut writer = BufWriter::new(&mut buffer);
 
-    let mut entries: Vec<_> = dir.read_dir().unwrap().map(Result::unwrap).collect();
+    // The hidden entries cannot be opened, they are left out
+    let mut entries: Vec<_> = dir
+        .read_dir()
+        .unwrap()
+        .map(Result::unwrap)
+        .filter(|e| !e.file_name().to_string_lossy().starts_with('.'))
+        .collect();
 
     entries.sort_by(|e1: &DirEntry, e2: &DirEntry| {
         e1.file_name().partial_cmp(&e2.file_name()).unwrap()
     });
 
-    let base_prefix = base.to_str().unwrap();
+    let relative_dir = library.context().to_relative_path(dir);
+    let server_dir = library.server_path(&relative_dir.to_string_lossy());
 
-    let relative_parent = base.parent().unwrap().to_str().unwrap();
+    // The parent of the root of a library is the list of the libraries
+    let parent_link = match relative_dir.parent() {
+        Some(parent) => format!("/serve/{}/", library.server_path(&parent.to_string_lossy())),
+        None => "/serve/".to_owned(),
+    };
 
     let _ = writer.write("<html><body>".as_bytes()).unwrap();
 
     writer
-        .write_fmt(format_args!(
-            "<a href=\"/serve/{:?}\">Parent</a><br/>",
-            relative_parent
-        ))
+        .write_fmt(format_args!("<a href=\"{parent_link}\">Parent</a><br/>"))
         .unwrap();
 
     for entry in &entries {
-        let entry_path = entry.path();
-        let entry_link = entry_path.strip_prefix(base_prefix).unwrap();
-
-        let serve_link = Path::new("/serve").join(entry_link);
-
         writer
             .write_fmt(format_args!(
-                "<a href=\"{}/\">{:?}</a><br/>",
-                serve_link.to_str().unwrap(),
+                "<a href=\"/serve/{server_dir}/{}/\">{:?}</a><br/>",
+                entry.file_name().to_string_lossy(),
                 entry.file_name()
             ))
             .unwrap();
     }
 
-    debug!("  Creating index link {dir:?}");
+    if can_sync {
+        debug!("  Creating index link {dir:?}");
 
-    let sync_link = Path::new("/sync").join(dir.strip_prefix(base_prefix).unwrap());
+        writer
+            .write_fmt(format_args!(
+                "<br><form method=\"post\" action=\"/sync/{server_dir}/\">\
+                 <button type=\"submit\">Index</button></form>"
+            ))
+            .unwrap();
+    }
 
-    writer
-        .write_fmt(format_args!(
-            "<br><a href=\"{}/\">Index</a></body></html>",
-            sync_link.to_str().unwrap()
-        ))
-        .unwrap();
+    let _ = writer.write("</body></html>".as_bytes()).unwrap();
 
     drop(writer);
 
@@ -278,23 +790,185 @@ fn list_directory(base: &std::path::Path, dir: &std::path::Path) -> Response<Bod
     Response::builder().body(body).unwrap()
 }
 
-fn serve_file(path: &std::path::Path) -> Response<Body> {
-    let mut response = Response::builder();
+/// The gallery page renders the `bundles.json` next to it, so it can show
+/// real directories and generated views alike.
+fn serve_gallery_page(state: &AppState) -> Response<Body> {
+    let gallery_page = std::fs::read_to_string(&state.config.gallery_index).unwrap();
+    let body: Body = Body::new(gallery_page);
+    let mut response: Response<Body> = Response::builder().body(body).unwrap();
+
+    response
+        .headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static("text/html"));
+
+    response
+}
+
+/// Looks up the thumbnails of images from any directory of any library by
+/// their paths, in the order of the paths. Images which are not indexed are
+/// left out.
+fn collect_thumbnails<'a>(
+    state: &AppState,
+    paths: impl IntoIterator<Item = &'a str>,
+) -> Vec<Thumbnail> {
+    let mut directories: HashMap<PathBuf, Vec<Thumbnail>> = HashMap::new();
+    let mut thumbnails = vec![];
+
+    for server_path in paths {
+        let Some((library, key)) = state.resolve(server_path) else {
+            continue;
+        };
+
+        let path = library.root.join(key);
+        let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+            continue;
+        };
+
+        let bundles = directories
+            .entry(dir.to_path_buf())
+            .or_insert_with(|| Thumbnail::read_bundles(dir).unwrap_or_default());
+
+        if let Some(t) = bundles.iter().find(|t| *t.original_name == *name) {
+            let mut t = t.clone();
+            t.meta = Some(library.catalog.lock().unwrap().meta(key));
+            t.in_library(&library.name);
+
+            thumbnails.push(t);
+        }
+    }
+
+    thumbnails
+}
+
+/// Serves the bundles.json, see `bundle_thumbnails`.
+fn serve_bundles(
+    state: &AppState,
+    library: &Library,
+    bundles_path: &Path,
+    filter: &MetaFilter,
+    quality: &QualityFilter,
+) -> Response<Body> {
+    match bundle_thumbnails(state, library, bundles_path, filter, quality) {
+        Some(thumbnails) => json_response(200, &thumbnails),
+        None => {
+            // A broken bundles.json is written again by a sync
+            if bundles_path.exists() && !library.read_only {
+                let dir = bundles_path.parent().unwrap();
+                let relative_dir = library.context().to_relative_path(dir);
+                let command = SyncCommand::SyncDirectory(
+                    library.name.clone(),
+                    relative_dir.to_string_lossy().into_owned(),
+                );
+
+                if state.command_tx.try_send(command).is_err() {
+                    error!("Cannot queue the sync of {dir:?}");
+                }
+            }
+
+            error_response(404, format!("File not found: {bundles_path:?}"))
+        }
+    }
+}
+
+/// The thumbnails of the bundles.json with the user metadata merged in from
+/// the catalog and the quality scores from the search index, leaving out the
+/// images which don't match the filters.
+fn bundle_thumbnails(
+    state: &AppState,
+    library: &Library,
+    bundles_path: &Path,
+    filter: &MetaFilter,
+    quality: &QualityFilter,
+) -> Option<Vec<Thumbnail>> {
+    let context = library.context();
+    let dir = bundles_path.parent().unwrap();
+    let thumbnails = Thumbnail::read_bundles(dir)?;
+
+    let relative_dir = context.to_relative_path(dir);
+    let catalog = library.catalog.lock().unwrap();
+
+    let mut thumbnails: Vec<Thumbnail> = thumbnails
+        .into_iter()
+        .filter_map(|mut t| {
+            let key = relative_dir.join(&t.original_name);
+            let meta = catalog.meta(&key.to_string_lossy());
+
+            if !filter.matches(&meta) {
+                return None;
+            }
+
+            t.meta = Some(meta);
+
+            Some(t)
+        })
+        .collect();
+
+    drop(catalog);
 
-    response = match path
-        .extension()
-        .unwrap()
-        .to_ascii_lowercase()
-        .to_str()
-        .unwrap()
     {
-        "jpg" => response.header("Content-Type", "image/jpeg"),
-        "jpeg" => response.header("Content-Type", "image/jpeg"),
-        "json" => response.header("Content-Type", "application/json"),
-        _ => todo!(),
-    };
+        let index = library.search_index.lock().unwrap();
+
+        thumbnails.retain_mut(|t| {
+            let key = relative_dir.join(&t.original_name);
+            let scores = index
+                .entries
+                .get(key.to_string_lossy().as_ref())
+                .and_then(|e| e.quality);
+
+            t.quality = scores;
+            t.issues = scores.map(|s| s.issues()).unwrap_or_default();
+
+            quality.matches(scores.as_ref())
+        });
+    }
+
+    similar::assign_bursts(state, library, &relative_dir, &mut thumbnails);
+
+    if quality.sort.is_some() {
+        thumbnails.sort_by(|a, b| {
+            let a = quality.sort_key(a.quality.as_ref());
+            let b = quality.sort_key(b.quality.as_ref());
+
+            match (a, b) {
+                (Some(a), Some(b)) => a.total_cmp(&b),
+                (a, b) => b.is_some().cmp(&a.is_some()),
+            }
+        });
+    }
+
+    for t in &mut thumbnails {
+        t.in_library(&library.name);
+    }
+
+    Some(thumbnails)
+}
 
-    let content = std::fs::read(path).unwrap();
+/// The value of the cookie in the request.
+fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(n, _)| *n == name)
+        .map(|(_, value)| value)
+}
 
-    response.body(content.into()).unwrap()
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn escape_url(text: &str) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
 }
@@ -0,0 +1,180 @@
+use std::sync::Arc;
+
+use axum::{body::Body, extract, response::Response};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AppState,
+    api::{collect_thumbnails, json_response, serve_gallery_page},
+    catalog::{index::IndexEntry, metadata::ImageMeta},
+    thumbnail::{
+        bundle::Thumbnail,
+        palette::{palette_matches, parse_color},
+    },
+};
+
+const DEFAULT_PER_PAGE: usize = 100;
+const MAX_PER_PAGE: usize = 1000;
+
+/// The search criteria, all of them have to match. The text criteria are
+/// case insensitive, the dates are compared as `YYYY-MM-DDTHH:MM:SS` prefixes,
+/// so `from=2024` or `to=2024-06-30` work as well.
+#[derive(Debug, Default, Deserialize)]
+pub struct SearchParams {
+    /// Matches the file name, the tags and the title of the image.
+    pub q: Option<String>,
+    pub tag: Option<String>,
+    pub camera: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub min_width: Option<u32>,
+    pub max_width: Option<u32>,
+    pub min_height: Option<u32>,
+    pub max_height: Option<u32>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub min_rating: Option<u8>,
+    /// A color as `#rrggbb` or a basic color name, matched against the main
+    /// colors of the images.
+    pub color: Option<String>,
+    /// Starts from 1.
+    pub page: Option<usize>,
+    pub per_page: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchResults {
+    pub total: usize,
+    pub page: usize,
+    pub per_page: usize,
+    pub results: Vec<Thumbnail>,
+}
+
+pub async fn search(
+    extract::Query(params): extract::Query<SearchParams>,
+    state: Arc<AppState>,
+) -> Response<Body> {
+    json_response(200, &run_search(&state, &params))
+}
+
+/// Serves the search results as a virtual folder: `/search/?q=...` is the
+/// gallery page which loads `/search/bundles.json?q=...`.
+pub async fn serve_search_view(
+    extract::Path(path): extract::Path<String>,
+    extract::Query(params): extract::Query<SearchParams>,
+    state: Arc<AppState>,
+) -> Response<Body> {
+    if path.trim_start_matches('/') == "bundles.json" {
+        json_response(200, &run_search(&state, &params).results)
+    } else {
+        serve_gallery_page(&state)
+    }
+}
+
+fn run_search(state: &AppState, params: &SearchParams) -> SearchResults {
+    debug!("Search: {params:?}");
+
+    let per_page = params
+        .per_page
+        .unwrap_or(DEFAULT_PER_PAGE)
+        .clamp(1, MAX_PER_PAGE);
+    let page = params.page.unwrap_or(1).max(1);
+
+    let mut keys: Vec<String> = vec![];
+
+    for library in &state.libraries {
+        let catalog = library.catalog.lock().unwrap();
+        let index = library.search_index.lock().unwrap();
+
+        keys.extend(
+            index
+                .entries
+                .iter()
+                .filter(|(key, entry)| matches(params, key, entry, &catalog.meta(key)))
+                .map(|(key, _)| library.server_path(key)),
+        );
+    }
+
+    let page_keys = keys.iter().skip((page - 1) * per_page).take(per_page);
+
+    SearchResults {
+        total: keys.len(),
+        page,
+        per_page,
+        results: collect_thumbnails(state, page_keys.map(String::as_str)),
+    }
+}
+
+fn matches(params: &SearchParams, key: &str, entry: &IndexEntry, meta: &ImageMeta) -> bool {
+    if let Some(q) = non_empty(&params.q) {
+        let q = q.to_lowercase();
+        let file_name = key.rsplit('/').next().unwrap_or(key);
+
+        let found = file_name.to_lowercase().contains(&q)
+            || meta.keywords.iter().any(|k| k.to_lowercase().contains(&q))
+            || meta
+                .title
+                .as_ref()
+                .is_some_and(|t| t.to_lowercase().contains(&q));
+
+        if !found {
+            return false;
+        }
+    }
+
+    if let Some(tag) = non_empty(&params.tag)
+        && !meta.keywords.iter().any(|k| k.eq_ignore_ascii_case(tag))
+    {
+        return false;
+    }
+
+    if let Some(camera) = non_empty(&params.camera) {
+        let camera = camera.to_lowercase();
+
+        if !entry
+            .capture
+            .camera
+            .as_ref()
+            .is_some_and(|c| c.to_lowercase().contains(&camera))
+        {
+            return false;
+        }
+    }
+
+    if non_empty(&params.from).is_some() || non_empty(&params.to).is_some() {
+        let Some(taken_at) = &entry.capture.taken_at else {
+            return false;
+        };
+
+        if non_empty(&params.from).is_some_and(|from| taken_at.as_str() < from) {
+            return false;
+        }
+
+        // The end of the range is inclusive on the precision it is given
+        if non_empty(&params.to).is_some_and(|to| taken_at.get(..to.len()).unwrap_or(taken_at) > to)
+        {
+            return false;
+        }
+    }
+
+    // An unknown color doesn't match anything
+    if let Some(color) = non_empty(&params.color)
+        && !parse_color(color).is_some_and(|c| palette_matches(&entry.palette, c))
+    {
+        return false;
+    }
+
+    in_range(entry.width, params.min_width, params.max_width)
+        && in_range(entry.height, params.min_height, params.max_height)
+        && in_range(entry.size, params.min_size, params.max_size)
+        && params.min_rating.is_none_or(|r| meta.rating >= r)
+}
+
+fn in_range<T: PartialOrd>(value: T, min: Option<T>, max: Option<T>) -> bool {
+    min.is_none_or(|min| value >= min) && max.is_none_or(|max| value <= max)
+}
+
+fn non_empty(value: &Option<String>) -> Option<&str> {
+    value.as_deref().map(str::trim).filter(|v| !v.is_empty())
+}
@@ -0,0 +1,718 @@
+use std::{collections::HashSet, path::Path, sync::Arc};
+
+use axum::{
+    Form, Json,
+    body::Body,
+    extract::{self, Multipart},
+    response::Response,
+};
+use http::{HeaderMap, header};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AppState,
+    api::{
+        albums, bundle_thumbnails, collect_thumbnails, cookie, download, error_response,
+        escape_html, escape_url, forbidden, json_response, not_found, read_only_library,
+        serve_gallery_page, stream,
+        upload::{self, UploadParams},
+    },
+    auth::CurrentUser,
+    catalog::metadata::MetaFilter,
+    config::Role,
+    share::{Share, ShareAccess, ShareError, ShareTarget},
+    thumbnail::{capture::format_timestamp, quality::QualityFilter},
+};
+
+/// The name of the cookie proving that the password of a share was given,
+/// it is only sent under the path of its link.
+const UNLOCK_COOKIE: &str = "mosaic_share";
+
+/// How long a link is valid if the request does not say.
+const DEFAULT_HOURS: u64 = 7 * 24;
+
+/// Shares a directory, given as `library/dir`, or an album.
+#[derive(Debug, Deserialize)]
+pub struct NewShare {
+    pub path: Option<String>,
+    pub album: Option<String>,
+    pub hours: Option<u64>,
+    #[serde(default)]
+    pub access: ShareAccess,
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreatedShare {
+    /// Revokes the link.
+    pub id: String,
+    pub url: String,
+    pub expires_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SharePermissions {
+    pub upload: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnlockForm {
+    pub password: String,
+}
+
+pub async fn create_share(
+    extract::Extension(user): extract::Extension<CurrentUser>,
+    state: Arc<AppState>,
+    Json(request): Json<NewShare>,
+) -> Response<Body> {
+    let Some(shares) = &state.shares else {
+        return sharing_disabled();
+    };
+
+    let target = match (&request.path, &request.album) {
+        (Some(dir), None) => {
+            let Some((library, path)) = state.resolve(dir) else {
+                return not_found(dir);
+            };
+
+            if let Some(response) = forbidden(&state, &user, library, path) {
+                return response;
+            }
+
+            if request.access == ShareAccess::Upload && library.read_only {
+                return read_only_library(library);
+            }
+
+            let context = library.context();
+            let (Some(full_path), Some(key)) = (
+                context.to_sandboxed_path(path),
+                context.to_relative_key(path),
+            ) else {
+                return error_response(400, format!("Invalid path: {dir}"));
+            };
+
+            if !full_path.is_dir() {
+                return error_response(404, format!("Directory not found: {dir}"));
+            }
+
+            ShareTarget::Directory(library.server_path(&key))
+        }
+        (None, Some(name)) => {
+            if let Some(response) = albums::forbidden_album_change(&state, &user) {
+                return response;
+            }
+
+            if request.access == ShareAccess::Upload {
+                return error_response(400, "Only directories can take uploads".to_owned());
+            }
+
+            if albums::album_images(&state, name).is_none() {
+                return error_response(404, format!("Album not found: {name}"));
+            }
+
+            ShareTarget::Album(name.clone())
+        }
+        _ => return error_response(400, "Either a path or an album is needed".to_owned()),
+    };
+
+    let hours = request
+        .hours
+        .unwrap_or(DEFAULT_HOURS.min(shares.max_hours()));
+
+    if hours == 0 || hours > shares.max_hours() {
+        return error_response(
+            400,
+            format!("The hours must be between 1 and {}", shares.max_hours()),
+        );
+    }
+
+    let password = request.password.as_deref().filter(|p| !p.is_empty());
+    let (share, token) = shares.create(target, hours, request.access, user.name, password);
+
+    info!(
+        "Share {} of {:?} created by {}",
+        share.id,
+        share.target,
+        share.user.as_deref().unwrap_or("anonymous")
+    );
+
+    json_response(
+        201,
+        &CreatedShare {
+            id: share.id,
+            url: format!("/s/{token}"),
+            expires_at: format_timestamp(share.expires as i64),
+        },
+    )
+}
+
+pub async fn revoke_share(
+    extract::Path(id): extract::Path<String>,
+    extract::Extension(user): extract::Extension<CurrentUser>,
+    state: Arc<AppState>,
+) -> Response<Body> {
+    let Some(shares) = &state.shares else {
+        return sharing_disabled();
+    };
+
+    // The link is not at hand, so only its id tells which share it was
+    if state.role(&user, "") < Role::Editor {
+        return error_response(403, "Permission denied".to_owned());
+    }
+
+    match shares.revoke(&id) {
+        Ok(()) => json_response(200, &id),
+        Err(e) => error_response(500, e),
+    }
+}
+
+/// The files and the gallery of the share, the paths are relative to the
+/// shared directory. An album is shown at the root with its files under
+/// their own paths.
+pub async fn serve_shared(
+    extract::Path((token, path)): extract::Path<(String, String)>,
+    extract::Query(filter): extract::Query<MetaFilter>,
+    extract::Query(quality): extract::Query<QualityFilter>,
+    headers: HeaderMap,
+    state: Arc<AppState>,
+) -> Response<Body> {
+    let share = match open_share(&state, &token, &headers) {
+        Ok(share) => share,
+        Err(e) => return share_error(e, &token),
+    };
+
+    if let ShareTarget::Album(name) = &share.target {
+        match path.trim_start_matches('/') {
+            "" => return serve_gallery_page(&state),
+            "bundles.json" => {
+                let images = albums::album_images(&state, name).unwrap_or_default();
+
+                return json_response(
+                    200,
+                    &collect_thumbnails(&state, images.iter().map(String::as_str)),
+                );
+            }
+            _ => {}
+        }
+    }
+
+    let Some(server_path) = shared_path(&state, &share, &path) else {
+        return not_shared(&path);
+    };
+    let Some((library, relative_path)) = state.resolve(&server_path) else {
+        return not_shared(&path);
+    };
+    let Some(full_path) = library.context().to_sandboxed_path(relative_path) else {
+        return error_response(400, format!("Invalid path: {path}"));
+    };
+
+    if full_path.is_dir() {
+        if full_path.join("bundles.json").exists() {
+            serve_gallery_page(&state)
+        } else {
+            list_shared_directory(&full_path)
+        }
+    } else if full_path.file_name().is_some_and(|n| n == "bundles.json") {
+        let Some(mut thumbnails) =
+            bundle_thumbnails(&state, library, &full_path, &filter, &quality)
+        else {
+            return not_shared(&path);
+        };
+
+        // The gallery asks for the files relative to the shared directory
+        if let ShareTarget::Directory(dir) = &share.target {
+            let prefix = format!("{dir}/");
+
+            for t in &mut thumbnails {
+                if let Some(rest) = t.relative_base_path.strip_prefix(&prefix) {
+                    t.relative_base_path = rest.to_owned();
+                }
+            }
+        }
+
+        json_response(200, &thumbnails)
+    } else {
+        stream::serve_file(&full_path, &headers).await
+    }
+}
+
+pub async fn serve_shared_preview(
+    extract::Path((token, path)): extract::Path<(String, String)>,
+    headers: HeaderMap,
+    state: Arc<AppState>,
+) -> Response<Body> {
+    let share = match open_share(&state, &token, &headers) {
+        Ok(share) => share,
+        Err(e) => return share_error(e, &token),
+    };
+
+    let Some(server_path) = shared_path(&state, &share, &path) else {
+        return not_shared(&path);
+    };
+
+    stream::serve_preview(extract::Path(server_path), headers, state).await
+}
+
+/// Downloads the shared directory or the images of the shared album, the
+/// path is the path of the directory with a `.zip` extension.
+pub async fn download_shared(
+    extract::Path((token, path)): extract::Path<(String, String)>,
+    headers: HeaderMap,
+    state: Arc<AppState>,
+) -> Response<Body> {
+    let share = match open_share(&state, &token, &headers) {
+        Ok(share) => share,
+        Err(e) => return share_error(e, &token),
+    };
+
+    if let ShareTarget::Album(name) = &share.target {
+        let images = albums::album_images(&state, name).unwrap_or_default();
+
+        return download::download_files(state, Json(images)).await;
+    }
+
+    let Some(dir) = path.strip_suffix(".zip") else {
+        return error_response(404, format!("Not an archive: {path}"));
+    };
+    let Some(server_path) = shared_path(&state, &share, dir) else {
+        return not_shared(&path);
+    };
+
+    download::download_directory(extract::Path(format!("{server_path}.zip")), state).await
+}
+
+/// Downloads the selected files of the share, the body is a list of paths
+/// relative to the shared directory.
+pub async fn download_shared_files(
+    extract::Path(token): extract::Path<String>,
+    headers: HeaderMap,
+    state: Arc<AppState>,
+    Json(files): Json<Vec<String>>,
+) -> Response<Body> {
+    let share = match open_share(&state, &token, &headers) {
+        Ok(share) => share,
+        Err(e) => return share_error(e, &token),
+    };
+
+    let mut server_paths = vec![];
+
+    for file in files {
+        let Some(server_path) = shared_path(&state, &share, &file) else {
+            return not_shared(&file);
+        };
+
+        server_paths.push(server_path);
+    }
+
+    download::download_files(state, Json(server_paths)).await
+}
+
+/// Uploads into a directory shared for uploads, with the role of the user
+/// who shared it.
+pub async fn upload_shared(
+    extract::Path((token, path)): extract::Path<(String, String)>,
+    extract::Query(params): extract::Query<UploadParams>,
+    headers: HeaderMap,
+    state: Arc<AppState>,
+    multipart: Multipart,
+) -> Response<Body> {
+    let share = match open_share(&state, &token, &headers) {
+        Ok(share) => share,
+        Err(e) => return share_error(e, &token),
+    };
+
+    if share.access != ShareAccess::Upload {
+        return error_response(403, "The share is read-only".to_owned());
+    }
+
+    let Some(server_path) = shared_path(&state, &share, &path) else {
+        return not_shared(&path);
+    };
+    let user = CurrentUser { name: share.user };
+
+    upload::upload_files(
+        extract::Path(server_path),
+        extract::Query(params),
+        extract::Extension(user),
+        state,
+        multipart,
+    )
+    .await
+}
+
+/// What the gallery can do with the share.
+pub async fn shared_permissions(
+    extract::Path(token): extract::Path<String>,
+    headers: HeaderMap,
+    state: Arc<AppState>,
+) -> Response<Body> {
+    match open_share(&state, &token, &headers) {
+        Ok(share) => json_response(
+            200,
+            &SharePermissions {
+                upload: share.access == ShareAccess::Upload,
+            },
+        ),
+        Err(e) => share_error(e, &token),
+    }
+}
+
+/// Checks the password of the share, the cookie set for the path of the link
+/// lets the browser in until the link expires.
+pub async fn unlock_share(
+    extract::Path(token): extract::Path<String>,
+    state: Arc<AppState>,
+    Form(form): Form<UnlockForm>,
+) -> Response<Body> {
+    let Some(shares) = &state.shares else {
+        return sharing_disabled();
+    };
+
+    let share = match shares.open(&token) {
+        Ok(share) => share,
+        Err(e) => return share_error(e, &token),
+    };
+
+    if !shares.check_password(&share, &form.password) {
+        warn!("Wrong password for share {}", share.id);
+
+        return password_form(401, &token, Some("Wrong password"));
+    }
+
+    let max_age = share.seconds_left();
+    let cookie = format!(
+        "{UNLOCK_COOKIE}={}; Path=/s/{token}; HttpOnly; SameSite=Lax; Max-Age={max_age}",
+        shares.unlock_token(&share)
+    );
+
+    Response::builder()
+        .status(303)
+        .header(header::LOCATION, format!("/s/{token}/serve/"))
+        .header(header::SET_COOKIE, cookie)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// The share of the token if it can be used. Without sharing there are no
+/// valid tokens.
+fn open_share(state: &AppState, token: &str, headers: &HeaderMap) -> Result<Share, ShareError> {
+    let Some(shares) = &state.shares else {
+        return Err(ShareError::Invalid);
+    };
+
+    let share = shares.open(token)?;
+
+    if !shares.is_unlocked(&share, cookie(headers, UNLOCK_COOKIE)) {
+        return Err(ShareError::Locked);
+    }
+
+    Ok(share)
+}
+
+/// The path of the clients for a path of the share, `None` if the share does
+/// not have it.
+fn shared_path(state: &AppState, share: &Share, path: &str) -> Option<String> {
+    let path = path.trim_matches('/');
+
+    match &share.target {
+        ShareTarget::Directory(dir) if path.is_empty() => Some(dir.clone()),
+        ShareTarget::Directory(dir) => Some(format!("{dir}/{path}")),
+        ShareTarget::Album(name) => album_files(state, name)
+            .contains(path)
+            .then(|| path.to_owned()),
+    }
+}
+
+/// The images of the album with their RAW siblings and their thumbnails.
+fn album_files(state: &AppState, name: &str) -> HashSet<String> {
+    let images = albums::album_images(state, name).unwrap_or_default();
+
+    collect_thumbnails(state, images.iter().map(String::as_str))
+        .iter()
+        .flat_map(|t| {
+            std::iter::once(&t.original_name)
+                .chain(&t.raw_name)
+                .map(String::as_str)
+                .chain(t.generated_files())
+                .map(|name| format!("{}{name}", t.relative_base_path))
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// The visible entries of a shared directory without `bundles.json`, the
+/// links are relative so they stay in the share.
+fn list_shared_directory(dir: &Path) -> Response<Body> {
+    let mut entries: Vec<_> = dir
+        .read_dir()
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|e| !e.file_name().to_string_lossy().starts_with('.'))
+        .collect();
+
+    entries.sort_by_key(|e| e.file_name());
+
+    let mut html = String::from("<html><body>");
+
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let slash = if entry.path().is_dir() { "/" } else { "" };
+
+        html.push_str(&format!(
+            "<a href=\"{}{slash}\">{}{slash}</a><br/>",
+            escape_url(&name),
+            escape_html(&name)
+        ));
+    }
+
+    html.push_str("</body></html>");
+
+    Response::builder()
+        .header("Content-Type", "text/html")
+        .body(Body::from(html))
+        .unwrap()
+}
+
+fn password_form(status: u16, token: &str, error: Option<&str>) -> Response<Body> {
+    let mut html = format!("<html><body><form method=\"post\" action=\"/s/{token}/login\">");
+
+    if let Some(error) = error {
+        html.push_str(&format!("<p>{}</p>", escape_html(error)));
+    }
+
+    html.push_str("<label>Password <input name=\"password\" type=\"password\" autofocus/></label>");
+    html.push_str("<button type=\"submit\">Open</button></form></body></html>");
+
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "text/html")
+        .body(Body::from(html))
+        .unwrap()
+}
+
+/// The response refusing the token, a share with a password asks for it.
+fn share_error(error: ShareError, token: &str) -> Response<Body> {
+    match error {
+        ShareError::Invalid => error_response(404, "Share not found".to_owned()),
+        ShareError::Expired => error_response(410, "The share has expired".to_owned()),
+        ShareError::Revoked => error_response(410, "The share was revoked".to_owned()),
+        ShareError::Locked => password_form(401, token, None),
+    }
+}
+
+fn sharing_disabled() -> Response<Body> {
+    error_response(404, "Sharing is not enabled".to_owned())
+}
+
+fn not_shared(path: &str) -> Response<Body> {
+    error_response(404, format!("Not in the share: {path}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{Router, body::to_bytes, extract::Request};
+    use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+    use http::Method;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        api::test_helpers::{TempDir, get, json_request, request, status},
+        config::{Config, ShareConfig},
+    };
+
+    /// The state of a server without auth, its root has `a/one.jpg` and
+    /// `b/two.jpg`.
+    fn state(dir: &TempDir) -> Arc<AppState> {
+        for path in ["a/one.jpg", "b/two.jpg"] {
+            dir.image(path);
+        }
+
+        let shares = ShareConfig {
+            secret_file: None,
+            revoked_file: None,
+            max_hours: 24,
+        };
+        let config = Config {
+            root_directory: dir.path().to_string_lossy().into_owned(),
+            shares: Some(shares),
+            ..Config::default()
+        };
+
+        Arc::new(AppState::new(config).unwrap().0)
+    }
+
+    /// Shares with the JSON body, returns the id and the URL of the link.
+    async fn share(app: &Router, body: &str) -> (String, String) {
+        let response = app
+            .clone()
+            .oneshot(json_request(Method::POST, "/api/shares", None, body))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 201);
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let created: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        (
+            created["id"].as_str().unwrap().to_owned(),
+            created["url"].as_str().unwrap().to_owned(),
+        )
+    }
+
+    #[tokio::test]
+    async fn only_the_shared_directory_is_served() {
+        let dir = TempDir::new("subtree");
+        let app = crate::app(state(&dir));
+        let (_, url) = share(&app, r#"{"path": "main/a"}"#).await;
+
+        assert_eq!(
+            status(&app, get(&format!("{url}/serve/one.jpg"), None)).await,
+            200
+        );
+        assert_eq!(
+            status(&app, get(&format!("{url}/serve/../b/two.jpg"), None)).await,
+            404
+        );
+        assert_eq!(
+            status(
+                &app,
+                get(&format!("{url}/serve/.mosaic/catalog.json"), None)
+            )
+            .await,
+            404
+        );
+
+        // The signature covers the path
+        let (payload, signature) = url.trim_start_matches("/s/").split_once('.').unwrap();
+        let payload = URL_SAFE_NO_PAD.decode(payload).unwrap();
+        let payload = String::from_utf8(payload)
+            .unwrap()
+            .replace("main/a", "main/b");
+        let forged = format!("/s/{}.{signature}", URL_SAFE_NO_PAD.encode(payload));
+
+        assert_eq!(
+            status(&app, get(&format!("{forged}/serve/two.jpg"), None)).await,
+            404
+        );
+    }
+
+    #[tokio::test]
+    async fn the_files_of_the_server_are_not_served() {
+        let dir = TempDir::new("server-files");
+        let app = crate::app(state(&dir));
+
+        std::fs::write(dir.join("mosaic.toml"), "port = 3000").unwrap();
+
+        // The secret is kept in the cache directory of the library
+        assert!(dir.join(".mosaic/share_secret").is_file());
+
+        for uri in [
+            "/serve/main/mosaic.toml",
+            "/serve/main/.mosaic/share_secret",
+            "/serve/main/a/../.mosaic/share_secret",
+            "/preview/main/mosaic.toml",
+        ] {
+            assert_eq!(status(&app, get(uri, None)).await, 404, "{uri}");
+        }
+
+        assert_eq!(status(&app, get("/serve/main/a/one.jpg", None)).await, 200);
+
+        let response = app.oneshot(get("/serve/main/", None)).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let listing = String::from_utf8(body.to_vec()).unwrap();
+
+        assert!(listing.contains("/serve/main/a/"));
+        assert!(!listing.contains(".mosaic"));
+    }
+
+    #[tokio::test]
+    async fn shares_are_read_only_by_default() {
+        let dir = TempDir::new("read-only");
+        let app = crate::app(state(&dir));
+        let (_, url) = share(&app, r#"{"path": "main/a"}"#).await;
+        let upload = Request::builder()
+            .method(Method::POST)
+            .uri(format!("{url}/api/upload/"))
+            .header(header::CONTENT_TYPE, "multipart/form-data; boundary=X")
+            .body(Body::from("--X--\r\n"))
+            .unwrap();
+
+        assert_eq!(status(&app, upload).await, 403);
+
+        let response = app
+            .oneshot(get(&format!("{url}/api/permissions"), None))
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+        assert_eq!(&body[..], br#"{"upload":false}"#);
+    }
+
+    #[tokio::test]
+    async fn password_unlocks_the_share() {
+        let dir = TempDir::new("password");
+        let app = crate::app(state(&dir));
+        let (_, url) = share(&app, r#"{"path": "main/a", "password": "open sesame"}"#).await;
+        let file = format!("{url}/serve/one.jpg");
+
+        assert_eq!(status(&app, get(&file, None)).await, 401);
+
+        let unlock = |password: &str| {
+            Request::builder()
+                .method(Method::POST)
+                .uri(format!("{url}/login"))
+                .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .body(Body::from(format!("password={password}")))
+                .unwrap()
+        };
+
+        assert_eq!(status(&app, unlock("wrong")).await, 401);
+
+        let response = app.clone().oneshot(unlock("open+sesame")).await.unwrap();
+        assert_eq!(response.status(), 303);
+
+        let cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+        assert!(cookie.contains(&format!("Path={url};")));
+
+        let mut request = get(&file, None);
+        request.headers_mut().insert(
+            header::COOKIE,
+            cookie.split(';').next().unwrap().parse().unwrap(),
+        );
+
+        assert_eq!(status(&app, request).await, 200);
+    }
+
+    #[tokio::test]
+    async fn revoked_and_expired_shares_are_gone() {
+        let dir = TempDir::new("revoked");
+        let state = state(&dir);
+        let app = crate::app(Arc::clone(&state));
+        let (id, url) = share(&app, r#"{"path": "main/a"}"#).await;
+        let file = format!("{url}/serve/one.jpg");
+
+        assert_eq!(status(&app, get(&file, None)).await, 200);
+
+        let revoke = request(Method::DELETE, &format!("/api/shares/{id}"), None);
+        assert_eq!(status(&app, revoke).await, 200);
+        assert_eq!(status(&app, get(&file, None)).await, 410);
+
+        let shares = state.shares.as_ref().unwrap();
+        let target = ShareTarget::Directory("main/a".to_owned());
+        let (_, token) = shares.create(target, 0, ShareAccess::View, None, None);
+
+        assert_eq!(shares.open(&token).unwrap_err(), ShareError::Expired);
+
+        let too_long = json_request(
+            Method::POST,
+            "/api/shares",
+            None,
+            r#"{"path": "main/a", "hours": 25}"#,
+        );
+        assert_eq!(status(&app, too_long).await, 400);
+    }
+}
@@ -0,0 +1,121 @@
+use std::{path::Path, sync::Arc};
+
+use axum::{body::Body, extract, response::Response};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AppState,
+    api::{collect_thumbnails, error_response, json_response, not_found},
+    library::Library,
+    thumbnail::{bundle::Thumbnail, phash::HashKind},
+};
+
+#[derive(Debug, Deserialize)]
+pub struct SimilarParams {
+    /// The maximum Hamming distance, the configured one by default.
+    pub threshold: Option<u32>,
+    #[serde(default)]
+    pub hash: HashKind,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SimilarImage {
+    pub distance: u32,
+    #[serde(flatten)]
+    pub thumbnail: Thumbnail,
+}
+
+/// The images of the library of the image which look similar to it, the
+/// closest ones first.
+pub async fn similar_images(
+    extract::Path(path): extract::Path<String>,
+    extract::Query(params): extract::Query<SimilarParams>,
+    state: Arc<AppState>,
+) -> Response<Body> {
+    let Some((library, file)) = state.resolve(&path) else {
+        return not_found(&path);
+    };
+
+    let Some(key) = library.context().to_relative_key(file) else {
+        return error_response(400, format!("Invalid path: {path}"));
+    };
+
+    let threshold = params
+        .threshold
+        .unwrap_or(state.config.similarity_threshold);
+
+    let similar: Vec<(String, u32)> = {
+        let index = library.search_index.lock().unwrap();
+
+        if !index.entries.contains_key(&key) {
+            return error_response(404, format!("Image is not indexed: {path}"));
+        }
+
+        index
+            .similar(&key, params.hash, threshold)
+            .into_iter()
+            .map(|(key, distance)| (library.server_path(&key), distance))
+            .collect()
+    };
+
+    let thumbnails = collect_thumbnails(&state, similar.iter().map(|(k, _)| k.as_str()));
+
+    let results: Vec<SimilarImage> = thumbnails
+        .into_iter()
+        .filter_map(|t| {
+            let key = format!("{}{}", t.relative_base_path, t.original_name);
+            let (_, distance) = similar.iter().find(|(k, _)| *k == key)?;
+
+            Some(SimilarImage {
+                distance: *distance,
+                thumbnail: t,
+            })
+        })
+        .collect();
+
+    json_response(200, &results)
+}
+
+/// Marks the bursts among the thumbnails of a directory: runs of consecutive
+/// images which are within the similarity threshold of the previous one.
+/// Images which are not part of a burst don't get a group.
+pub(super) fn assign_bursts(
+    state: &AppState,
+    library: &Library,
+    relative_dir: &Path,
+    thumbnails: &mut [Thumbnail],
+) {
+    let index = library.search_index.lock().unwrap();
+    let threshold = state.config.similarity_threshold;
+
+    let hashes: Vec<_> = thumbnails
+        .iter()
+        .map(|t| {
+            let key = relative_dir.join(&t.original_name);
+
+            index
+                .entries
+                .get(key.to_string_lossy().as_ref())
+                .and_then(|e| e.perceptual)
+        })
+        .collect();
+
+    let mut group = 0;
+
+    for i in 1..thumbnails.len() {
+        let (Some(previous), Some(current)) = (hashes[i - 1], hashes[i]) else {
+            continue;
+        };
+
+        if current.distance(&previous, HashKind::Phash) > threshold {
+            continue;
+        }
+
+        if thumbnails[i - 1].group.is_none() {
+            group += 1;
+            thumbnails[i - 1].group = Some(group);
+        }
+
+        thumbnails[i].group = Some(group);
+    }
+}
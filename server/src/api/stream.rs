@@ -0,0 +1,236 @@
+use std::{io::SeekFrom, path::Path, sync::Arc};
+
+use axum::{
+    body::{Body, Bytes},
+    extract,
+    response::Response,
+};
+use http::{HeaderMap, HeaderValue, header};
+use log::{debug, error};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+    sync::mpsc,
+};
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::{
+    AppState,
+    api::{error_response, not_found},
+    scanner::directory::{Directory, MediaType},
+    thumbnail::{heif, raw::RawPreview},
+};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Serves a file of the library. A single byte range is supported, so the
+/// browsers can seek in the videos without downloading them. The HEIF images
+/// are transcoded to JPEG for the browsers which don't accept them.
+pub(super) async fn serve_file(path: &Path, headers: &HeaderMap) -> Response<Body> {
+    // Only the media and the thumbnails, not the other files which may be
+    // next to them like the config of the server
+    let generated = path.file_name().is_some_and(Directory::is_generated_file);
+
+    if MediaType::from_path(path).is_none() && !generated {
+        return error_response(404, format!("File not found: {path:?}"));
+    }
+
+    let Ok(metadata) = path.metadata() else {
+        return error_response(404, format!("File not found: {path:?}"));
+    };
+
+    if Directory::has_heif_extension(path) && !accepts_heif(headers) {
+        let heif_path = path.to_path_buf();
+        let jpeg = tokio::task::spawn_blocking(move || heif::transcode_to_jpeg(&heif_path))
+            .await
+            .ok()
+            .flatten();
+
+        // The original file is better than nothing
+        if let Some(jpeg) = jpeg {
+            debug!("  Transcoded {path:?} to JPEG");
+
+            return Response::builder()
+                .header(header::CONTENT_TYPE, "image/jpeg")
+                .header(header::CONTENT_LENGTH, jpeg.len())
+                .body(Body::from(jpeg))
+                .unwrap();
+        }
+    }
+
+    let range = headers.get(header::RANGE);
+
+    let size = metadata.len();
+    let response = Response::builder()
+        .header(header::CONTENT_TYPE, content_type(path))
+        .header(header::ACCEPT_RANGES, "bytes");
+
+    let (response, start, length) = match range.map(|r| parse_range(r, size)) {
+        Some(Some((start, end))) => {
+            debug!("  Range {start}-{end} of {size}");
+
+            let response = response
+                .status(206)
+                .header(header::CONTENT_RANGE, format!("bytes {start}-{end}/{size}"));
+
+            (response, start, end - start + 1)
+        }
+        Some(None) => {
+            return Response::builder()
+                .status(416)
+                .header(header::CONTENT_RANGE, format!("bytes */{size}"))
+                .body(Body::empty())
+                .unwrap();
+        }
+        None => (response, 0, size),
+    };
+
+    response
+        .header(header::CONTENT_LENGTH, length)
+        .body(stream_file(path, start, length))
+        .unwrap()
+}
+
+/// Serves a version of the file which the browsers can show: the embedded
+/// JPEG preview of the RAW files, the file itself otherwise.
+pub async fn serve_preview(
+    extract::Path(path): extract::Path<String>,
+    headers: HeaderMap,
+    state: Arc<AppState>,
+) -> Response<Body> {
+    let Some((library, relative_path)) = state.resolve(&path) else {
+        return not_found(&path);
+    };
+
+    let Some(full_path) = library.context().to_sandboxed_path(relative_path) else {
+        return error_response(400, format!("Invalid path: {path}"));
+    };
+
+    if !Directory::has_raw_extension(&full_path) {
+        return serve_file(&full_path, &headers).await;
+    }
+
+    let preview = tokio::task::spawn_blocking(move || {
+        RawPreview::from_path(&full_path).and_then(RawPreview::into_oriented_jpeg)
+    })
+    .await
+    .ok()
+    .flatten();
+
+    match preview {
+        Some(jpeg) => Response::builder()
+            .header(header::CONTENT_TYPE, "image/jpeg")
+            .header(header::CONTENT_LENGTH, jpeg.len())
+            .body(Body::from(jpeg))
+            .unwrap(),
+        None => error_response(404, format!("No preview in {path}")),
+    }
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .map(|e| e.to_ascii_lowercase())
+        .unwrap_or_default();
+
+    match ext.to_str().unwrap_or_default() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "json" => "application/json",
+        "mp4" | "m4v" => "video/mp4",
+        "mov" => "video/quicktime",
+        "webm" => "video/webm",
+        "cr2" => "image/x-canon-cr2",
+        "nef" => "image/x-nikon-nef",
+        "arw" => "image/x-sony-arw",
+        "dng" => "image/x-adobe-dng",
+        "heic" => "image/heic",
+        "heif" => "image/heif",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Safari sends the HEIF types in the `Accept` header of the images.
+fn accepts_heif(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("image/heic") || accept.contains("image/heif"))
+}
+
+/// Parses a `bytes=start-end` range header, the end is inclusive. Returns
+/// `None` if the range cannot be satisfied.
+fn parse_range(header: &HeaderValue, size: u64) -> Option<(u64, u64)> {
+    let range = header.to_str().ok()?.strip_prefix("bytes=")?;
+
+    // Multiple ranges are not supported
+    if range.contains(',') {
+        return None;
+    }
+
+    let (start, end) = range.trim().split_once('-')?;
+
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) => (start, end.min(size.saturating_sub(1))),
+        (Ok(start), Err(_)) if end.is_empty() => (start, size.saturating_sub(1)),
+        // The suffix range, the last bytes of the file
+        (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => {
+            (size.saturating_sub(suffix), size.saturating_sub(1))
+        }
+        _ => return None,
+    };
+
+    (start <= end && start < size).then_some((start, end))
+}
+
+/// Reads the part of the file in a task and streams it in chunks.
+fn stream_file(path: &Path, start: u64, length: u64) -> Body {
+    let path = path.to_path_buf();
+    let (tx, rx) = mpsc::channel::<std::io::Result<Bytes>>(4);
+
+    tokio::spawn(async move {
+        let mut file = match File::open(&path).await {
+            Ok(file) => file,
+            Err(e) => {
+                error!("Cannot open {path:?}: {e}");
+                let _ = tx.send(Err(e)).await;
+                return;
+            }
+        };
+
+        if let Err(e) = file.seek(SeekFrom::Start(start)).await {
+            let _ = tx.send(Err(e)).await;
+            return;
+        }
+
+        let mut remaining = length;
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+
+        while remaining > 0 {
+            let wanted = (remaining as usize).min(CHUNK_SIZE);
+
+            match file.read(&mut buffer[..wanted]).await {
+                Ok(0) => break,
+                Ok(read) => {
+                    remaining -= read as u64;
+
+                    // The client went away
+                    if tx
+                        .send(Ok(Bytes::copy_from_slice(&buffer[..read])))
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    let _ = tx.send(Err(e)).await;
+                    break;
+                }
+            }
+        }
+    });
+
+    Body::from_stream(ReceiverStream::new(rx))
+}
@@ -0,0 +1,221 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use axum::{Json, body::Body, extract, response::Response};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AppState,
+    api::{
+        collect_thumbnails, error_response, escape_html, escape_url, forbidden, json_response,
+        meta::write_sidecar, not_found, read_only_library, serve_gallery_page,
+    },
+    auth::CurrentUser,
+    library::Library,
+};
+
+/// Adds and removes tags on a set of images. Paths start with the name of
+/// their library.
+#[derive(Debug, Deserialize)]
+pub struct TagUpdate {
+    pub files: Vec<String>,
+    #[serde(default)]
+    pub add: Vec<String>,
+    #[serde(default)]
+    pub remove: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TagCount {
+    pub tag: String,
+    pub count: usize,
+}
+
+/// Lists all the tags of the libraries with the number of images having them.
+pub async fn list_tags(state: Arc<AppState>) -> Response<Body> {
+    json_response(200, &tag_counts(&state))
+}
+
+/// The thumbnails of the images with the tag from all directories.
+pub async fn tagged_images(
+    extract::Path(tag): extract::Path<String>,
+    state: Arc<AppState>,
+) -> Response<Body> {
+    let keys = tagged_keys(&state, &tag);
+
+    json_response(
+        200,
+        &collect_thumbnails(&state, keys.iter().map(String::as_str)),
+    )
+}
+
+pub async fn update_tags(
+    extract::Extension(user): extract::Extension<CurrentUser>,
+    state: Arc<AppState>,
+    Json(update): Json<TagUpdate>,
+) -> Response<Body> {
+    let add = normalize_tags(&update.add);
+    let remove = normalize_tags(&update.remove);
+    let mut keys: Vec<(&Library, String)> = vec![];
+
+    for file in &update.files {
+        let Some((library, path)) = state.resolve(file) else {
+            return not_found(file);
+        };
+
+        if library.read_only {
+            return read_only_library(library);
+        }
+
+        if let Some(response) = forbidden(&state, &user, library, path) {
+            return response;
+        }
+
+        let context = library.context();
+
+        match context.to_relative_key(path) {
+            Some(key) if context.base_dir.join(&key).is_file() => keys.push((library, key)),
+            Some(_) => return error_response(404, format!("File not found: {file}")),
+            None => return error_response(400, format!("Invalid path: {file}")),
+        }
+    }
+
+    debug!(
+        "Tagging {} files with {add:?}, removing {remove:?}",
+        keys.len()
+    );
+
+    for library in &state.libraries {
+        let library_keys: Vec<&String> = keys
+            .iter()
+            .filter(|(l, _)| l.name == library.name)
+            .map(|(_, key)| key)
+            .collect();
+
+        if library_keys.is_empty() {
+            continue;
+        }
+
+        let context = library.context();
+        let mut catalog = library.catalog.lock().unwrap();
+        let mut changed = false;
+
+        for key in library_keys {
+            let mut meta = catalog.meta(key);
+
+            meta.keywords.retain(|k| !remove.contains(k));
+
+            for tag in &add {
+                if !meta.keywords.contains(tag) {
+                    meta.keywords.push(tag.clone());
+                }
+            }
+
+            if meta != catalog.meta(key) {
+                write_sidecar(&state, &context, key, &meta);
+                catalog.set_meta(key, meta);
+                changed = true;
+            }
+        }
+
+        if changed && let Err(e) = catalog.save() {
+            return error_response(500, e);
+        }
+    }
+
+    json_response(200, &tag_counts(&state))
+}
+
+/// Serves a tag as a virtual folder: `/tags/` lists the tags, `/tags/{tag}/`
+/// is the gallery page which loads `/tags/{tag}/bundles.json`.
+pub async fn serve_tag_view(
+    extract::Path(path): extract::Path<String>,
+    state: Arc<AppState>,
+) -> Response<Body> {
+    let path = path.trim_start_matches('/');
+
+    if path.is_empty() {
+        return list_tag_links(&state);
+    }
+
+    match path.strip_suffix("/bundles.json") {
+        Some(tag) => {
+            let keys = tagged_keys(&state, tag);
+
+            json_response(
+                200,
+                &collect_thumbnails(&state, keys.iter().map(String::as_str)),
+            )
+        }
+        None => serve_gallery_page(&state),
+    }
+}
+
+fn list_tag_links(state: &AppState) -> Response<Body> {
+    let mut html = String::from("<html><body><a href=\"/serve/\">Library</a><br/><br/>");
+
+    for TagCount { tag, count } in tag_counts(state) {
+        html.push_str(&format!(
+            "<a href=\"/tags/{}/\">{}</a> ({count})<br/>",
+            escape_url(&tag),
+            escape_html(&tag)
+        ));
+    }
+
+    html.push_str("</body></html>");
+
+    Response::builder()
+        .header("Content-Type", "text/html")
+        .body(Body::from(html))
+        .unwrap()
+}
+
+fn tag_counts(state: &AppState) -> Vec<TagCount> {
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+
+    for library in &state.libraries {
+        let catalog = library.catalog.lock().unwrap();
+
+        for meta in catalog.images.values() {
+            for keyword in &meta.keywords {
+                *counts.entry(keyword.clone()).or_default() += 1;
+            }
+        }
+    }
+
+    counts
+        .into_iter()
+        .map(|(tag, count)| TagCount { tag, count })
+        .collect()
+}
+
+/// The paths of the images with the tag in all the libraries.
+fn tagged_keys(state: &AppState, tag: &str) -> Vec<String> {
+    let mut paths = vec![];
+
+    for library in &state.libraries {
+        let catalog = library.catalog.lock().unwrap();
+
+        paths.extend(
+            catalog
+                .images
+                .iter()
+                .filter(|(_, meta)| meta.keywords.iter().any(|k| k == tag))
+                .map(|(key, _)| library.server_path(key)),
+        );
+    }
+
+    paths
+}
+
+fn normalize_tags(tags: &[String]) -> Vec<String> {
+    let mut result: Vec<String> = vec![];
+
+    for tag in tags.iter().map(|t| t.trim()).filter(|t| !t.is_empty()) {
+        if !result.iter().any(|t| t == tag) {
+            result.push(tag.to_owned());
+        }
+    }
+
+    result
+}
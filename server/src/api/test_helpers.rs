@@ -0,0 +1,89 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use axum::{Router, body::Body, extract::Request};
+use http::{Method, header};
+use tower::ServiceExt;
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// The root directory of a test, removed with its content when the test is
+/// done. Every call gets its own directory, the tests run in parallel.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "mosaic-{name}-{}-{}",
+            std::process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        std::fs::create_dir_all(&dir).unwrap();
+
+        TempDir(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.0.join(path)
+    }
+
+    /// Creates an image file, returns its path for the clients in the `main`
+    /// library.
+    pub fn image(&self, path: &str) -> String {
+        let file = self.join(path);
+
+        std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+        std::fs::write(&file, b"jpeg").unwrap();
+
+        format!("main/{path}")
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+pub fn get(uri: &str, cookie: Option<&str>) -> Request {
+    request(Method::GET, uri, cookie)
+}
+
+pub fn post(uri: &str, cookie: Option<&str>) -> Request {
+    request(Method::POST, uri, cookie)
+}
+
+pub fn request(method: Method, uri: &str, cookie: Option<&str>) -> Request {
+    json_request(method, uri, cookie, "")
+}
+
+/// A request with a JSON body, an empty body is sent without a content type.
+pub fn json_request(method: Method, uri: &str, cookie: Option<&str>, body: &str) -> Request {
+    let mut request = Request::builder().method(method).uri(uri);
+
+    if let Some(cookie) = cookie {
+        request = request.header(header::COOKIE, cookie);
+    }
+
+    if !body.is_empty() {
+        request = request.header(header::CONTENT_TYPE, "application/json");
+    }
+
+    request.body(Body::from(body.to_owned())).unwrap()
+}
+
+pub async fn status(app: &Router, request: Request) -> u16 {
+    app.clone()
+        .oneshot(request)
+        .await
+        .unwrap()
+        .status()
+        .as_u16()
+}
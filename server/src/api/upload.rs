@@ -0,0 +1,250 @@
+use std::{
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use axum::{
+    body::Body,
+    extract::{self, Multipart, multipart::Field},
+    response::Response,
+};
+use image::ImageReader;
+use log::{debug, info};
+use serde::Deserialize;
+use tokio::{fs::File, io::AsyncWriteExt};
+
+use crate::{
+    AppState,
+    api::{
+        SyncCommand, error_response,
+        files::{ConflictStrategy, OperationResult, is_plain_file_name, resolve_conflict},
+        forbidden, json_response, not_found, read_only_library, remove_generated_files,
+    },
+    auth::CurrentUser,
+    scanner::directory::{Directory, MediaType},
+    thumbnail::{heif::HeifFile, raw::RawPreview, video::Video},
+};
+
+/// Numbers the temporary files, so the concurrent uploads of the same name
+/// don't write into the same file.
+static NEXT_UPLOAD: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Deserialize)]
+pub struct UploadParams {
+    /// Uploads get a suffixed name by default instead of failing, since the
+    /// browser has no way to pick a new name.
+    #[serde(default = "default_conflict")]
+    pub conflict: ConflictStrategy,
+}
+
+fn default_conflict() -> ConflictStrategy {
+    ConflictStrategy::Suffix
+}
+
+enum UploadError {
+    TooLarge,
+    InvalidFile(String),
+    Io(String),
+}
+
+/// Stores the files of a multipart request in the directory, and resyncs it
+/// if anything was uploaded.
+pub async fn upload_files(
+    extract::Path(dir): extract::Path<String>,
+    extract::Query(params): extract::Query<UploadParams>,
+    extract::Extension(user): extract::Extension<CurrentUser>,
+    state: Arc<AppState>,
+    mut multipart: Multipart,
+) -> Response<Body> {
+    let Some((library, path)) = state.resolve(&dir) else {
+        return not_found(&dir);
+    };
+
+    if library.read_only {
+        return read_only_library(library);
+    }
+
+    if let Some(response) = forbidden(&state, &user, library, path) {
+        return response;
+    }
+
+    let context = library.context();
+
+    let Some(target_dir) = context.to_sandboxed_path(path) else {
+        return error_response(400, format!("Invalid path: {dir}"));
+    };
+
+    if !target_dir.is_dir() {
+        return error_response(404, format!("Directory not found: {dir}"));
+    }
+
+    let mut result = OperationResult::default();
+
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => {
+                result.failed.push((String::new(), e.body_text()));
+                break;
+            }
+        };
+
+        let Some(name) = field
+            .file_name()
+            .and_then(|n| Path::new(n).file_name())
+            .map(|n| n.to_string_lossy().into_owned())
+        else {
+            debug!(
+                "Skipping multipart field without file name: {:?}",
+                field.name()
+            );
+            continue;
+        };
+
+        if !is_plain_file_name(&name) || MediaType::from_path(Path::new(&name)).is_none() {
+            result
+                .failed
+                .push((name, "Unsupported file type".to_owned()));
+            continue;
+        }
+
+        let temp_path = target_dir.join(format!(
+            ".{name}.{}.upload",
+            NEXT_UPLOAD.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let outcome = match receive_file(field, &temp_path, state.config.max_upload_size).await {
+            Ok(_) => validate_media(&temp_path, Path::new(&name)),
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = outcome {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+
+            let reason = match e {
+                UploadError::TooLarge => {
+                    format!("File is larger than {} bytes", state.config.max_upload_size)
+                }
+                UploadError::InvalidFile(e) => format!("Invalid file: {e}"),
+                UploadError::Io(e) => e,
+            };
+
+            result.failed.push((name, reason));
+            continue;
+        }
+
+        let Some(target) = resolve_conflict(&target_dir.join(&name), params.conflict) else {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+
+            match params.conflict {
+                ConflictStrategy::Skip => result.skipped.push(name),
+                _ => result.failed.push((name, "Target file exists".to_owned())),
+            }
+            continue;
+        };
+
+        match tokio::fs::rename(&temp_path, &target).await {
+            Ok(_) => {
+                info!("Uploaded file: {target:?}");
+
+                let relative_target = context.to_relative_path(&target);
+
+                result.done.push((
+                    name,
+                    library.server_path(&relative_target.to_string_lossy()),
+                ));
+            }
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&temp_path).await;
+
+                result.failed.push((name, e.to_string()));
+            }
+        }
+    }
+
+    if !result.done.is_empty() {
+        remove_generated_files(&target_dir);
+
+        let relative_dir = context
+            .to_relative_path(&target_dir)
+            .to_string_lossy()
+            .into_owned();
+
+        state
+            .command_tx
+            .send(SyncCommand::SyncDirectory(
+                library.name.clone(),
+                relative_dir,
+            ))
+            .await
+            .expect("Failed to send internal command");
+    }
+
+    json_response(200, &result)
+}
+
+/// Streams the content of the field into the file chunk by chunk, so the
+/// upload is never kept in memory as a whole.
+async fn receive_file(mut field: Field<'_>, path: &Path, max_size: u64) -> Result<(), UploadError> {
+    let mut file = File::create(path)
+        .await
+        .map_err(|e| UploadError::Io(e.to_string()))?;
+    let mut written = 0u64;
+
+    while let Some(chunk) = field
+        .chunk()
+        .await
+        .map_err(|e| UploadError::Io(e.body_text()))?
+    {
+        written += chunk.len() as u64;
+
+        if written > max_size {
+            return Err(UploadError::TooLarge);
+        }
+
+        file.write_all(&chunk)
+            .await
+            .map_err(|e| UploadError::Io(e.to_string()))?;
+    }
+
+    file.flush()
+        .await
+        .map_err(|e| UploadError::Io(e.to_string()))
+}
+
+/// Checks that the content is really a file of the type of its name: the
+/// images are decoded up to their header, the RAW, HEIF and video files are
+/// parsed like the scanner does.
+fn validate_media(path: &Path, name: &Path) -> Result<(), UploadError> {
+    let valid = if Directory::has_raw_extension(name) {
+        RawPreview::from_path(path).is_some()
+    } else if Directory::has_heif_extension(name) {
+        HeifFile::from_path(path).is_some()
+    } else if let Some(extension) = name
+        .extension()
+        .filter(|_| Directory::has_video_extension(name))
+    {
+        Video::read(path, extension).is_some()
+    } else {
+        return ImageReader::open(path)
+            .map_err(|e| UploadError::Io(e.to_string()))?
+            .with_guessed_format()
+            .map_err(|e| UploadError::Io(e.to_string()))?
+            .into_dimensions()
+            .map(|_| ())
+            .map_err(|e| UploadError::InvalidFile(e.to_string()));
+    };
+
+    if valid {
+        Ok(())
+    } else {
+        Err(UploadError::InvalidFile(format!(
+            "Cannot read {}",
+            name.to_string_lossy()
+        )))
+    }
+}
@@ -0,0 +1,289 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::ErrorKind,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use argon2::{
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+    password_hash::{
+        SaltString,
+        rand_core::{OsRng, RngCore},
+    },
+};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{AuthConfig, Role, RuleConfig, UserConfig};
+
+/// The users who can log in and their sessions. The sessions are only kept in
+/// memory, restarting the server logs everyone out.
+pub struct Auth {
+    users: Vec<UserConfig>,
+    rules: Vec<RuleConfig>,
+    session_ttl: Duration,
+    /// The sessions by their token.
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+/// The user of a request, the login middleware adds it to every request.
+/// There is no user if the server has no auth.
+#[derive(Clone, Debug, Default)]
+pub struct CurrentUser {
+    pub name: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Session {
+    pub user: String,
+    expires_at: Instant,
+}
+
+/// The users file has the same user entries as the config.
+#[derive(Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct UsersFile {
+    #[serde(default)]
+    users: Vec<UserConfig>,
+}
+
+impl Auth {
+    pub fn new(config: &AuthConfig) -> Self {
+        // The config is validated at startup, so this only fails if the users
+        // file changed in the meantime
+        let users = load_users(config).unwrap_or_else(|e| {
+            error!("{e}");
+
+            config.users.clone()
+        });
+
+        Auth {
+            users,
+            rules: config.rules.clone(),
+            session_ttl: Duration::from_secs(config.session_hours * 60 * 60),
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks the password of the user. Hashing is slow on purpose, so this
+    /// should not run on the async threads.
+    pub fn authenticate(&self, name: &str, password: &str) -> bool {
+        match self.users.iter().find(|u| u.name == name) {
+            Some(user) => verify_password(&user.password_hash, password),
+            None => {
+                // An unknown user takes as long as a wrong password, so the
+                // user names cannot be guessed from the response time
+                if let Some(user) = self.users.first() {
+                    verify_password(&user.password_hash, password);
+                }
+
+                false
+            }
+        }
+    }
+
+    /// The role of the user for a path of the clients, which starts with the
+    /// name of the library.
+    pub fn role(&self, name: &str, path: &str) -> Role {
+        let Some(user) = self.users.iter().find(|u| u.name == name) else {
+            return Role::Viewer;
+        };
+
+        if user.role == Role::Admin {
+            return Role::Admin;
+        }
+
+        let path = path.trim_matches('/');
+
+        self.rules
+            .iter()
+            .filter(|r| r.users.is_empty() || r.users.contains(&user.name))
+            .filter(|r| is_under(path, r.path.trim_matches('/')))
+            .max_by_key(|r| (r.path.trim_matches('/').len(), !r.users.is_empty()))
+            .map_or(user.role, |r| r.role)
+    }
+
+    /// Starts a session of the user, returns its token for the cookie.
+    pub fn login(&self, user: &str) -> String {
+        let mut bytes = [0u8; 32];
+
+        OsRng.fill_bytes(&mut bytes);
+
+        let token: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+
+        sessions.retain(|_, s| s.expires_at > now);
+        sessions.insert(
+            token.clone(),
+            Session {
+                user: user.to_owned(),
+                expires_at: now + self.session_ttl,
+            },
+        );
+
+        info!("User {user} logged in");
+
+        token
+    }
+
+    /// The session of the token if it has not expired yet.
+    pub fn session(&self, token: &str) -> Option<Session> {
+        let sessions = self.sessions.lock().unwrap();
+
+        sessions
+            .get(token)
+            .filter(|s| s.expires_at > Instant::now())
+            .cloned()
+    }
+
+    pub fn logout(&self, token: &str) -> Option<Session> {
+        self.sessions.lock().unwrap().remove(token)
+    }
+
+    pub fn session_ttl(&self) -> Duration {
+        self.session_ttl
+    }
+}
+
+/// Hashes the password with Argon2id and a random salt, the result is in the
+/// PHC string format which has the parameters and the salt as well.
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Cannot hash the password")
+        .to_string()
+}
+
+pub fn verify_password(hash: &str, password: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+/// The users of the config followed by the ones of the users file. A missing
+/// users file has no users.
+pub fn load_users(config: &AuthConfig) -> Result<Vec<UserConfig>, String> {
+    let mut users = config.users.clone();
+
+    if let Some(path) = &config.users_file {
+        users.extend(read_users_file(Path::new(path))?.users);
+    }
+
+    Ok(users)
+}
+
+/// Checks the users of the config and of the users file and the rules,
+/// returns the problems found. Having no users is only a problem for the
+/// server.
+pub fn check_config(config: &AuthConfig, libraries: &[String], serving: bool) -> Vec<String> {
+    let users = match load_users(config) {
+        Ok(users) => users,
+        Err(e) => return vec![e],
+    };
+
+    let mut errors = vec![];
+    let mut names = HashSet::new();
+
+    for user in &users {
+        let name = &user.name;
+
+        if name.is_empty() {
+            errors.push("auth has a user without a name".to_owned());
+        }
+
+        if !names.insert(name) {
+            errors.push(format!("user {name:?} is defined more than once"));
+        }
+
+        if PasswordHash::new(&user.password_hash).is_err() {
+            errors.push(format!(
+                "password_hash of user {name:?} is not an Argon2 hash"
+            ));
+        }
+    }
+
+    if serving && users.is_empty() {
+        errors.push("auth has no users, add one with `mosaic passwd`".to_owned());
+    }
+
+    for rule in &config.rules {
+        let path = rule.path.trim_matches('/');
+        let library = path.split('/').next().unwrap_or_default();
+
+        if !libraries.iter().any(|l| l == library) {
+            errors.push(format!("rule path {:?} is not in a library", rule.path));
+        }
+
+        if rule.role == Role::Admin {
+            errors.push(format!(
+                "rule for {:?} cannot give the admin role",
+                rule.path
+            ));
+        }
+
+        // The users may still be added with `mosaic passwd`
+        if !serving {
+            continue;
+        }
+
+        for name in rule.users.iter().filter(|n| !names.contains(n)) {
+            errors.push(format!(
+                "rule for {:?} has the unknown user {name:?}",
+                rule.path
+            ));
+        }
+    }
+
+    errors
+}
+
+/// Sets the password of the user in the users file, the user is added if it
+/// is not there yet. The role of an existing user is only changed if given.
+pub fn set_password(
+    path: &Path,
+    name: &str,
+    password: &str,
+    role: Option<Role>,
+) -> Result<(), String> {
+    let mut file = read_users_file(path)?;
+    let password_hash = hash_password(password);
+
+    match file.users.iter_mut().find(|u| u.name == name) {
+        Some(user) => {
+            user.password_hash = password_hash;
+            user.role = role.unwrap_or(user.role);
+        }
+        None => file.users.push(UserConfig {
+            name: name.to_owned(),
+            password_hash,
+            role: role.unwrap_or_default(),
+        }),
+    }
+
+    let content = toml::to_string(&file).expect("Cannot serialize the users");
+
+    std::fs::write(path, content).map_err(|e| format!("Cannot write {path:?}: {e}"))
+}
+
+/// True if the path is the directory or is inside of it.
+fn is_under(path: &str, dir: &str) -> bool {
+    path.strip_prefix(dir)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+fn read_users_file(path: &Path) -> Result<UsersFile, String> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(UsersFile::default()),
+        Err(e) => return Err(format!("Cannot read {path:?}: {e}")),
+    };
+
+    toml::from_str(&content).map_err(|e| format!("Error parsing {path:?}: {e}"))
+}
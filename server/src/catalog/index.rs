@@ -0,0 +1,165 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use crate::thumbnail::{
+    capture::CaptureInfo,
+    phash::{HashKind, PerceptualHashes},
+    quality::QualityScores,
+};
+
+/// The searchable properties of the images, built during the sync. Unlike the
+/// catalog it can be rebuilt any time by syncing the directories again.
+#[derive(Default, Deserialize, Serialize)]
+pub struct SearchIndex {
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(default)]
+    pub entries: BTreeMap<String, IndexEntry>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct IndexEntry {
+    pub size: u64,
+    pub width: u32,
+    pub height: u32,
+    /// The content hash, identical files have the same hash.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub perceptual: Option<PerceptualHashes>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quality: Option<QualityScores>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub palette: Vec<String>,
+    #[serde(default, flatten)]
+    pub capture: CaptureInfo,
+}
+
+impl SearchIndex {
+    pub fn open(cache_dir: impl AsRef<Path>) -> Self {
+        let path = cache_dir.as_ref().join("index.json");
+
+        let mut index = match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                info!("Cannot parse search index {path:?}, starting a new one: {e}");
+
+                SearchIndex::default()
+            }),
+            Err(_) => SearchIndex::default(),
+        };
+
+        index.path = path;
+        index
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        debug!("Saving search index {:?}", self.path);
+
+        let temp_path = self.path.with_extension("json.tmp");
+
+        std::fs::create_dir_all(self.path.parent().unwrap())
+            .and_then(|_| File::create(&temp_path))
+            .and_then(|file| {
+                let mut writer = BufWriter::new(file);
+
+                serde_json::to_writer(&mut writer, self)?;
+                writer.flush()
+            })
+            .and_then(|_| std::fs::rename(&temp_path, &self.path))
+            .map_err(|e| format!("Cannot save search index {:?}: {e}", self.path))
+    }
+
+    /// Replaces the entries of a directory with the result of a new scan. The
+    /// directory is a relative path, an empty string for the root.
+    pub fn replace_directory(&mut self, dir: &Path, entries: Vec<(String, IndexEntry)>) {
+        self.entries
+            .retain(|key, _| Path::new(key).parent() != Some(dir));
+        self.entries.extend(entries);
+    }
+
+    pub fn rename(&mut self, from: &str, to: &str) -> bool {
+        match self.entries.remove(from) {
+            Some(entry) => {
+                self.entries.insert(to.to_owned(), entry);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn copy(&mut self, from: &str, to: &str) -> bool {
+        match self.entries.get(from).cloned() {
+            Some(entry) => {
+                self.entries.insert(to.to_owned(), entry);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn remove(&mut self, path: &str) -> bool {
+        self.entries.remove(path).is_some()
+    }
+
+    /// The images whose perceptual hash is within the distance of the hash of
+    /// the image, the closest ones first.
+    pub fn similar(&self, path: &str, kind: HashKind, threshold: u32) -> Vec<(String, u32)> {
+        let Some(hashes) = self.entries.get(path).and_then(|e| e.perceptual) else {
+            return vec![];
+        };
+
+        let mut similar: Vec<(String, u32)> = self
+            .entries
+            .iter()
+            .filter(|(key, _)| *key != path)
+            .filter_map(|(key, entry)| {
+                let distance = entry.perceptual?.distance(&hashes, kind);
+
+                (distance <= threshold).then(|| (key.clone(), distance))
+            })
+            .collect();
+
+        similar.sort_by_key(|(_, distance)| *distance);
+        similar
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        api::{scan_directory, test_helpers::TempDir},
+        config::LibraryConfig,
+        library::Library,
+        scanner::pipeline::ScanPipeline,
+    };
+
+    #[test]
+    fn failed_saves_are_in_the_sync_report() {
+        let dir = TempDir::new("index");
+        let library = Library::open(&LibraryConfig {
+            name: "main".to_owned(),
+            root_directory: dir.path().to_string_lossy().into_owned(),
+            cache_directory: None,
+            read_only: false,
+        })
+        .unwrap();
+
+        dir.image("one.jpg");
+
+        // The temporary file of the index cannot be created
+        std::fs::create_dir_all(dir.join(".mosaic/index.json.tmp")).unwrap();
+
+        let report = scan_directory(&library, &ScanPipeline::new(1), String::new());
+        let errors: Vec<_> = report.errors.iter().filter(|e| e.file.is_empty()).collect();
+
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].error.starts_with("Cannot save search index"));
+    }
+}
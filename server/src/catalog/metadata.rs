@@ -0,0 +1,167 @@
+use serde::{Deserialize, Serialize};
+
+use crate::catalog::xmp::XmpData;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Flag {
+    #[default]
+    None,
+    Pick,
+    Reject,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorLabel {
+    Red,
+    Yellow,
+    Green,
+    Blue,
+    Purple,
+}
+
+impl ColorLabel {
+    /// The label names used by Lightroom and darktable in `xmp:Label`.
+    pub fn xmp_name(self) -> &'static str {
+        match self {
+            ColorLabel::Red => "Red",
+            ColorLabel::Yellow => "Yellow",
+            ColorLabel::Green => "Green",
+            ColorLabel::Blue => "Blue",
+            ColorLabel::Purple => "Purple",
+        }
+    }
+
+    pub fn from_xmp_name(name: &str) -> Option<ColorLabel> {
+        [
+            ColorLabel::Red,
+            ColorLabel::Yellow,
+            ColorLabel::Green,
+            ColorLabel::Blue,
+            ColorLabel::Purple,
+        ]
+        .into_iter()
+        .find(|l| l.xmp_name().eq_ignore_ascii_case(name.trim()))
+    }
+}
+
+/// The user metadata of an image which is not stored in the image file.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ImageMeta {
+    /// Star rating from 1 to 5, 0 means unrated.
+    #[serde(default)]
+    pub rating: u8,
+    #[serde(default)]
+    pub flag: Flag,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<ColorLabel>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub keywords: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+}
+
+/// A partial update of the metadata, the missing fields are left as they are.
+/// The label can be removed with an explicit `null`.
+#[derive(Debug, Default, Deserialize)]
+pub struct MetaUpdate {
+    pub rating: Option<u8>,
+    pub flag: Option<Flag>,
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub label: Option<Option<ColorLabel>>,
+    pub keywords: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub title: Option<Option<String>>,
+}
+
+/// Filters for the thumbnail listing, the images have to match all of them.
+#[derive(Debug, Default, Deserialize)]
+pub struct MetaFilter {
+    pub min_rating: Option<u8>,
+    pub flag: Option<Flag>,
+    pub label: Option<ColorLabel>,
+}
+
+impl ImageMeta {
+    pub fn is_empty(&self) -> bool {
+        *self == ImageMeta::default()
+    }
+
+    /// Applies the update, returns an error message if a value is invalid.
+    pub fn apply(&mut self, update: &MetaUpdate) -> Result<(), String> {
+        if let Some(rating) = update.rating {
+            if rating > 5 {
+                return Err(format!("Rating must be between 0 and 5: {rating}"));
+            }
+
+            self.rating = rating;
+        }
+
+        if let Some(flag) = update.flag {
+            self.flag = flag;
+        }
+
+        if let Some(label) = update.label {
+            self.label = label;
+        }
+
+        if let Some(keywords) = &update.keywords {
+            self.keywords = keywords.clone();
+        }
+
+        if let Some(title) = &update.title {
+            self.title = title.clone();
+        }
+
+        Ok(())
+    }
+
+    /// Takes over the values found in the XMP data of the image, since they
+    /// could have been changed by other applications.
+    pub fn merge_xmp(&mut self, xmp: &XmpData) {
+        match xmp.rating {
+            Some(rating) if rating < 0 => {
+                self.rating = 0;
+                self.flag = Flag::Reject;
+            }
+            Some(rating) => {
+                self.rating = rating.min(5) as u8;
+
+                if self.flag == Flag::Reject {
+                    self.flag = Flag::None;
+                }
+            }
+            None => {}
+        }
+
+        if let Some(label) = &xmp.label {
+            self.label = ColorLabel::from_xmp_name(label);
+        }
+
+        if let Some(keywords) = &xmp.keywords {
+            self.keywords = keywords.clone();
+        }
+
+        if let Some(title) = &xmp.title {
+            self.title = Some(title.clone());
+        }
+    }
+}
+
+impl MetaFilter {
+    pub fn matches(&self, meta: &ImageMeta) -> bool {
+        self.min_rating.is_none_or(|r| meta.rating >= r)
+            && self.flag.is_none_or(|f| meta.flag == f)
+            && self.label.is_none_or(|l| meta.label == Some(l))
+    }
+}
+
+/// Distinguishes a missing field from an explicit `null`.
+fn deserialize_some<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
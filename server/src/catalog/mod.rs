@@ -0,0 +1,4 @@
+pub mod index;
+pub mod metadata;
+pub mod store;
+pub mod xmp;
@@ -0,0 +1,152 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufWriter, ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use crate::catalog::metadata::ImageMeta;
+
+/// The catalog keeps the data of the library which cannot be regenerated from
+/// the files. It lives in the cache directory of the library, the images are
+/// keyed by their path relative to the root of the library.
+#[derive(Default, Deserialize, Serialize)]
+pub struct Catalog {
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(default)]
+    pub images: BTreeMap<String, ImageMeta>,
+    #[serde(default)]
+    pub albums: BTreeMap<String, Album>,
+}
+
+/// A named, ordered selection of images from any directory.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Album {
+    pub images: Vec<String>,
+}
+
+impl Catalog {
+    /// Opens the catalog of the cache directory, a missing one is empty. A
+    /// catalog which cannot be read is an error, an empty one would replace
+    /// it at the next save.
+    pub fn open(cache_dir: impl AsRef<Path>) -> Result<Self, String> {
+        let path = cache_dir.as_ref().join("catalog.json");
+
+        let mut catalog = match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)
+                .map_err(|e| format!("Cannot parse catalog {path:?}: {e}"))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                info!("No catalog found at {path:?}, starting with an empty one");
+
+                Catalog::default()
+            }
+            Err(e) => return Err(format!("Cannot read catalog {path:?}: {e}")),
+        };
+
+        catalog.path = path;
+        Ok(catalog)
+    }
+
+    /// Writes the catalog into a temporary file first, so a crash cannot leave
+    /// a half written catalog behind.
+    pub fn save(&self) -> Result<(), String> {
+        debug!("Saving catalog {:?}", self.path);
+
+        let temp_path = self.path.with_extension("json.tmp");
+
+        std::fs::create_dir_all(self.path.parent().unwrap())
+            .and_then(|_| File::create(&temp_path))
+            .and_then(|file| {
+                let mut writer = BufWriter::new(file);
+
+                serde_json::to_writer_pretty(&mut writer, self)?;
+                writer.flush()
+            })
+            .and_then(|_| std::fs::rename(&temp_path, &self.path))
+            .map_err(|e| format!("Cannot save catalog {:?}: {e}", self.path))
+    }
+
+    pub fn meta(&self, path: &str) -> ImageMeta {
+        self.images.get(path).cloned().unwrap_or_default()
+    }
+
+    pub fn set_meta(&mut self, path: &str, meta: ImageMeta) {
+        if meta.is_empty() {
+            self.images.remove(path);
+        } else {
+            self.images.insert(path.to_owned(), meta);
+        }
+    }
+
+    /// Follows the file to its new path after a move or rename.
+    pub fn rename(&mut self, from: &str, to: &str) -> bool {
+        let mut changed = false;
+
+        if let Some(meta) = self.images.remove(from) {
+            self.images.insert(to.to_owned(), meta);
+            changed = true;
+        }
+
+        for album in self.albums.values_mut() {
+            for image in album.images.iter_mut().filter(|i| *i == from) {
+                *image = to.to_owned();
+                changed = true;
+            }
+        }
+
+        changed
+    }
+
+    pub fn copy(&mut self, from: &str, to: &str) -> bool {
+        match self.images.get(from).cloned() {
+            Some(meta) => {
+                self.images.insert(to.to_owned(), meta);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Forgets a deleted file, it is removed from the albums as well.
+    pub fn remove(&mut self, path: &str) -> bool {
+        let mut changed = self.images.remove(path).is_some();
+
+        for album in self.albums.values_mut() {
+            let count = album.images.len();
+
+            album.images.retain(|i| i != path);
+            changed |= album.images.len() != count;
+        }
+
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::test_helpers::TempDir;
+
+    #[test]
+    fn broken_catalogs_are_errors() {
+        let dir = TempDir::new("catalog");
+
+        std::fs::write(dir.join("catalog.json"), "{\"images\":").unwrap();
+
+        assert!(Catalog::open(dir.path()).is_err());
+
+        std::fs::remove_file(dir.join("catalog.json")).unwrap();
+
+        let catalog = Catalog::open(dir.path()).unwrap();
+
+        // The temporary file cannot be created
+        std::fs::create_dir(dir.join("catalog.json.tmp")).unwrap();
+
+        assert!(catalog.save().is_err());
+        assert!(!dir.join("catalog.json").exists());
+    }
+}
@@ -0,0 +1,357 @@
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    path::{Path, PathBuf},
+};
+
+use log::{debug, warn};
+use quick_xml::{
+    Reader, Writer,
+    escape::{resolve_predefined_entity, unescape},
+    events::{BytesEnd, BytesStart, BytesText, Event},
+};
+
+use crate::catalog::metadata::{Flag, ImageMeta};
+
+const XMP_NS: &str = "http://ns.adobe.com/xap/1.0/";
+const DC_NS: &str = "http://purl.org/dc/elements/1.1/";
+
+/// The header of the APP1 segment which holds the XMP packet in a JPEG file.
+const JPEG_XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+const EMPTY_XMP: &str = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""/>
+ </rdf:RDF>
+</x:xmpmeta>
+"#;
+
+/// The elements of the description which are managed by us, everything else
+/// is kept untouched when a sidecar is updated.
+const MANAGED_ELEMENTS: [&[u8]; 4] = [b"xmp:Rating", b"xmp:Label", b"dc:subject", b"dc:title"];
+
+/// The values we are interested in from an XMP packet. The fields are `None`
+/// if the packet doesn't have them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct XmpData {
+    /// From -1 to 5, where -1 means rejected.
+    pub rating: Option<i8>,
+    pub label: Option<String>,
+    pub keywords: Option<Vec<String>>,
+    pub title: Option<String>,
+}
+
+/// The sidecar which belongs only to this file, darktable style
+/// (`IMG_0001.JPG.xmp`).
+pub fn own_sidecar_path(image: &Path) -> PathBuf {
+    let mut name = image.file_name().unwrap().to_os_string();
+    name.push(".xmp");
+
+    image.with_file_name(name)
+}
+
+/// Finds the sidecar of the image, either darktable (`IMG_0001.JPG.xmp`) or
+/// Lightroom style (`IMG_0001.xmp`).
+pub fn existing_sidecar_path(image: &Path) -> Option<PathBuf> {
+    [own_sidecar_path(image), image.with_extension("xmp")]
+        .into_iter()
+        .find(|p| p.is_file())
+}
+
+/// Reads the XMP data of an image from its sidecar, or from the packet
+/// embedded in the file if there is no sidecar.
+pub fn read_for_image(image: &Path) -> Option<XmpData> {
+    let xml = match existing_sidecar_path(image) {
+        Some(sidecar) => std::fs::read_to_string(sidecar).ok()?,
+        None => read_embedded(image)?,
+    };
+
+    match parse(&xml) {
+        Ok(data) => Some(data),
+        Err(e) => {
+            warn!("Cannot parse XMP of {image:?}: {e}");
+            None
+        }
+    }
+}
+
+/// Writes the metadata into the sidecar of the image. An existing sidecar is
+/// updated in place, so the data of other applications survives.
+pub fn write_sidecar(image: &Path, meta: &ImageMeta) -> std::io::Result<()> {
+    let (path, xml) = match existing_sidecar_path(image) {
+        Some(path) => {
+            let xml = std::fs::read_to_string(&path)?;
+
+            (path, xml)
+        }
+        None => (own_sidecar_path(image), EMPTY_XMP.to_owned()),
+    };
+
+    debug!("Writing XMP sidecar {path:?}");
+
+    let updated = update(&xml, meta).map_err(std::io::Error::other)?;
+    let temp_path = path.with_extension("xmp.tmp");
+
+    std::fs::write(&temp_path, updated)?;
+    std::fs::rename(&temp_path, &path)
+}
+
+/// Finds the XMP packet in the APP1 segments of a JPEG file.
+fn read_embedded(image: &Path) -> Option<String> {
+    let mut reader = BufReader::new(File::open(image).ok()?);
+    let mut marker = [0u8; 4];
+
+    reader.read_exact(&mut marker[..2]).ok()?;
+
+    if marker[..2] != [0xFF, 0xD8] {
+        return None;
+    }
+
+    loop {
+        reader.read_exact(&mut marker).ok()?;
+
+        if marker[0] != 0xFF {
+            return None;
+        }
+
+        let length = u16::from_be_bytes([marker[2], marker[3]]) as usize;
+
+        // Start of scan, there are no more metadata segments
+        if marker[1] == 0xDA || length < 2 {
+            return None;
+        }
+
+        let mut segment = vec![0u8; length - 2];
+        reader.read_exact(&mut segment).ok()?;
+
+        if marker[1] == 0xE1 && segment.starts_with(JPEG_XMP_HEADER) {
+            let packet = &segment[JPEG_XMP_HEADER.len()..];
+
+            return Some(String::from_utf8_lossy(packet).into_owned());
+        }
+    }
+}
+
+pub fn parse(xml: &str) -> Result<XmpData, quick_xml::Error> {
+    let mut reader = Reader::from_str(xml);
+    let mut data = XmpData::default();
+    let mut stack: Vec<Vec<u8>> = vec![];
+    let mut text = String::new();
+
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) => {
+                if e.name().as_ref() == b"rdf:Description" {
+                    read_attributes(&e, &mut data)?;
+                }
+
+                if e.name().as_ref() == b"dc:subject" {
+                    data.keywords = Some(vec![]);
+                }
+
+                stack.push(e.name().as_ref().to_vec());
+                text.clear();
+            }
+            Event::Empty(e) if e.name().as_ref() == b"rdf:Description" => {
+                read_attributes(&e, &mut data)?;
+            }
+            Event::Text(e) => text.push_str(&unescape(&e.decode()?)?),
+            Event::CData(e) => text.push_str(&e.decode()?),
+            Event::GeneralRef(e) => {
+                if let Some(ch) = e.resolve_char_ref()? {
+                    text.push(ch);
+                } else if let Some(value) = resolve_predefined_entity(&e.decode()?) {
+                    text.push_str(value);
+                }
+            }
+            Event::End(_) => {
+                let name = stack.pop().unwrap_or_default();
+                let value = text.trim().to_owned();
+
+                match name.as_slice() {
+                    b"xmp:Rating" => data.rating = value.parse().ok(),
+                    b"xmp:Label" => data.label = Some(value),
+                    b"rdf:li" if stack.iter().any(|n| n == b"dc:subject") => {
+                        data.keywords.get_or_insert_default().push(value)
+                    }
+                    b"rdf:li" if stack.iter().any(|n| n == b"dc:title") => {
+                        data.title.get_or_insert(value);
+                    }
+                    _ => {}
+                }
+
+                text.clear();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(data)
+}
+
+fn read_attributes(element: &BytesStart, data: &mut XmpData) -> Result<(), quick_xml::Error> {
+    for attr in element.attributes() {
+        let attr = attr.map_err(quick_xml::Error::from)?;
+        let value = attr.unescape_value()?;
+
+        match attr.key.as_ref() {
+            b"xmp:Rating" => data.rating = value.trim().parse().ok(),
+            b"xmp:Label" => data.label = Some(value.into_owned()),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Replaces the rating, label, keywords and title in the first description of
+/// the XMP document, leaving everything else as it was.
+pub fn update(xml: &str, meta: &ImageMeta) -> Result<String, quick_xml::Error> {
+    let mut reader = Reader::from_str(xml);
+    let mut writer = Writer::new(Vec::new());
+    let mut depth = 0usize;
+    // The depth of the description being patched
+    let mut description_depth = None;
+    // The depth of the managed element being dropped
+    let mut skip_depth = None;
+    let mut patched = false;
+
+    loop {
+        let event = reader.read_event()?;
+
+        match &event {
+            Event::Start(_) => depth += 1,
+            Event::End(_) => depth -= 1,
+            _ => {}
+        }
+
+        if let Some(skip) = skip_depth {
+            if matches!(event, Event::End(_)) && depth < skip {
+                skip_depth = None;
+            }
+
+            continue;
+        }
+
+        match event {
+            Event::Start(e) if !patched && e.name().as_ref() == b"rdf:Description" => {
+                writer.write_event(Event::Start(patch_description(&e, meta)?))?;
+                description_depth = Some(depth);
+            }
+            Event::Empty(e) if !patched && e.name().as_ref() == b"rdf:Description" => {
+                let start = patch_description(&e, meta)?;
+                let end = start.to_end().into_owned();
+
+                writer.write_event(Event::Start(start))?;
+                write_elements(&mut writer, meta)?;
+                writer.write_event(Event::End(end))?;
+                patched = true;
+            }
+            Event::Start(e)
+                if description_depth == Some(depth - 1)
+                    && MANAGED_ELEMENTS.contains(&e.name().as_ref()) =>
+            {
+                skip_depth = Some(depth);
+            }
+            Event::Empty(e)
+                if description_depth == Some(depth)
+                    && MANAGED_ELEMENTS.contains(&e.name().as_ref()) => {}
+            Event::End(e) if description_depth == Some(depth + 1) => {
+                write_elements(&mut writer, meta)?;
+                writer.write_event(Event::End(e))?;
+                description_depth = None;
+                patched = true;
+            }
+            Event::Eof => break,
+            e => writer.write_event(e)?,
+        }
+    }
+
+    Ok(String::from_utf8_lossy(&writer.into_inner()).into_owned())
+}
+
+fn patch_description(
+    element: &BytesStart,
+    meta: &ImageMeta,
+) -> Result<BytesStart<'static>, quick_xml::Error> {
+    let mut patched = BytesStart::new("rdf:Description");
+    let mut has_xmp_ns = false;
+    let mut has_dc_ns = false;
+
+    for attr in element.attributes() {
+        let attr = attr.map_err(quick_xml::Error::from)?;
+
+        match attr.key.as_ref() {
+            b"xmp:Rating" | b"xmp:Label" => continue,
+            b"xmlns:xmp" => has_xmp_ns = true,
+            b"xmlns:dc" => has_dc_ns = true,
+            _ => {}
+        }
+
+        patched.push_attribute(attr);
+    }
+
+    if !has_xmp_ns {
+        patched.push_attribute(("xmlns:xmp", XMP_NS));
+    }
+
+    if !has_dc_ns {
+        patched.push_attribute(("xmlns:dc", DC_NS));
+    }
+
+    let rating = if meta.flag == Flag::Reject {
+        -1
+    } else {
+        meta.rating as i8
+    };
+
+    patched.push_attribute(("xmp:Rating", rating.to_string().as_str()));
+
+    if let Some(label) = meta.label {
+        patched.push_attribute(("xmp:Label", label.xmp_name()));
+    }
+
+    Ok(patched)
+}
+
+fn write_elements(writer: &mut Writer<Vec<u8>>, meta: &ImageMeta) -> Result<(), quick_xml::Error> {
+    if !meta.keywords.is_empty() {
+        writer.write_event(Event::Start(BytesStart::new("dc:subject")))?;
+        writer.write_event(Event::Start(BytesStart::new("rdf:Bag")))?;
+
+        for keyword in &meta.keywords {
+            write_text_element(writer, BytesStart::new("rdf:li"), keyword)?;
+        }
+
+        writer.write_event(Event::End(BytesEnd::new("rdf:Bag")))?;
+        writer.write_event(Event::End(BytesEnd::new("dc:subject")))?;
+    }
+
+    if let Some(title) = &meta.title {
+        let mut li = BytesStart::new("rdf:li");
+        li.push_attribute(("xml:lang", "x-default"));
+
+        writer.write_event(Event::Start(BytesStart::new("dc:title")))?;
+        writer.write_event(Event::Start(BytesStart::new("rdf:Alt")))?;
+        write_text_element(writer, li, title)?;
+        writer.write_event(Event::End(BytesEnd::new("rdf:Alt")))?;
+        writer.write_event(Event::End(BytesEnd::new("dc:title")))?;
+    }
+
+    Ok(())
+}
+
+fn write_text_element(
+    writer: &mut Writer<Vec<u8>>,
+    start: BytesStart,
+    text: &str,
+) -> Result<(), quick_xml::Error> {
+    let end = start.to_end().into_owned();
+
+    writer.write_event(Event::Start(start))?;
+    writer.write_event(Event::Text(BytesText::new(text)))?;
+    writer.write_event(Event::End(end))?;
+
+    Ok(())
+}
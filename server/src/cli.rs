@@ -0,0 +1,500 @@
+use std::{
+    collections::HashSet,
+    net::{SocketAddr, TcpStream},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::Duration,
+};
+
+use clap::{Parser, Subcommand, ValueEnum};
+
+use crate::{
+    AppState,
+    api::{self, SyncReport},
+    auth,
+    config::{ConfigArgs, Role},
+    library::Library,
+    scanner::{
+        directory::{Directory, MediaType, ScannerContext},
+        pipeline::ScanPipeline,
+    },
+    thumbnail::bundle::Thumbnail,
+};
+
+/// A photo and video gallery served from a directory tree.
+#[derive(Parser)]
+#[command(name = "mosaic", version)]
+pub struct Cli {
+    /// The configuration file, `mosaic.toml` is used if it exists.
+    #[arg(long, global = true, env = "MOSAIC_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Print the effective configuration and exit.
+    #[arg(long, global = true)]
+    pub print_config: bool,
+    #[command(flatten)]
+    pub overrides: ConfigArgs,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// The commands besides `serve` work on the files directly, they are meant
+/// for preparing a library while the server is not running, e.g. from cron.
+/// The server keeps the catalog and the search index in memory and would
+/// overwrite what `sync` and `clean` save, so they refuse to run while
+/// something listens on the port of the server.
+#[derive(Subcommand)]
+pub enum Command {
+    /// Serve the gallery over HTTP, the default.
+    Serve,
+    /// Index a directory, given as `library/dir`. The server must not be
+    /// running.
+    Sync {
+        dir: String,
+        /// Index the subdirectories with media files as well.
+        #[arg(long, short)]
+        recursive: bool,
+    },
+    /// Remove the thumbnails and the bundles.json of a directory.
+    Clean {
+        dir: String,
+        #[arg(long, short)]
+        recursive: bool,
+    },
+    /// Check the bundles.json files against the files on disk.
+    Verify {
+        /// The directory to check as `library/dir`, every library by default.
+        dir: Option<String>,
+    },
+    /// Print the number and size of the media files in the libraries.
+    Stats,
+    /// Set the password of a user in the users file, read from the standard
+    /// input. Without a users file the user is printed for the config.
+    Passwd {
+        user: String,
+        /// The role of the user, new users are editors by default.
+        #[arg(long, value_enum)]
+        role: Option<Role>,
+    },
+}
+
+pub fn sync(state: &AppState, dir: &str, recursive: bool) -> ExitCode {
+    if server_running(state) {
+        return ExitCode::FAILURE;
+    }
+
+    let Some((library, dirs)) = library_directories(state, dir, recursive) else {
+        return ExitCode::FAILURE;
+    };
+    let context = library.context();
+    let pipeline = ScanPipeline::new(state.config.scan_workers);
+    let mut failed = false;
+
+    for abs_dir in dirs {
+        // The given directory is always indexed, the subdirectories only if
+        // they have media files
+        if recursive && !has_media(&abs_dir) {
+            continue;
+        }
+
+        let relative_dir = context.to_relative_path(&abs_dir);
+
+        api::remove_generated_files(&abs_dir);
+
+        let report = api::scan_directory(
+            library,
+            &pipeline,
+            relative_dir.to_string_lossy().into_owned(),
+        );
+
+        failed |= print_report(&report);
+    }
+
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+/// Checks if the server is running on the configured port and prints why the
+/// command cannot run then.
+fn server_running(state: &AppState) -> bool {
+    let addr = SocketAddr::from(([127, 0, 0, 1], state.config.port));
+
+    if TcpStream::connect_timeout(&addr, Duration::from_millis(500)).is_err() {
+        return false;
+    }
+
+    eprintln!(
+        "The server is running on port {}, it would overwrite the index. Stop it first, or sync the directory from the gallery.",
+        state.config.port
+    );
+
+    true
+}
+
+/// Prints the result of a sync, returns true if the directory could not be
+/// scanned at all.
+fn print_report(report: &SyncReport) -> bool {
+    println!("/{}: {} files", report.directory, report.files);
+
+    for error in &report.errors {
+        if error.file.is_empty() {
+            println!("  {}", error.error);
+        } else {
+            println!("  {}: {}", error.file, error.error);
+        }
+    }
+
+    report.errors.iter().any(|e| e.file.is_empty())
+}
+
+pub fn clean(state: &AppState, dir: &str, recursive: bool) -> ExitCode {
+    if server_running(state) {
+        return ExitCode::FAILURE;
+    }
+
+    let Some((library, dirs)) = library_directories(state, dir, recursive) else {
+        return ExitCode::FAILURE;
+    };
+    let context = library.context();
+    let mut index = library.search_index.lock().unwrap();
+
+    for abs_dir in dirs {
+        if !abs_dir.join("bundles.json").exists() {
+            continue;
+        }
+
+        let relative_dir = context.to_relative_path(&abs_dir);
+
+        api::remove_generated_files(&abs_dir);
+        index.replace_directory(&relative_dir, vec![]);
+
+        println!(
+            "/{}: cleaned",
+            library.server_path(&relative_dir.to_string_lossy())
+        );
+    }
+
+    if let Err(e) = index.save() {
+        eprintln!("{e}");
+
+        return ExitCode::FAILURE;
+    }
+
+    ExitCode::SUCCESS
+}
+
+pub fn verify(state: &AppState, dir: Option<&str>) -> ExitCode {
+    let libraries = match dir {
+        Some(dir) => match library_directories(state, dir, true) {
+            Some(library) => vec![library],
+            None => return ExitCode::FAILURE,
+        },
+        None => {
+            let mut libraries = vec![];
+
+            for library in &state.libraries {
+                let Some(dirs) = directories(&library.context(), "", true) else {
+                    return ExitCode::FAILURE;
+                };
+
+                libraries.push((library, dirs));
+            }
+
+            libraries
+        }
+    };
+    let mut problems = 0;
+
+    for (library, dirs) in libraries {
+        let context = library.context();
+
+        for abs_dir in dirs {
+            let relative_dir = context.to_relative_path(&abs_dir);
+
+            for problem in verify_directory(&abs_dir) {
+                println!(
+                    "/{}: {problem}",
+                    library.server_path(&relative_dir.to_string_lossy())
+                );
+
+                problems += 1;
+            }
+        }
+    }
+
+    if problems > 0 {
+        println!("{problems} problems found");
+
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+/// Compares the bundles.json of an indexed directory with its files: the
+/// listed files and their thumbnails have to exist, and every media file has
+/// to be listed.
+fn verify_directory(dir: &Path) -> Vec<String> {
+    let content = match std::fs::read_to_string(dir.join("bundles.json")) {
+        Ok(content) => content,
+        Err(_) => return vec![],
+    };
+    let thumbnails: Vec<Thumbnail> = match serde_json::from_str(&content) {
+        Ok(thumbnails) => thumbnails,
+        Err(e) => return vec![format!("bundles.json cannot be parsed: {e}")],
+    };
+
+    let mut problems = vec![];
+    let mut listed = HashSet::new();
+
+    for thumbnail in &thumbnails {
+        let files = std::iter::once(&thumbnail.original_name).chain(&thumbnail.raw_name);
+
+        for name in files {
+            listed.insert(name.as_str());
+
+            if !dir.join(name).exists() {
+                problems.push(format!("{name} is missing"));
+            }
+        }
+
+        for name in thumbnail.generated_files() {
+            if !dir.join(name).exists() {
+                problems.push(format!("{name} of {} is missing", thumbnail.original_name));
+            }
+        }
+
+        if let Some(error) = &thumbnail.error {
+            problems.push(format!(
+                "{} is unreadable: {error}",
+                thumbnail.original_name
+            ));
+        }
+    }
+
+    for entry in dir.read_dir().into_iter().flatten().flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+
+        if Directory::is_media(&entry)
+            && !Directory::is_generated_file(&entry.file_name())
+            && !listed.contains(name.as_str())
+        {
+            problems.push(format!("{name} is not indexed"));
+        }
+    }
+
+    problems.sort();
+    problems.dedup();
+    problems
+}
+
+#[derive(Default)]
+struct Stats {
+    directories: u32,
+    indexed_directories: u32,
+    images: u32,
+    videos: u32,
+    media_size: u64,
+    generated_size: u64,
+}
+
+pub fn stats(state: &AppState) -> ExitCode {
+    let mut stats = Stats::default();
+    let mut indexed_files = 0;
+    let mut catalog_files = 0;
+    let mut albums = HashSet::new();
+
+    for library in &state.libraries {
+        let context = library.context();
+        let Some(dirs) = directories(&context, "", true) else {
+            return ExitCode::FAILURE;
+        };
+
+        println!(
+            "{:<21}{}",
+            format!("Library {}:", library.name),
+            context.base_dir.display()
+        );
+
+        for dir in dirs {
+            stats.directories += 1;
+
+            if dir.join("bundles.json").exists() {
+                stats.indexed_directories += 1;
+            }
+
+            for entry in dir.read_dir().into_iter().flatten().flatten() {
+                let size = entry.metadata().map_or(0, |m| m.len());
+
+                if Directory::is_generated_file(&entry.file_name()) {
+                    stats.generated_size += size;
+                } else if Directory::is_media(&entry) {
+                    match MediaType::from_path(&entry.path()) {
+                        Some(MediaType::Video) => stats.videos += 1,
+                        _ => stats.images += 1,
+                    }
+
+                    stats.media_size += size;
+                }
+            }
+        }
+
+        let catalog = library.catalog.lock().unwrap();
+
+        indexed_files += library.search_index.lock().unwrap().entries.len();
+        catalog_files += catalog.images.len();
+        albums.extend(catalog.albums.keys().cloned());
+    }
+
+    println!("Directories:         {}", stats.directories);
+    println!("Indexed directories: {}", stats.indexed_directories);
+    println!("Images:              {}", stats.images);
+    println!("Videos:              {}", stats.videos);
+    println!("Media size:          {}", format_size(stats.media_size));
+    println!("Thumbnails size:     {}", format_size(stats.generated_size));
+    println!("Search index:        {indexed_files} files");
+    println!("Catalog:             {catalog_files} files with metadata");
+    println!("Albums:              {}", albums.len());
+
+    ExitCode::SUCCESS
+}
+
+pub fn passwd(state: &AppState, user: &str, role: Option<Role>) -> ExitCode {
+    if user.is_empty() {
+        eprintln!("The user name cannot be empty");
+
+        return ExitCode::FAILURE;
+    }
+
+    let mut password = String::new();
+
+    if let Err(e) = std::io::stdin().read_line(&mut password) {
+        eprintln!("Cannot read the password: {e}");
+
+        return ExitCode::FAILURE;
+    }
+
+    let password = password.trim_end_matches(['\r', '\n']);
+
+    if password.is_empty() {
+        eprintln!("The password cannot be empty");
+
+        return ExitCode::FAILURE;
+    }
+
+    let users_file = state
+        .config
+        .auth
+        .as_ref()
+        .and_then(|a| a.users_file.as_ref());
+
+    let Some(users_file) = users_file else {
+        println!("[[auth.users]]");
+        println!("name = {user:?}");
+        println!("password_hash = {:?}", auth::hash_password(password));
+        println!(
+            "role = {:?}",
+            role.unwrap_or_default()
+                .to_possible_value()
+                .unwrap()
+                .get_name()
+        );
+
+        return ExitCode::SUCCESS;
+    };
+
+    match auth::set_password(Path::new(users_file), user, password, role) {
+        Ok(()) => {
+            println!("Password of {user} set in {users_file}, restart the server to use it");
+
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("{e}");
+
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+
+    let mut size = size as f64;
+    let mut unit = 0;
+
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// The library of a `library/dir` argument with the directories to process,
+/// see `directories`.
+fn library_directories<'a>(
+    state: &'a AppState,
+    dir: &str,
+    recursive: bool,
+) -> Option<(&'a Library, Vec<PathBuf>)> {
+    let Some((library, path)) = state.resolve(dir) else {
+        let names: Vec<&str> = state.libraries.iter().map(|l| l.name.as_str()).collect();
+
+        eprintln!(
+            "{dir} is not in a library, the libraries are: {}",
+            names.join(", ")
+        );
+
+        return None;
+    };
+
+    Some((library, directories(&library.context(), path, recursive)?))
+}
+
+/// The absolute path of the directory and of its subdirectories if
+/// recursive, skipping the hidden ones like the `.mosaic` data directory.
+fn directories(context: &ScannerContext, dir: &str, recursive: bool) -> Option<Vec<PathBuf>> {
+    let Some(abs_dir) = context.to_sandboxed_path(dir).filter(|d| d.is_dir()) else {
+        eprintln!(
+            "{dir} is not a directory under {}",
+            context.base_dir.display()
+        );
+
+        return None;
+    };
+
+    let mut result = vec![];
+    let mut pending = vec![abs_dir];
+
+    while let Some(dir) = pending.pop() {
+        if recursive {
+            let mut subdirs: Vec<PathBuf> = dir
+                .read_dir()
+                .into_iter()
+                .flatten()
+                .flatten()
+                .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+                .filter(|e| !e.file_name().to_string_lossy().starts_with('.'))
+                .map(|e| e.path())
+                .collect();
+
+            subdirs.sort();
+            pending.extend(subdirs.into_iter().rev());
+        }
+
+        result.push(dir);
+    }
+
+    Some(result)
+}
+
+fn has_media(dir: &Path) -> bool {
+    dir.read_dir()
+        .into_iter()
+        .flatten()
+        .flatten()
+        .any(|e| Directory::is_media(&e))
+}
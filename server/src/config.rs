@@ -0,0 +1,383 @@
+use std::{
+    collections::HashSet,
+    net::TcpListener,
+    path::{Path, PathBuf},
+};
+
+use clap::{Args, ValueEnum};
+use serde::{Deserialize, Serialize};
+
+use crate::{auth, share};
+
+/// The name of the library when only the root directory is configured.
+const DEFAULT_LIBRARY: &str = "main";
+
+/// The settings of the server. Every key has a default, which is overridden
+/// by the config file, then by the `MOSAIC_*` environment variables and
+/// finally by the command line.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The HTML page of the gallery.
+    pub gallery_index: String,
+    /// `stdout` or the path of the log file.
+    pub logfile: String,
+    /// The maximum total size of the files in a ZIP download in bytes.
+    pub max_download_size: u64,
+    /// The maximum size of an uploaded file in bytes.
+    pub max_upload_size: u64,
+    pub port: u16,
+    pub root_directory: String,
+    /// The number of threads decoding the images during the sync, 0 means
+    /// one per CPU.
+    pub scan_workers: usize,
+    /// The maximum Hamming distance of the perceptual hashes of two images
+    /// to be considered similar.
+    pub similarity_threshold: u32,
+    /// Write the metadata changes back to XMP sidecars of the images.
+    pub xmp_sidecars: bool,
+    /// The libraries served under `/serve/{name}/`. Without any, the
+    /// `root_directory` is served as the library `main`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub libraries: Vec<LibraryConfig>,
+    /// Without this section anyone reaching the port can use the server.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth: Option<AuthConfig>,
+    /// Without this section no share links can be created.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shares: Option<ShareConfig>,
+}
+
+/// A directory tree served as a library.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LibraryConfig {
+    pub name: String,
+    pub root_directory: String,
+    /// Where the catalog and the search index are kept, the `.mosaic`
+    /// directory of the root directory by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_directory: Option<String>,
+    /// The files of a read-only library cannot be uploaded, changed or
+    /// deleted through the server, nor their metadata. The server does not
+    /// index it either, the thumbnails are written by `mosaic sync`.
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+impl LibraryConfig {
+    pub fn cache_directory(&self) -> PathBuf {
+        match &self.cache_directory {
+            Some(dir) => PathBuf::from(dir),
+            None => Path::new(&self.root_directory).join(".mosaic"),
+        }
+    }
+}
+
+/// Login with a user name and password, the users come from the config and
+/// from the users file.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub users: Vec<UserConfig>,
+    /// The local store of users, `mosaic passwd` adds them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub users_file: Option<String>,
+    /// How long a login lasts.
+    #[serde(default = "default_session_hours")]
+    pub session_hours: u64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<RuleConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    pub name: String,
+    /// An Argon2 hash in the PHC string format, `mosaic passwd` prints one.
+    pub password_hash: String,
+    #[serde(default)]
+    pub role: Role,
+}
+
+/// What a user can do: viewers browse, search and download, editors change
+/// the files, their metadata and the albums and sync the directories.
+/// Admins are editors everywhere, the rules don't apply to them.
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord, ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Viewer,
+    #[default]
+    Editor,
+    Admin,
+}
+
+/// Gives a role to the users under a library or a directory of a library,
+/// e.g. `family/2024`. The rule with the longest path decides, a rule
+/// naming the user wins over one for everyone with the same path.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    pub path: String,
+    pub role: Role,
+    /// The users the rule applies to, everyone if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub users: Vec<String>,
+}
+
+/// Links for people without a login, which show a directory or an album
+/// until they expire.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShareConfig {
+    /// The key signing the links, it is generated if the file does not exist.
+    /// Replacing it invalidates every link. `share_secret` in the cache
+    /// directory of the first library by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret_file: Option<String>,
+    /// The ids of the revoked links, one per line. `revoked_shares` in the
+    /// cache directory of the first library by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revoked_file: Option<String>,
+    /// The longest a link can be valid for.
+    #[serde(default = "default_max_hours")]
+    pub max_hours: u64,
+}
+
+impl ShareConfig {
+    /// The files are kept in a cache directory by default, which is never
+    /// served, not next to the images.
+    pub fn secret_file(&self, cache_dir: &Path) -> PathBuf {
+        match &self.secret_file {
+            Some(file) => PathBuf::from(file),
+            None => cache_dir.join("share_secret"),
+        }
+    }
+
+    pub fn revoked_file(&self, cache_dir: &Path) -> PathBuf {
+        match &self.revoked_file {
+            Some(file) => PathBuf::from(file),
+            None => cache_dir.join("revoked_shares"),
+        }
+    }
+}
+
+fn default_session_hours() -> u64 {
+    7 * 24
+}
+
+fn default_max_hours() -> u64 {
+    30 * 24
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            gallery_index: "./gallery_index.html".to_owned(),
+            logfile: "stdout".to_owned(),
+            max_download_size: 4 * 1024 * 1024 * 1024,
+            max_upload_size: 100 * 1024 * 1024,
+            port: 3000,
+            root_directory: ".".to_owned(),
+            scan_workers: 0,
+            similarity_threshold: 10,
+            xmp_sidecars: true,
+            libraries: vec![],
+            auth: None,
+            shares: None,
+        }
+    }
+}
+
+/// The overrides of the config keys, clap takes them from the command line
+/// or from the environment variables.
+#[derive(Args, Debug, Default)]
+pub struct ConfigArgs {
+    #[arg(long, global = true, env = "MOSAIC_GALLERY_INDEX")]
+    pub gallery_index: Option<String>,
+    #[arg(long, global = true, env = "MOSAIC_LOGFILE")]
+    pub logfile: Option<String>,
+    #[arg(long, global = true, env = "MOSAIC_MAX_DOWNLOAD_SIZE")]
+    pub max_download_size: Option<u64>,
+    #[arg(long, global = true, env = "MOSAIC_MAX_UPLOAD_SIZE")]
+    pub max_upload_size: Option<u64>,
+    #[arg(long, global = true, env = "MOSAIC_PORT")]
+    pub port: Option<u16>,
+    /// The root directory, when no libraries are configured.
+    #[arg(long, alias = "path", global = true, env = "MOSAIC_ROOT")]
+    pub root: Option<String>,
+    #[arg(long, global = true, env = "MOSAIC_SCAN_WORKERS")]
+    pub scan_workers: Option<usize>,
+    #[arg(long, global = true, env = "MOSAIC_SIMILARITY_THRESHOLD")]
+    pub similarity_threshold: Option<u32>,
+    #[arg(long, global = true, env = "MOSAIC_XMP_SIDECARS")]
+    pub xmp_sidecars: Option<bool>,
+}
+
+impl Config {
+    /// Reads the config file over the defaults and applies the overrides. A
+    /// missing file is only an error if it was given explicitly.
+    pub fn load(path: Option<&Path>, overrides: &ConfigArgs) -> Result<Config, String> {
+        let default_path = PathBuf::from("mosaic.toml");
+        let mut config = match path {
+            Some(path) => Config::read(path)?,
+            None if default_path.exists() => Config::read(&default_path)?,
+            None => Config::default(),
+        };
+
+        config.apply(overrides);
+
+        Ok(config)
+    }
+
+    fn read(path: &Path) -> Result<Config, String> {
+        let content =
+            std::fs::read_to_string(path).map_err(|e| format!("Cannot read {path:?}: {e}"))?;
+
+        toml::from_str(&content).map_err(|e| format!("Error parsing {path:?}: {e}"))
+    }
+
+    fn apply(&mut self, overrides: &ConfigArgs) {
+        let ConfigArgs {
+            gallery_index,
+            logfile,
+            max_download_size,
+            max_upload_size,
+            port,
+            root,
+            scan_workers,
+            similarity_threshold,
+            xmp_sidecars,
+        } = overrides;
+
+        if let Some(gallery_index) = gallery_index {
+            self.gallery_index = gallery_index.clone();
+        }
+        if let Some(logfile) = logfile {
+            self.logfile = logfile.clone();
+        }
+        if let Some(max_download_size) = max_download_size {
+            self.max_download_size = *max_download_size;
+        }
+        if let Some(max_upload_size) = max_upload_size {
+            self.max_upload_size = *max_upload_size;
+        }
+        if let Some(port) = port {
+            self.port = *port;
+        }
+        if let Some(root) = root {
+            self.root_directory = root.clone();
+        }
+        if let Some(scan_workers) = scan_workers {
+            self.scan_workers = *scan_workers;
+        }
+        if let Some(similarity_threshold) = similarity_threshold {
+            self.similarity_threshold = *similarity_threshold;
+        }
+        if let Some(xmp_sidecars) = xmp_sidecars {
+            self.xmp_sidecars = *xmp_sidecars;
+        }
+    }
+
+    /// The configured libraries, or the root directory as the only one.
+    pub fn libraries(&self) -> Vec<LibraryConfig> {
+        if !self.libraries.is_empty() {
+            return self.libraries.clone();
+        }
+
+        vec![LibraryConfig {
+            name: DEFAULT_LIBRARY.to_owned(),
+            root_directory: self.root_directory.clone(),
+            cache_directory: None,
+            read_only: false,
+        }]
+    }
+
+    /// Where the share files are kept unless they are configured.
+    pub fn share_cache_dir(&self) -> PathBuf {
+        self.libraries()[0].cache_directory()
+    }
+
+    /// Checks the settings which would only fail later, while serving. The
+    /// gallery page and the port are only needed by the server.
+    pub fn validate(&self, serving: bool) -> Result<(), Vec<String>> {
+        let mut errors = vec![];
+        let mut names = HashSet::new();
+
+        for library in self.libraries() {
+            let name = &library.name;
+
+            if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+                errors.push(format!("{name:?} is not a valid library name"));
+            }
+
+            if !names.insert(name.clone()) {
+                errors.push(format!("library {name:?} is configured more than once"));
+            }
+
+            if !Path::new(&library.root_directory).is_dir() {
+                errors.push(format!(
+                    "root_directory {:?} of library {name:?} is not an existing directory",
+                    library.root_directory
+                ));
+            }
+        }
+
+        if let Some(auth) = &self.auth {
+            let libraries: Vec<String> = self.libraries().into_iter().map(|l| l.name).collect();
+
+            errors.extend(auth::check_config(auth, &libraries, serving));
+
+            if auth.session_hours == 0 {
+                errors.push("auth.session_hours must be greater than 0".to_owned());
+            }
+        }
+
+        if let Some(shares) = &self.shares {
+            errors.extend(share::check_config(
+                shares,
+                &self.share_cache_dir(),
+                serving,
+            ));
+        }
+
+        if self.max_download_size == 0 {
+            errors.push("max_download_size must be greater than 0".to_owned());
+        }
+
+        if self.max_upload_size == 0 {
+            errors.push("max_upload_size must be greater than 0".to_owned());
+        }
+
+        if serving {
+            if let Err(e) = std::fs::File::open(&self.gallery_index) {
+                errors.push(format!(
+                    "gallery_index {:?} cannot be read: {e}",
+                    self.gallery_index
+                ));
+            }
+
+            if let Err(e) = TcpListener::bind(self.bind_addr()) {
+                errors.push(format!("port {} cannot be used: {e}", self.port));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn bind_addr(&self) -> String {
+        format!("0.0.0.0:{}", self.port)
+    }
+
+    /// The effective settings in the format of the config file.
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).expect("Cannot serialize the config")
+    }
+}
@@ -0,0 +1,102 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::{
+    api::SyncReport,
+    catalog::{index::SearchIndex, store::Catalog, xmp},
+    config::LibraryConfig,
+    scanner::directory::ScannerContext,
+};
+
+/// A directory tree served under `/serve/{name}/` with its own catalog and
+/// search index. The clients address the files with the name of the library
+/// as the first path segment, inside the server the paths are relative to
+/// the root of the library.
+pub struct Library {
+    pub name: String,
+    pub root: PathBuf,
+    /// Where the catalog, the search index and the trash are kept.
+    pub cache_dir: PathBuf,
+    pub read_only: bool,
+    pub catalog: Mutex<Catalog>,
+    pub search_index: Mutex<SearchIndex>,
+    /// The report of the last sync by directory.
+    pub sync_reports: Mutex<HashMap<String, SyncReport>>,
+}
+
+impl Library {
+    pub fn open(config: &LibraryConfig) -> Result<Self, String> {
+        let cache_dir = config.cache_directory();
+
+        Ok(Library {
+            name: config.name.clone(),
+            root: PathBuf::from(&config.root_directory),
+            read_only: config.read_only,
+            catalog: Mutex::new(Catalog::open(&cache_dir)?),
+            search_index: Mutex::new(SearchIndex::open(&cache_dir)),
+            sync_reports: Mutex::new(HashMap::new()),
+            cache_dir,
+        })
+    }
+
+    pub fn context(&self) -> ScannerContext {
+        ScannerContext::new(&self.root)
+    }
+
+    /// The path of a file of the library as the clients see it.
+    pub fn server_path(&self, key: &str) -> String {
+        let key = key.trim_start_matches('/');
+
+        if key.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{}/{key}", self.name)
+        }
+    }
+
+    /// Moves a file of the library into the trash under the cache directory,
+    /// with its own XMP sidecar. The file keeps its relative path there, an
+    /// earlier file of the same path gets a numbered name.
+    pub fn trash(&self, key: &str) -> io::Result<PathBuf> {
+        let path = self.root.join(key);
+        let sidecar = xmp::own_sidecar_path(&path);
+        let first = self.cache_dir.join("trash").join(key);
+        let mut target = first.clone();
+        let mut n = 1;
+
+        while target.exists() || xmp::own_sidecar_path(&target).exists() {
+            let stem = first.file_stem().unwrap_or_default().to_string_lossy();
+
+            target = match first.extension() {
+                Some(ext) => first.with_file_name(format!("{stem}.{n}.{}", ext.to_string_lossy())),
+                None => first.with_file_name(format!("{stem}.{n}")),
+            };
+            n += 1;
+        }
+
+        std::fs::create_dir_all(target.parent().unwrap())?;
+        move_file(&path, &target)?;
+
+        if sidecar.is_file() {
+            move_file(&sidecar, &xmp::own_sidecar_path(&target))?;
+        }
+
+        Ok(target)
+    }
+}
+
+/// Renames the file, falling back to copy and delete if the target is on
+/// another filesystem. The other errors of the rename are returned.
+pub fn move_file(source: &Path, target: &Path) -> io::Result<()> {
+    match std::fs::rename(source, target) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            std::fs::copy(source, target)?;
+            std::fs::remove_file(source)
+        }
+        other => other,
+    }
+}
@@ -1,31 +1,43 @@
-use std::{fs::File, sync::Arc};
+use std::{fs::File, process::ExitCode, sync::Arc};
 
 use axum::{
     Router,
+    extract::{self, DefaultBodyLimit},
+    middleware,
     response::Redirect,
-    routing::{get, post},
+    routing::{delete, get, post},
 };
-use log::info;
-use serde::Deserialize;
+use clap::Parser;
+use log::{LevelFilter, error, info};
 use tokio::{net::TcpListener, sync::mpsc};
 
-use crate::api::SyncCommand;
+use crate::{
+    api::SyncCommand,
+    auth::{Auth, CurrentUser},
+    cli::{Cli, Command},
+    config::{Config, Role},
+    library::Library,
+    share::Shares,
+};
 
 mod api;
+mod auth;
+mod catalog;
+mod cli;
+mod config;
+mod library;
 mod scanner;
+mod share;
 mod thumbnail;
 
-#[derive(Deserialize)]
-pub struct Config {
-    pub gallery_index: String,
-    logfile: String,
-    port: u16,
-    pub root_directory: String,
-}
-
 pub struct AppState {
     pub command_tx: mpsc::Sender<SyncCommand>,
     pub config: Config,
+    pub libraries: Vec<Library>,
+    /// `None` if the server is open to everyone.
+    pub auth: Option<Auth>,
+    /// `None` if sharing is not enabled.
+    pub shares: Option<Shares>,
 }
 
 // TODO
@@ -35,90 +47,468 @@ pub struct AppState {
 // We can always use fetch('./thumbs.json') to get the file from the current
 // directory
 
-#[tokio::main]
-async fn main() {
-    let config = read_config();
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let config = match Config::load(cli.config.as_deref(), &cli.overrides) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
 
-    init_logger(&config.logfile);
+            return ExitCode::from(2);
+        }
+    };
 
-    let bind_addr = format!("0.0.0.0:{}", config.port);
-    let (cmd_tx, cmd_rx) = mpsc::channel(16);
+    if cli.print_config {
+        print!("{}", config.to_toml());
 
-    tokio::spawn(async move {
-        api::sync_directory(cmd_rx).await;
-    });
+        return ExitCode::SUCCESS;
+    }
+
+    let command = cli.command.unwrap_or(Command::Serve);
+
+    if let Err(errors) = config.validate(matches!(command, Command::Serve)) {
+        eprintln!("Invalid configuration:");
+
+        for error in errors {
+            eprintln!("  {error}");
+        }
+
+        return ExitCode::from(2);
+    }
+
+    // The offline commands print their results, the log is for the problems
+    let level = match command {
+        Command::Serve => LevelFilter::Debug,
+        _ => LevelFilter::Warn,
+    };
+
+    init_logger(&config.logfile, level);
+
+    let (state, cmd_rx) = match AppState::new(config) {
+        Ok(state) => state,
+        Err(e) => {
+            eprintln!("{e}");
+
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match command {
+        Command::Serve => {
+            serve(state, cmd_rx);
+
+            ExitCode::SUCCESS
+        }
+        Command::Sync { dir, recursive } => cli::sync(&state, &dir, recursive),
+        Command::Clean { dir, recursive } => cli::clean(&state, &dir, recursive),
+        Command::Verify { dir } => cli::verify(&state, dir.as_deref()),
+        Command::Stats => cli::stats(&state),
+        Command::Passwd { user, role } => cli::passwd(&state, &user, role),
+    }
+}
+
+impl AppState {
+    /// The state with an empty queue of sync commands, the receiver is for
+    /// the sync worker. Fails if a catalog cannot be read.
+    fn new(config: Config) -> Result<(Self, mpsc::Receiver<SyncCommand>), String> {
+        let (cmd_tx, cmd_rx) = mpsc::channel(16);
+
+        let state = AppState {
+            command_tx: cmd_tx,
+            libraries: config
+                .libraries()
+                .iter()
+                .map(Library::open)
+                .collect::<Result<_, _>>()?,
+            auth: config.auth.as_ref().map(Auth::new),
+            // The config is validated at startup, so this only fails for the
+            // commands which don't serve
+            shares: config.shares.as_ref().and_then(|c| {
+                Shares::new(c, &config.share_cache_dir())
+                    .inspect_err(|e| error!("{e}"))
+                    .ok()
+            }),
+            config,
+        };
+
+        Ok((state, cmd_rx))
+    }
+
+    pub fn library(&self, name: &str) -> Option<&Library> {
+        self.libraries.iter().find(|l| l.name == name)
+    }
+
+    /// Splits a path of the clients into its library and the path inside the
+    /// library. The hidden files and directories and the cache directory are
+    /// never reached, they hold the catalog, the trash and the settings.
+    pub fn resolve<'a>(&self, path: &'a str) -> Option<(&Library, &'a str)> {
+        let path = path.trim_start_matches('/');
+        let (name, rest) = path.split_once('/').unwrap_or((path, ""));
+
+        if rest.split('/').any(|segment| segment.starts_with('.')) {
+            return None;
+        }
+
+        let library = self.library(name)?;
+
+        // A cache directory which is configured inside the root is not hidden
+        let in_cache = library
+            .context()
+            .to_sandboxed_path(rest)
+            .is_some_and(|path| path.starts_with(&library.cache_dir));
+
+        (!in_cache).then_some((library, rest))
+    }
+
+    /// The role of the user for a path of the clients, everyone is an admin
+    /// if the server has no auth.
+    pub fn role(&self, user: &CurrentUser, path: &str) -> Role {
+        match (&self.auth, &user.name) {
+            (Some(auth), Some(name)) => auth.role(name, path),
+            (Some(_), None) => Role::Viewer,
+            (None, _) => Role::Admin,
+        }
+    }
+}
+
+#[tokio::main]
+async fn serve(state: AppState, cmd_rx: mpsc::Receiver<SyncCommand>) {
+    let bind_addr = state.config.bind_addr();
+    let state = Arc::new(state);
+
+    tokio::spawn({
+        let shared_state = Arc::clone(&state);
 
-    let state = Arc::new(AppState {
-        command_tx: cmd_tx,
-        config,
+        async move {
+            api::sync_directory(cmd_rx, shared_state).await;
+        }
     });
 
-    let app = Router::new()
+    let listener = TcpListener::bind(&bind_addr).await.unwrap();
+
+    info!("Starting HTTP serve on {bind_addr}");
+
+    axum::serve(listener, app(state)).await.unwrap();
+}
+
+/// The routes of the server, all of them need a login if auth is configured
+/// except the share links under `/s/`.
+fn app(state: Arc<AppState>) -> Router {
+    Router::new()
         .route("/", get(|| async { Redirect::permanent("/serve/") }))
         .route(
             "/sync/{*path}",
+            post({
+                let shared_state = Arc::clone(&state);
+                move |path, user| api::directory_sync_handler(path, user, shared_state)
+            }),
+        )
+        .route(
+            "/api/sync/{*path}",
             get({
                 let shared_state = Arc::clone(&state);
-                move |path| api::directory_sync_handler(path, shared_state)
+                move |path| api::sync_report(path, shared_state)
             }),
         )
         .route(
             "/serve{*path}",
             get({
                 let shared_state = Arc::clone(&state);
-                move |path| api::serve_content(path, shared_state)
+                move |path, filter, quality, headers, user| {
+                    api::serve_content(path, filter, quality, headers, user, shared_state)
+                }
             }),
         )
         .route(
-            "/delete/{*path}",
+            "/preview/{*path}",
             get({
                 let shared_state = Arc::clone(&state);
-                move |path| api::delete_image(path, shared_state)
+                move |path, headers| api::stream::serve_preview(path, headers, shared_state)
+            }),
+        )
+        .route(
+            "/delete/{*path}",
+            post({
+                let shared_state = Arc::clone(&state);
+                move |path, user| api::delete_image(path, user, shared_state)
             }),
         )
         .route(
             "/delete",
             post({
                 let shared_state = Arc::clone(&state);
-                move |body| api::delete_images(shared_state, body)
+                move |user, body| api::delete_images(user, shared_state, body)
             }),
-        );
-
-    let listener = TcpListener::bind(bind_addr).await.unwrap();
-
-    info!("Starting HTTP serve on :3000");
-
-    axum::serve(listener, app).await.unwrap();
-}
-
-fn read_config() -> Config {
-    let cfg_file = std::fs::read_to_string("mosaic.toml").expect("Cannot find mosaic.toml");
-    let mut config: Config = toml::from_str(&cfg_file).expect("Error parsing mosaic.toml");
-
-    let mut args = std::env::args();
-
-    while let Some(arg) = args.next() {
-        if arg == "--path"
-            && let Some(path) = args.next()
-        {
-            info!("Use {path} as root directory");
-
-            config.root_directory = path;
-        }
-    }
-
-    config
+        )
+        .route(
+            "/download/{*path}",
+            get({
+                let shared_state = Arc::clone(&state);
+                move |path| api::download::download_directory(path, shared_state)
+            }),
+        )
+        .route(
+            "/api/download",
+            post({
+                let shared_state = Arc::clone(&state);
+                move |body| api::download::download_files(shared_state, body)
+            }),
+        )
+        .route(
+            "/api/meta/{*path}",
+            get({
+                let shared_state = Arc::clone(&state);
+                move |path| api::meta::get_meta(path, shared_state)
+            })
+            .put({
+                let shared_state = Arc::clone(&state);
+                move |path, user, body| api::meta::update_meta(path, user, shared_state, body)
+            }),
+        )
+        .route(
+            "/api/tags",
+            get({
+                let shared_state = Arc::clone(&state);
+                move || api::tags::list_tags(shared_state)
+            })
+            .post({
+                let shared_state = Arc::clone(&state);
+                move |user, body| api::tags::update_tags(user, shared_state, body)
+            }),
+        )
+        .route(
+            "/api/tags/{tag}",
+            get({
+                let shared_state = Arc::clone(&state);
+                move |tag| api::tags::tagged_images(tag, shared_state)
+            }),
+        )
+        .route(
+            "/tags{*path}",
+            get({
+                let shared_state = Arc::clone(&state);
+                move |path| api::tags::serve_tag_view(path, shared_state)
+            }),
+        )
+        .route(
+            "/api/duplicates",
+            get({
+                let shared_state = Arc::clone(&state);
+                move || api::duplicates::list_duplicates(shared_state)
+            }),
+        )
+        .route(
+            "/api/duplicates/resolve",
+            post({
+                let shared_state = Arc::clone(&state);
+                move |user, body| api::duplicates::resolve_duplicates(user, shared_state, body)
+            }),
+        )
+        .route(
+            "/api/similar/{*path}",
+            get({
+                let shared_state = Arc::clone(&state);
+                move |path, query| api::similar::similar_images(path, query, shared_state)
+            }),
+        )
+        .route(
+            "/api/search",
+            get({
+                let shared_state = Arc::clone(&state);
+                move |query| api::search::search(query, shared_state)
+            }),
+        )
+        .route(
+            "/search{*path}",
+            get({
+                let shared_state = Arc::clone(&state);
+                move |path, query| api::search::serve_search_view(path, query, shared_state)
+            }),
+        )
+        .route(
+            "/api/albums",
+            get({
+                let shared_state = Arc::clone(&state);
+                move || api::albums::list_albums(shared_state)
+            })
+            .post({
+                let shared_state = Arc::clone(&state);
+                move |user, body| api::albums::create_album(user, shared_state, body)
+            }),
+        )
+        .route(
+            "/api/albums/{name}",
+            get({
+                let shared_state = Arc::clone(&state);
+                move |name| api::albums::get_album(name, shared_state)
+            })
+            .put({
+                let shared_state = Arc::clone(&state);
+                move |name, user, body| api::albums::update_album(name, user, shared_state, body)
+            })
+            .delete({
+                let shared_state = Arc::clone(&state);
+                move |name, user| api::albums::delete_album(name, user, shared_state)
+            }),
+        )
+        .route(
+            "/albums{*path}",
+            get({
+                let shared_state = Arc::clone(&state);
+                move |path| api::albums::serve_album_view(path, shared_state)
+            }),
+        )
+        .route(
+            "/api/files/move",
+            post({
+                let shared_state = Arc::clone(&state);
+                move |user, body| api::files::move_files(user, shared_state, body)
+            }),
+        )
+        .route(
+            "/api/files/rename",
+            post({
+                let shared_state = Arc::clone(&state);
+                move |user, body| api::files::rename_file(user, shared_state, body)
+            }),
+        )
+        .route(
+            "/api/files/copy",
+            post({
+                let shared_state = Arc::clone(&state);
+                move |user, body| api::files::copy_files(user, shared_state, body)
+            }),
+        )
+        .route(
+            "/api/upload/{*path}",
+            post({
+                let shared_state = Arc::clone(&state);
+                move |path, query, user, multipart| {
+                    api::upload::upload_files(path, query, user, shared_state, multipart)
+                }
+            })
+            // The size of the uploaded files is checked one by one
+            .layer(DefaultBodyLimit::disable()),
+        )
+        .route(
+            "/api/permissions",
+            get({
+                let shared_state = Arc::clone(&state);
+                move |query, user| api::auth::permissions(query, user, shared_state)
+            }),
+        )
+        .route(
+            "/api/shares",
+            post({
+                let shared_state = Arc::clone(&state);
+                move |user, body| api::shares::create_share(user, shared_state, body)
+            }),
+        )
+        .route(
+            "/api/shares/{id}",
+            delete({
+                let shared_state = Arc::clone(&state);
+                move |id, user| api::shares::revoke_share(id, user, shared_state)
+            }),
+        )
+        .route(
+            "/s/{token}",
+            get(|extract::Path(token): extract::Path<String>| async move {
+                Redirect::to(&format!("/s/{token}/serve/"))
+            }),
+        )
+        .route(
+            "/s/{token}/serve{*path}",
+            get({
+                let shared_state = Arc::clone(&state);
+                move |path, filter, quality, headers| {
+                    api::shares::serve_shared(path, filter, quality, headers, shared_state)
+                }
+            }),
+        )
+        .route(
+            "/s/{token}/preview/{*path}",
+            get({
+                let shared_state = Arc::clone(&state);
+                move |path, headers| api::shares::serve_shared_preview(path, headers, shared_state)
+            }),
+        )
+        .route(
+            "/s/{token}/download/{*path}",
+            get({
+                let shared_state = Arc::clone(&state);
+                move |path, headers| api::shares::download_shared(path, headers, shared_state)
+            }),
+        )
+        .route(
+            "/s/{token}/api/download",
+            post({
+                let shared_state = Arc::clone(&state);
+                move |token, headers, body| {
+                    api::shares::download_shared_files(token, headers, shared_state, body)
+                }
+            }),
+        )
+        .route(
+            "/s/{token}/api/upload{*path}",
+            post({
+                let shared_state = Arc::clone(&state);
+                move |path, query, headers, multipart| {
+                    api::shares::upload_shared(path, query, headers, shared_state, multipart)
+                }
+            })
+            .layer(DefaultBodyLimit::disable()),
+        )
+        .route(
+            "/s/{token}/api/permissions",
+            get({
+                let shared_state = Arc::clone(&state);
+                move |token, headers| api::shares::shared_permissions(token, headers, shared_state)
+            }),
+        )
+        .route(
+            "/s/{token}/login",
+            post({
+                let shared_state = Arc::clone(&state);
+                move |token, form| api::shares::unlock_share(token, shared_state, form)
+            }),
+        )
+        .route(
+            "/login",
+            get({
+                let shared_state = Arc::clone(&state);
+                move |query| api::auth::login_page(query, shared_state)
+            })
+            .post({
+                let shared_state = Arc::clone(&state);
+                move |form| api::auth::login(shared_state, form)
+            }),
+        )
+        .route(
+            "/logout",
+            get({
+                let shared_state = Arc::clone(&state);
+                move |headers| api::auth::logout(headers, shared_state)
+            })
+            .post({
+                let shared_state = Arc::clone(&state);
+                move |headers| api::auth::logout(headers, shared_state)
+            }),
+        )
+        .layer(middleware::from_fn(move |request, next| {
+            api::auth::require_login(request, next, Arc::clone(&state))
+        }))
 }
 
-fn init_logger(logfile: &str) {
+fn init_logger(logfile: &str, level: LevelFilter) {
     use env_logger::Target;
 
     let mut builder = env_logger::builder();
 
-    builder.filter_level(log::LevelFilter::Debug);
+    builder.filter_level(level);
 
     if logfile != "stdout" {
-        let logfile = File::create("./mosaic.log").expect("Failed to open logfile");
+        let logfile = File::create(logfile).expect("Failed to open logfile");
 
         builder.target(Target::Pipe(Box::new(logfile)));
     }
@@ -1,20 +1,49 @@
 use std::{
-    fs::{DirEntry, File},
-    io::BufWriter,
-    path::{Path, PathBuf},
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
+    fs::DirEntry,
+    path::{Component, Path, PathBuf},
     time::Instant,
 };
 
 use log::debug;
-use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
-use crate::thumbnail::{bundle::ImageBundle, image::Image};
+use crate::{
+    scanner::pipeline::ScanPipeline,
+    thumbnail::{bundle::Thumbnail, image::Image},
+};
 
 pub struct ScannerContext {
     /// The directory which is the root of the whole application.
     pub base_dir: PathBuf,
 }
 
+/// The kind of the media files picked up by the scanner.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaType {
+    #[default]
+    Image,
+    Video,
+}
+
+impl MediaType {
+    pub fn from_path(path: &Path) -> Option<MediaType> {
+        if Directory::has_image_extension(path)
+            || Directory::has_raw_extension(path)
+            || Directory::has_heif_extension(path)
+            || Directory::has_animation_extension(path)
+        {
+            Some(MediaType::Image)
+        } else if Directory::has_video_extension(path) {
+            Some(MediaType::Video)
+        } else {
+            None
+        }
+    }
+}
+
 pub struct Directory {
     pub id: u32,
     pub absolute_path: PathBuf,
@@ -47,23 +76,76 @@ impl ScannerContext {
         }
     }
 
-    pub fn scan(&self, path: impl AsRef<Path>) -> Directory {
+    /// Resolves a path relative to the base dir, refusing anything which would
+    /// escape from it via `..` components.
+    pub fn to_sandboxed_path(&self, path: impl AsRef<Path>) -> Option<PathBuf> {
+        let mut result = self.base_dir.clone();
+
+        for component in path.as_ref().components() {
+            match component {
+                Component::Normal(name) => result.push(name),
+                Component::RootDir | Component::CurDir => {}
+                Component::ParentDir | Component::Prefix(_) => return None,
+            }
+        }
+
+        Some(result)
+    }
+
+    /// The normalized relative path of a file, which is used as a key in the
+    /// catalog.
+    pub fn to_relative_key(&self, path: impl AsRef<Path>) -> Option<String> {
+        let abs_path = self.to_sandboxed_path(path)?;
+
+        Some(
+            self.to_relative_path(abs_path)
+                .to_string_lossy()
+                .into_owned(),
+        )
+    }
+
+    /// Scans the directory with the pipeline and writes its sprites and its
+    /// `bundles.json`. The files which cannot be read are skipped or get a
+    /// placeholder thumbnail, only an unreadable directory is an error.
+    pub fn scan(
+        &self,
+        pipeline: &ScanPipeline,
+        path: impl AsRef<Path>,
+    ) -> Result<Directory, String> {
         let abs_path = self.to_absolute_path(&path);
 
-        let mut entries: Vec<_> = abs_path.read_dir().unwrap().map(Result::unwrap).collect();
+        let mut entries: Vec<_> = abs_path
+            .read_dir()
+            .map_err(|e| format!("Cannot read directory {abs_path:?}: {e}"))?
+            .filter_map(Result::ok)
+            .collect();
 
         entries.sort_by(|e1: &DirEntry, e2: &DirEntry| {
             e1.file_name().partial_cmp(&e2.file_name()).unwrap()
         });
 
-        // TODO Here we need to check if file mtime > related thumbnail file mtime
-        let images = entries
-            .par_iter()
-            .filter(|e| Directory::is_image(e))
-            .map(|entry| {
-                debug!("{entry:?}");
+        // The RAW files shot together with a JPEG are attached to the JPEG
+        // instead of being shown on their own
+        let jpeg_stems: HashSet<String> = entries
+            .iter()
+            .filter(|e| Directory::has_image_extension(&e.path()))
+            .filter_map(|e| Directory::file_stem(&e.path()))
+            .collect();
+
+        let (raw_siblings, entries): (Vec<&DirEntry>, Vec<&DirEntry>) = entries
+            .iter()
+            .filter(|e| Directory::is_media(e))
+            .partition(|e| {
+                Directory::has_raw_extension(&e.path())
+                    && Directory::file_stem(&e.path()).is_some_and(|s| jpeg_stems.contains(&s))
+            });
 
-                Image::from_path(entry)
+        let raw_siblings: HashMap<String, String> = raw_siblings
+            .iter()
+            .filter_map(|raw| {
+                let name = raw.file_name().to_string_lossy().into_owned();
+
+                Some((Directory::file_stem(&raw.path())?, name))
             })
             .collect();
 
@@ -72,52 +154,129 @@ impl ScannerContext {
             path.as_ref()
         );
 
-        Directory {
+        let mut directory = Directory {
             id: 0,
             absolute_path: abs_path,
             relative_path: path.as_ref().to_path_buf(),
             file_count: 0,
             total_size: 0,
             scanned_at: Instant::now(),
-            images,
-        }
+            images: vec![],
+        };
+
+        // TODO Here we need to check if file mtime > related thumbnail file mtime
+        let (images, thumbnails): (Vec<Image>, Vec<Thumbnail>) = pipeline
+            .run(&directory, entries, raw_siblings)
+            .into_iter()
+            .unzip();
+
+        directory.file_count = images.len() as u32;
+        directory.total_size = images.iter().map(|i| i.size).sum();
+        directory.images = images;
+        directory.save(&thumbnails);
+
+        Ok(directory)
     }
 }
 
 impl Directory {
-    pub fn is_image(entry: &DirEntry) -> bool {
-        if entry.file_type().unwrap().is_file()
-            && let Some(ext) = entry.path().extension()
-        {
+    pub fn is_media(entry: &DirEntry) -> bool {
+        entry.file_type().is_ok_and(|t| t.is_file())
+            && MediaType::from_path(&entry.path()).is_some()
+    }
+
+    /// Checks if the file is created by the scanner: the bundles.json and the
+    /// thumbnail sprites.
+    pub fn is_generated_file(name: &OsStr) -> bool {
+        name == "bundles.json" || name.to_string_lossy().starts_with("thumbs")
+    }
+
+    /// Checks if the file name has an extension which is picked up by the
+    /// scanner.
+    pub fn has_image_extension(path: &Path) -> bool {
+        if let Some(ext) = path.extension() {
             return ext.eq_ignore_ascii_case("jpg");
         }
 
         false
     }
 
-    // TODO
-    // here we also need to calculate a hash from the names of the file names
-    // and mtimes, so if someone delete a file, it should sync
-    pub fn save(&self, bundles: &Vec<ImageBundle<'_>>) {
-        let json_file = self.absolute_path.join("bundles.json");
+    /// Checks if the file is a camera RAW file with an embedded JPEG preview.
+    pub fn has_raw_extension(path: &Path) -> bool {
+        if let Some(ext) = path.extension() {
+            return ["cr2", "nef", "arw", "dng"]
+                .iter()
+                .any(|r| ext.eq_ignore_ascii_case(r));
+        }
+
+        false
+    }
 
-        for bundle in bundles {
-            bundle.create_thumbnails();
+    pub fn has_heif_extension(path: &Path) -> bool {
+        if let Some(ext) = path.extension() {
+            return ["heic", "heif"].iter().any(|h| ext.eq_ignore_ascii_case(h));
         }
 
-        let mut thumbnails = vec![];
+        false
+    }
 
-        for image in &self.images {
-            for bundle in bundles {
-                if let Some(t) = bundle.extract_metadata(&image.id) {
-                    thumbnails.push(t);
-                }
-            }
+    /// GIF and WebP images, which can be animated. The still ones are handled
+    /// like any other image.
+    pub fn has_animation_extension(path: &Path) -> bool {
+        if let Some(ext) = path.extension() {
+            return ["gif", "webp"].iter().any(|a| ext.eq_ignore_ascii_case(a));
+        }
+
+        false
+    }
+
+    pub fn has_video_extension(path: &Path) -> bool {
+        if let Some(ext) = path.extension() {
+            return ["mp4", "mov", "m4v", "webm"]
+                .iter()
+                .any(|v| ext.eq_ignore_ascii_case(v));
         }
 
-        let jf = File::create(json_file).unwrap();
-        let writer = BufWriter::new(jf);
+        false
+    }
+
+    /// The lowercase file name without the extension, which pairs the RAW
+    /// files with their JPEG siblings.
+    pub fn file_stem(path: &Path) -> Option<String> {
+        path.file_stem().map(|s| s.to_string_lossy().to_lowercase())
+    }
+
+    /// The RAW file next to the JPEG which the scanner pairs with it, the
+    /// file operations keep the two together.
+    pub fn raw_sibling(path: &Path) -> Option<PathBuf> {
+        if !Directory::has_image_extension(path) {
+            return None;
+        }
+
+        let stem = Directory::file_stem(path)?;
+
+        path.parent()?
+            .read_dir()
+            .ok()?
+            .flatten()
+            .map(|entry| entry.path())
+            .find(|p| {
+                Directory::has_raw_extension(p) && Directory::file_stem(p) == Some(stem.clone())
+            })
+    }
+
+    // TODO
+    // here we also need to calculate a hash from the names of the file names
+    // and mtimes, so if someone delete a file, it should sync
+    fn save(&self, thumbnails: &[Thumbnail]) {
+        Thumbnail::write_bundles(&self.absolute_path, thumbnails);
 
-        serde_json::to_writer_pretty(writer, &thumbnails).unwrap();
+        debug!(
+            "Directory {} saved: {} files, {} bytes, {:?} since scan",
+            self.id,
+            self.file_count,
+            self.total_size,
+            self.scanned_at.elapsed()
+        );
     }
 }
@@ -1 +1,2 @@
 pub mod directory;
+pub mod pipeline;
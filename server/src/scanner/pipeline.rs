@@ -0,0 +1,197 @@
+use std::{
+    any::Any,
+    collections::HashMap,
+    fs::DirEntry,
+    panic::{self, AssertUnwindSafe},
+    path::PathBuf,
+    sync::mpsc::{Receiver, SyncSender, sync_channel},
+    thread,
+};
+
+use log::{debug, warn};
+use rayon::{ThreadPool, ThreadPoolBuilder, prelude::*};
+
+use crate::{
+    scanner::directory::Directory,
+    thumbnail::{
+        bundle::{ImageBundle, Thumbnail},
+        image::Image,
+    },
+};
+
+/// The number of the decoded images waiting for the packer per worker.
+const QUEUE_SIZE_PER_WORKER: usize = 2;
+/// The partially filled bundles are written when there are more than this,
+/// so a directory with many different thumbnail heights doesn't keep all of
+/// them in memory.
+const MAX_OPEN_BUNDLES: usize = 16;
+
+/// Scans the directories in stages: the workers of the pool decode the
+/// images and create their thumbnails, the packer collects the thumbnails
+/// into bundles by height and a writer thread encodes the full sprites. The
+/// stages are connected by bounded channels, so only a limited number of
+/// thumbnails is in memory, however large the directory is.
+pub struct ScanPipeline {
+    pool: ThreadPool,
+    queue_size: usize,
+}
+
+/// The files written by the writer thread.
+enum Output {
+    Sprite(ImageBundle),
+    AnimatedPreview(PathBuf, Vec<u8>),
+}
+
+impl ScanPipeline {
+    /// Creates the pool of the decoding workers, 0 workers means one per CPU.
+    pub fn new(workers: usize) -> Self {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(workers)
+            .thread_name(|i| format!("scanner-{i}"))
+            .build()
+            .expect("Cannot create the scanner thread pool");
+
+        let queue_size = pool.current_num_threads() * QUEUE_SIZE_PER_WORKER;
+
+        ScanPipeline { pool, queue_size }
+    }
+
+    /// Decodes the images of the entries, writes the sprites into the
+    /// directory and returns the images with the metadata of their
+    /// thumbnails in the order of the entries. The thumbnails themselves are
+    /// dropped once they are in a sprite.
+    pub fn run(
+        &self,
+        directory: &Directory,
+        entries: Vec<&DirEntry>,
+        raw_siblings: HashMap<String, String>,
+    ) -> Vec<(Image, Thumbnail)> {
+        let (image_tx, image_rx) = sync_channel::<(usize, Image)>(self.queue_size);
+        let (output_tx, output_rx) = sync_channel::<Output>(2);
+
+        thread::scope(|scope| {
+            scope.spawn(move || write_outputs(output_rx));
+
+            scope.spawn(move || {
+                self.pool.install(|| {
+                    entries.par_iter().enumerate().for_each_with(
+                        image_tx,
+                        |image_tx, (index, entry)| {
+                            debug!("{entry:?}");
+
+                            let mut image = read_image(entry);
+
+                            if let Some(stem) = Directory::file_stem(&image.file_path) {
+                                image.raw_name = raw_siblings.get(&stem).cloned();
+                            }
+
+                            // The packer is gone only if it panicked
+                            let _ = image_tx.send((index, image));
+                        },
+                    );
+                });
+            });
+
+            pack(directory, image_rx, output_tx)
+        })
+    }
+}
+
+/// Reads the image, the files which cannot be read or make the decoders
+/// panic get a placeholder with the error.
+fn read_image(entry: &DirEntry) -> Image {
+    let path = entry.path();
+
+    let error = match panic::catch_unwind(AssertUnwindSafe(|| Image::from_path(entry))) {
+        Ok(Ok(image)) => return image,
+        Ok(Err(e)) => e,
+        Err(panic) => panic_message(panic.as_ref()),
+    };
+
+    warn!("Cannot read {path:?}: {error}");
+
+    Image::failed(&path, error)
+}
+
+pub fn panic_message(panic: &(dyn Any + Send)) -> String {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => match panic.downcast_ref::<String>() {
+            Some(message) => message.clone(),
+            None => "Unknown panic".to_owned(),
+        },
+    }
+}
+
+/// Collects the thumbnails into bundles as the images arrive, the full
+/// bundles are sent to the writer.
+fn pack(
+    directory: &Directory,
+    images: Receiver<(usize, Image)>,
+    outputs: SyncSender<Output>,
+) -> Vec<(Image, Thumbnail)> {
+    let mut open_bundles: Vec<ImageBundle> = vec![];
+    let mut next_id = 1u32;
+    let mut results = vec![];
+
+    for (index, mut image) in images {
+        if let Some(preview) = image.animated_preview.take() {
+            let path = directory.absolute_path.join(image.animated_preview_name());
+
+            let _ = outputs.send(Output::AnimatedPreview(path, preview));
+        }
+
+        let position = match open_bundles.iter().position(|b| b.height() == image.height) {
+            Some(position) => position,
+            None => {
+                if open_bundles.len() >= MAX_OPEN_BUNDLES {
+                    let _ = outputs.send(Output::Sprite(open_bundles.remove(0)));
+                }
+
+                open_bundles.push(ImageBundle::new(
+                    next_id,
+                    &directory.absolute_path,
+                    &directory.relative_path,
+                    image.height,
+                ));
+                next_id += 1;
+
+                open_bundles.len() - 1
+            }
+        };
+
+        let thumbnail = open_bundles[position].add(&mut image);
+
+        if open_bundles[position].is_full() {
+            let _ = outputs.send(Output::Sprite(open_bundles.remove(position)));
+        }
+
+        results.push((index, image, thumbnail));
+    }
+
+    for bundle in open_bundles {
+        let _ = outputs.send(Output::Sprite(bundle));
+    }
+
+    debug!("{} bundles created", next_id - 1);
+
+    results.sort_by_key(|(index, _, _)| *index);
+
+    results
+        .into_iter()
+        .map(|(_, image, thumbnail)| (image, thumbnail))
+        .collect()
+}
+
+fn write_outputs(outputs: Receiver<Output>) {
+    for output in outputs {
+        match output {
+            Output::Sprite(bundle) => bundle.write(),
+            Output::AnimatedPreview(path, preview) => {
+                if let Err(e) = std::fs::write(&path, preview) {
+                    warn!("Cannot write animated preview {path:?}: {e}");
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,300 @@
+use std::{
+    collections::HashSet,
+    fs::OpenOptions,
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use hmac::{Hmac, Mac};
+use log::info;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::config::ShareConfig;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The shortest key accepted in the secret file, in hex digits.
+const MIN_SECRET_LENGTH: usize = 32;
+
+/// Signs and checks the share links. A link carries everything about its
+/// share, the server only keeps the key and the ids of the revoked links.
+pub struct Shares {
+    secret: Vec<u8>,
+    revoked_file: PathBuf,
+    revoked: Mutex<HashSet<String>>,
+    max_hours: u64,
+}
+
+/// What a link shows, a directory as a path of the clients or an album.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ShareTarget {
+    Directory(String),
+    Album(String),
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ShareAccess {
+    #[default]
+    View,
+    /// Files can be uploaded into the shared directory too.
+    Upload,
+}
+
+/// The signed content of a link.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Share {
+    pub id: String,
+    pub target: ShareTarget,
+    /// Unix time in seconds.
+    pub expires: u64,
+    pub access: ShareAccess,
+    /// Who created the link, the uploads are done with their role.
+    pub user: Option<String>,
+    /// The MAC of the password, it can only be checked with the key, so the
+    /// password cannot be guessed from the link.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+}
+
+impl Share {
+    pub fn seconds_left(&self) -> u64 {
+        self.expires.saturating_sub(now())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ShareError {
+    Invalid,
+    Expired,
+    Revoked,
+    /// The share has a password which was not given yet.
+    Locked,
+}
+
+impl Shares {
+    pub fn new(config: &ShareConfig, cache_dir: &Path) -> Result<Self, String> {
+        let revoked_file = config.revoked_file(cache_dir);
+
+        Ok(Shares {
+            secret: load_secret(&config.secret_file(cache_dir))?,
+            revoked: Mutex::new(read_revoked(&revoked_file)?),
+            revoked_file,
+            max_hours: config.max_hours,
+        })
+    }
+
+    pub fn max_hours(&self) -> u64 {
+        self.max_hours
+    }
+
+    /// Creates a share valid for the given hours, returns it with the token
+    /// of its link.
+    pub fn create(
+        &self,
+        target: ShareTarget,
+        hours: u64,
+        access: ShareAccess,
+        user: Option<String>,
+        password: Option<&str>,
+    ) -> (Share, String) {
+        let mut id = [0u8; 12];
+
+        OsRng.fill_bytes(&mut id);
+
+        let id: String = id.iter().map(|b| format!("{b:02x}")).collect();
+        let password = password.map(|p| self.encode_mac("password", &[&id, p]));
+        let share = Share {
+            id,
+            target,
+            expires: now() + hours * 60 * 60,
+            access,
+            user,
+            password,
+        };
+
+        let payload = serde_json::to_vec(&share).expect("Cannot serialize the share");
+        let signature = self.mac("link", &[&payload]).finalize().into_bytes();
+        let token = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(&payload),
+            URL_SAFE_NO_PAD.encode(signature)
+        );
+
+        (share, token)
+    }
+
+    /// The share of a token if its signature is right and it is still valid.
+    pub fn open(&self, token: &str) -> Result<Share, ShareError> {
+        let (payload, signature) = token.split_once('.').ok_or(ShareError::Invalid)?;
+        let (Ok(payload), Ok(signature)) = (
+            URL_SAFE_NO_PAD.decode(payload),
+            URL_SAFE_NO_PAD.decode(signature),
+        ) else {
+            return Err(ShareError::Invalid);
+        };
+
+        self.mac("link", &[&payload])
+            .verify_slice(&signature)
+            .map_err(|_| ShareError::Invalid)?;
+
+        let share: Share = serde_json::from_slice(&payload).map_err(|_| ShareError::Invalid)?;
+
+        if share.expires <= now() {
+            return Err(ShareError::Expired);
+        }
+
+        if self.revoked.lock().unwrap().contains(&share.id) {
+            return Err(ShareError::Revoked);
+        }
+
+        Ok(share)
+    }
+
+    pub fn check_password(&self, share: &Share, password: &str) -> bool {
+        share
+            .password
+            .as_ref()
+            .is_none_or(|expected| self.verify_mac("password", &[&share.id, password], expected))
+    }
+
+    /// The cookie value proving that the password of the share was given.
+    pub fn unlock_token(&self, share: &Share) -> String {
+        self.encode_mac("unlock", &[&share.id])
+    }
+
+    /// True if the share has no password or the cookie value is its unlock
+    /// token.
+    pub fn is_unlocked(&self, share: &Share, token: Option<&str>) -> bool {
+        share.password.is_none()
+            || token.is_some_and(|t| self.verify_mac("unlock", &[&share.id], t))
+    }
+
+    /// Adds the id to the revoked links, its link stops working at once.
+    pub fn revoke(&self, id: &str) -> Result<(), String> {
+        let mut revoked = self.revoked.lock().unwrap();
+
+        if revoked.contains(id) {
+            return Ok(());
+        }
+
+        let path = &self.revoked_file;
+
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| writeln!(file, "{id}"))
+            .map_err(|e| format!("Cannot write {path:?}: {e}"))?;
+
+        revoked.insert(id.to_owned());
+
+        info!("Share {id} revoked");
+
+        Ok(())
+    }
+
+    /// The MAC of the parts, the purpose keeps the MACs of the links, the
+    /// passwords and the cookies apart.
+    fn mac(&self, purpose: &str, parts: &[&[u8]]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC takes any key size");
+
+        mac.update(purpose.as_bytes());
+
+        for part in parts {
+            mac.update(&[0]);
+            mac.update(part);
+        }
+
+        mac
+    }
+
+    fn encode_mac(&self, purpose: &str, parts: &[&str]) -> String {
+        let parts: Vec<&[u8]> = parts.iter().map(|p| p.as_bytes()).collect();
+
+        URL_SAFE_NO_PAD.encode(self.mac(purpose, &parts).finalize().into_bytes())
+    }
+
+    /// Compares in constant time, so the MAC cannot be found byte by byte.
+    fn verify_mac(&self, purpose: &str, parts: &[&str], expected: &str) -> bool {
+        let parts: Vec<&[u8]> = parts.iter().map(|p| p.as_bytes()).collect();
+
+        URL_SAFE_NO_PAD
+            .decode(expected)
+            .is_ok_and(|expected| self.mac(purpose, &parts).verify_slice(&expected).is_ok())
+    }
+}
+
+/// Checks the share settings, the key and the revoked links are only read
+/// by the server.
+pub fn check_config(config: &ShareConfig, cache_dir: &Path, serving: bool) -> Vec<String> {
+    let mut errors = vec![];
+
+    if config.max_hours == 0 {
+        errors.push("shares.max_hours must be greater than 0".to_owned());
+    }
+
+    if serving {
+        errors.extend(load_secret(&config.secret_file(cache_dir)).err());
+        errors.extend(read_revoked(&config.revoked_file(cache_dir)).err());
+    }
+
+    errors
+}
+
+/// The key in the secret file, a random one is written into it if the file
+/// does not exist yet.
+fn load_secret(path: &Path) -> Result<Vec<u8>, String> {
+    match std::fs::read_to_string(path) {
+        Ok(content) if content.trim().len() < MIN_SECRET_LENGTH => Err(format!(
+            "The share secret in {path:?} is shorter than {MIN_SECRET_LENGTH} characters"
+        )),
+        Ok(content) => Ok(content.trim().as_bytes().to_vec()),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let mut secret = [0u8; 32];
+
+            OsRng.fill_bytes(&mut secret);
+
+            let secret: String = secret.iter().map(|b| format!("{b:02x}")).collect();
+
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)
+                    .map_err(|e| format!("Cannot create {parent:?}: {e}"))?;
+            }
+
+            std::fs::write(path, format!("{secret}\n"))
+                .map_err(|e| format!("Cannot write {path:?}: {e}"))?;
+
+            info!("Generated the share secret in {path:?}");
+
+            Ok(secret.into_bytes())
+        }
+        Err(e) => Err(format!("Cannot read {path:?}: {e}")),
+    }
+}
+
+/// The revoked ids, a missing file has none.
+fn read_revoked(path: &Path) -> Result<HashSet<String>, String> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => Ok(content
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .map(str::to_owned)
+            .collect()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(HashSet::new()),
+        Err(e) => Err(format!("Cannot read {path:?}: {e}")),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
@@ -0,0 +1,145 @@
+use std::{fs::File, io::BufReader, path::Path, time::Duration};
+
+use image::{
+    AnimationDecoder, Delay, DynamicImage, Frame, Frames,
+    codecs::{
+        gif::{GifDecoder, GifEncoder, Repeat},
+        webp::WebPDecoder,
+    },
+};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+/// The animated preview covers the beginning of the animation.
+const PREVIEW_DURATION: Duration = Duration::from_secs(3);
+const PREVIEW_MAX_FRAMES: usize = 45;
+/// The browsers show the frames with a shorter delay than this for 100ms.
+const MIN_FRAME_DELAY: Duration = Duration::from_millis(20);
+
+/// The frame count and the length of an animated GIF or WebP.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct AnimationInfo {
+    pub frames: u32,
+    /// The length of one loop in seconds.
+    pub duration: f64,
+}
+
+/// The result of decoding an animation: its info, the frame used for the
+/// thumbnail and a short animated GIF of thumbnail size.
+pub struct Animation {
+    pub info: AnimationInfo,
+    pub frame: DynamicImage,
+    pub preview: Option<Vec<u8>>,
+}
+
+impl Animation {
+    /// Decodes all the frames of a GIF or WebP file, `None` if the image is
+    /// not animated.
+    pub fn from_path(path: &Path) -> Option<Animation> {
+        let reader = BufReader::new(File::open(path).ok()?);
+        let ext = path.extension()?.to_ascii_lowercase();
+
+        let frames = match ext.to_str()? {
+            "gif" => GifDecoder::new(reader).ok()?.into_frames(),
+            "webp" => {
+                let decoder = WebPDecoder::new(reader).ok()?;
+
+                if !decoder.has_animation() {
+                    return None;
+                }
+
+                decoder.into_frames()
+            }
+            _ => return None,
+        };
+
+        let animation = Animation::from_frames(frames)?;
+
+        debug!(
+            "Animation {path:?}: {} frames, {:.2}s",
+            animation.info.frames, animation.info.duration
+        );
+
+        Some(animation)
+    }
+
+    /// The frames are decoded one by one, only the best frame so far and the
+    /// small preview frames are kept in memory. The frame with the most
+    /// contrast is picked for the thumbnail, which skips the blank frames of
+    /// the fade ins.
+    fn from_frames(frames: Frames<'_>) -> Option<Animation> {
+        let mut count = 0u32;
+        let mut duration = Duration::ZERO;
+        let mut best: Option<(f64, DynamicImage)> = None;
+        let mut preview_frames = vec![];
+
+        for frame in frames {
+            let Ok(frame) = frame else {
+                break;
+            };
+
+            let delay = Duration::from(frame.delay());
+            let delay = if delay < MIN_FRAME_DELAY {
+                Duration::from_millis(100)
+            } else {
+                delay
+            };
+
+            let img = DynamicImage::ImageRgba8(frame.into_buffer());
+            let small = img.thumbnail(256, 256);
+            let score = contrast(&small);
+
+            if duration < PREVIEW_DURATION && preview_frames.len() < PREVIEW_MAX_FRAMES {
+                preview_frames.push(Frame::from_parts(
+                    small.to_rgba8(),
+                    0,
+                    0,
+                    Delay::from_saturating_duration(delay),
+                ));
+            }
+
+            if best.as_ref().is_none_or(|(best_score, _)| score > *best_score) {
+                best = Some((score, img));
+            }
+
+            count += 1;
+            duration += delay;
+        }
+
+        // A single frame is a still image
+        if count < 2 {
+            return None;
+        }
+
+        Some(Animation {
+            info: AnimationInfo {
+                frames: count,
+                duration: duration.as_secs_f64(),
+            },
+            frame: best?.1,
+            preview: encode_preview(preview_frames),
+        })
+    }
+}
+
+fn encode_preview(frames: Vec<Frame>) -> Option<Vec<u8>> {
+    let mut gif = vec![];
+
+    {
+        let mut encoder = GifEncoder::new_with_speed(&mut gif, 20);
+
+        encoder.set_repeat(Repeat::Infinite).ok()?;
+        encoder.encode_frames(frames).ok()?;
+    }
+
+    Some(gif)
+}
+
+/// The standard deviation of the luma values.
+fn contrast(img: &DynamicImage) -> f64 {
+    let luma = img.to_luma8();
+    let count = luma.len().max(1) as f64;
+    let mean = luma.iter().map(|v| *v as f64).sum::<f64>() / count;
+
+    (luma.iter().map(|v| (*v as f64 - mean).powi(2)).sum::<f64>() / count).sqrt()
+}
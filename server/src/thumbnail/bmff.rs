@@ -0,0 +1,152 @@
+use std::io::Read;
+
+/// The header of a box of the ISO base media file format, which is the
+/// container of the MP4 videos and the HEIF images.
+pub(super) struct BoxHeader {
+    pub(super) kind: [u8; 4],
+    /// The size of the content without the header, `None` if it lasts until
+    /// the end of the file.
+    pub(super) size: Option<u64>,
+}
+
+pub(super) fn read_box_header(reader: &mut impl Read) -> Option<BoxHeader> {
+    let mut header = [0u8; 8];
+    reader.read_exact(&mut header).ok()?;
+
+    let size = u32::from_be_bytes(header[..4].try_into().unwrap()) as u64;
+    let kind = header[4..].try_into().unwrap();
+
+    let size = match size {
+        0 => None,
+        1 => {
+            let mut large = [0u8; 8];
+            reader.read_exact(&mut large).ok()?;
+
+            Some(u64::from_be_bytes(large).checked_sub(16)?)
+        }
+        size => Some(size.checked_sub(8)?),
+    };
+
+    Some(BoxHeader { kind, size })
+}
+
+/// Iterates over the boxes in a buffer.
+pub(super) fn boxes(mut data: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    std::iter::from_fn(move || {
+        let header = read_box_header(&mut data)?;
+        let size = (header.size.unwrap_or(data.len() as u64) as usize).min(data.len());
+        let (content, rest) = data.split_at(size);
+
+        data = rest;
+
+        Some((header.kind, content))
+    })
+}
+
+pub(super) fn find_box<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> Option<&'a [u8]> {
+    let (first, rest) = path.split_first()?;
+    let content = boxes(data).find(|(kind, _)| kind == *first)?.1;
+
+    if rest.is_empty() {
+        Some(content)
+    } else {
+        find_box(content, rest)
+    }
+}
+
+pub(super) fn be_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        data.get(offset..offset.checked_add(4)?)?.try_into().ok()?,
+    ))
+}
+
+pub(super) fn be_u64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(
+        data.get(offset..offset.checked_add(8)?)?.try_into().ok()?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn boxed(kind: &[u8; 4], content: &[u8]) -> Vec<u8> {
+        [&(content.len() as u32 + 8).to_be_bytes(), kind, content].concat()
+    }
+
+    #[test]
+    fn headers_with_64_bit_sizes() {
+        let header = read_box_header(&mut &boxed(b"ftyp", b"heic")[..]).unwrap();
+
+        assert_eq!(&header.kind, b"ftyp");
+        assert_eq!(header.size, Some(4));
+
+        let large = [&1u32.to_be_bytes(), b"mdat", &(16u64 + 5).to_be_bytes()[..]].concat();
+        let header = read_box_header(&mut &large[..]).unwrap();
+
+        assert_eq!(&header.kind, b"mdat");
+        assert_eq!(header.size, Some(5));
+
+        // Until the end of the file
+        let header = read_box_header(&mut &[0, 0, 0, 0, b'm', b'd', b'a', b't'][..]).unwrap();
+
+        assert_eq!(header.size, None);
+    }
+
+    #[test]
+    fn broken_headers() {
+        // Shorter than the header itself
+        assert!(read_box_header(&mut &[0, 0, 0, 4, b'f', b'r', b'e', b'e'][..]).is_none());
+
+        let large = [&1u32.to_be_bytes(), b"mdat", &8u64.to_be_bytes()[..]].concat();
+
+        assert!(read_box_header(&mut &large[..]).is_none());
+        // Truncated 64-bit size and truncated header
+        assert!(read_box_header(&mut &large[..12]).is_none());
+        assert!(read_box_header(&mut &b"\0\0\0\x10mo"[..]).is_none());
+    }
+
+    #[test]
+    fn truncated_boxes_end_with_the_data() {
+        let mut data = [boxed(b"ftyp", b"heic"), boxed(b"meta", &[1; 8])].concat();
+
+        // The meta box claims 4 bytes more than there are
+        data.truncate(data.len() - 4);
+
+        let found: Vec<_> = boxes(&data).collect();
+
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[1], (*b"meta", &[1u8; 4][..]));
+
+        // A header cut in half ends the iteration
+        data.extend(b"\0\0");
+
+        assert_eq!(boxes(&data).count(), 2);
+    }
+
+    #[test]
+    fn nested_boxes_are_found() {
+        let data = [
+            boxed(b"free", b""),
+            boxed(b"moov", &boxed(b"trak", &boxed(b"tkhd", b"ok"))),
+        ]
+        .concat();
+
+        assert_eq!(
+            find_box(&data, &[b"moov", b"trak", b"tkhd"]),
+            Some(&b"ok"[..])
+        );
+        assert_eq!(find_box(&data, &[b"moov", b"mdia"]), None);
+    }
+
+    #[test]
+    fn numbers_past_the_end() {
+        let data = [0, 0, 0, 1, 0, 0, 0, 2];
+
+        assert_eq!(be_u32(&data, 4), Some(2));
+        assert_eq!(be_u32(&data, 5), None);
+        assert_eq!(be_u64(&data, 0), Some(0x1_0000_0002));
+        assert_eq!(be_u64(&data, 1), None);
+        assert_eq!(be_u32(&data, usize::MAX - 1), None);
+    }
+}
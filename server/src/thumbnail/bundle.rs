@@ -1,21 +1,42 @@
-use std::{ffi::OsString, path::PathBuf, time::Instant};
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    time::Instant,
+};
 
-use image::RgbImage;
-use log::debug;
+use image::{RgbImage, imageops};
+use log::{debug, error};
 use serde::{Deserialize, Serialize};
 
-use crate::{scanner::directory::Directory, thumbnail::image::Image};
+use crate::{
+    catalog::metadata::ImageMeta,
+    scanner::directory::MediaType,
+    thumbnail::{
+        animation::AnimationInfo,
+        image::Image,
+        quality::{QualityIssue, QualityScores},
+        video::VideoInfo,
+    },
+};
 
-pub struct ImageBundle<'dir> {
+/// The number of thumbnails in a sprite.
+const MAX_BUNDLE_SIZE: usize = 8;
+
+/// The thumbnails of images with the same height, which are written side by
+/// side into one sprite.
+pub struct ImageBundle {
     id: u32,
     absolute_path: PathBuf,
-    relative_path: PathBuf,
+    relative_base_path: String,
     file_name: String,
     height: u32,
-    images: Vec<&'dir Image>,
+    /// The width of the thumbnails added so far.
+    width: u32,
+    thumbnails: Vec<RgbImage>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Thumbnail {
     pub relative_base_path: String,
     absolute_base_path: String,
@@ -25,111 +46,213 @@ pub struct Thumbnail {
     height: u32,
     pub original_name: String,
     file_size: u32,
+    #[serde(default)]
+    pub media_type: MediaType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub video: Option<VideoInfo>,
+    /// The RAW file next to the JPEG, the two files are shown as one item.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub animation: Option<AnimationInfo>,
+    /// The short animated GIF the gallery plays on hover.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    animated_preview: Option<String>,
+    /// Why the file could not be read, the thumbnail is a placeholder then.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// A placeholder color and a BlurHash of the image, which the gallery
+    /// shows until the sprite is loaded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dominant_color: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    blurhash: Option<String>,
+    /// The user metadata from the catalog, it is only filled in when the
+    /// bundles are served.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<ImageMeta>,
+    /// The burst the image belongs to in the directory, consecutive images
+    /// which look nearly the same. It is only filled in when served.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<u32>,
+    /// The quality scores from the search index with the issues found by
+    /// them, only filled in when served.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quality: Option<QualityScores>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub issues: Vec<QualityIssue>,
 }
 
-impl<'dir> ImageBundle<'dir> {
-    pub fn from_directory(dir: &'dir Directory) -> Vec<ImageBundle<'dir>> {
-        let mut bundles: Vec<ImageBundle> = vec![];
-        let mut id = 1u32;
+impl Thumbnail {
+    /// Reads the `bundles.json` of a directory, `None` if the directory is not
+    /// indexed yet or its `bundles.json` is broken and needs a new scan.
+    pub fn read_bundles(dir: &Path) -> Option<Vec<Thumbnail>> {
+        let path = dir.join("bundles.json");
+        let content = std::fs::read_to_string(&path).ok()?;
 
-        'outer: for image in &dir.images {
-            println!(
-                "Handling image {:?} {}x{}",
-                image.file_path.file_name(),
-                image.width,
-                image.height
-            );
+        serde_json::from_str(&content)
+            .inspect_err(|e| error!("Cannot parse {path:?}: {e}"))
+            .ok()
+    }
 
-            for bundle in bundles.iter_mut() {
-                if bundle.height == image.height && bundle.images.len() < 8 {
-                    debug!("  Bundle found with height {}", bundle.height);
+    /// The bundles.json has the paths relative to the root of the library,
+    /// the clients get them with the name of the library in front.
+    pub fn in_library(&mut self, library: &str) {
+        self.relative_base_path = format!("{library}/{}", self.relative_base_path);
+    }
 
-                    bundle.images.push(image);
+    /// The files of the thumbnail written by the scanner next to the
+    /// original: the sprite and the animated preview.
+    pub fn generated_files(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.thumbnail_name.as_str()).chain(self.animated_preview.as_deref())
+    }
 
-                    continue 'outer;
-                }
-            }
+    pub fn write_bundles(dir: &Path, thumbnails: &[Thumbnail]) {
+        let jf = File::create(dir.join("bundles.json")).unwrap();
+        let writer = BufWriter::new(jf);
 
-            let bundle = ImageBundle {
-                id,
-                absolute_path: dir.absolute_path.clone(),
-                relative_path: dir.relative_path.clone(),
-                file_name: format!("thumbs_{id}.jpg"),
-                height: image.height,
-                images: Vec::from(&[image]),
-            };
+        serde_json::to_writer_pretty(writer, thumbnails).unwrap();
+    }
+}
 
-            id += 1;
+impl ImageBundle {
+    pub fn new(id: u32, absolute_path: &Path, relative_path: &Path, height: u32) -> Self {
+        // The gallery concatenates the base path and the file name, so the
+        // base path always ends with a separator
+        let mut relative_base_path = relative_path.to_str().unwrap().to_owned();
 
-            debug!("  Bundle created with the image");
+        if !relative_base_path.is_empty() && !relative_base_path.ends_with('/') {
+            relative_base_path.push('/');
+        }
 
-            bundles.push(bundle);
+        ImageBundle {
+            id,
+            absolute_path: absolute_path.to_path_buf(),
+            relative_base_path,
+            file_name: format!("thumbs_{id}.jpg"),
+            height,
+            width: 0,
+            thumbnails: vec![],
         }
+    }
 
-        bundles
+    pub fn height(&self) -> u32 {
+        self.height
     }
 
-    pub fn create_thumbnails(&self) {
-        let total_width = self
-            .images
-            .iter()
-            .map(|i| i.thumbnail.width())
-            .reduce(|acc, e| acc + e)
-            .unwrap();
-        let height = self.images.first().unwrap().thumbnail.height();
+    pub fn is_full(&self) -> bool {
+        self.thumbnails.len() >= MAX_BUNDLE_SIZE
+    }
 
-        let start = Instant::now();
+    /// Adds the thumbnail of the image to the sprite and returns the metadata
+    /// of the image. The thumbnail is taken from the image, so it is only
+    /// kept in memory until the sprite is written.
+    pub fn add(&mut self, image: &mut Image) -> Thumbnail {
+        let thumbnail = Thumbnail {
+            absolute_base_path: self.absolute_path.to_str().unwrap().to_owned(),
+            relative_base_path: self.relative_base_path.clone(),
+            thumbnail_name: self.file_name.clone(),
+            position_x: self.width,
+            width: image.width,
+            height: self.height,
+            original_name: image
+                .file_path
+                .file_name()
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_owned(),
+            file_size: image.size as u32,
+            media_type: image.media_type,
+            video: image.video.clone(),
+            raw_name: image.raw_name.clone(),
+            animation: image.animation.clone(),
+            animated_preview: image
+                .animated_preview
+                .as_ref()
+                .map(|_| image.animated_preview_name()),
+            dominant_color: Some(image.dominant_color.clone()),
+            blurhash: image.blurhash.clone(),
+            error: image.error.clone(),
+            meta: None,
+            group: None,
+            quality: None,
+            issues: vec![],
+        };
+
+        debug!(
+            "  Image {:?} added to bundle {} at {}",
+            image.id, self.id, self.width
+        );
 
-        let mut thumbs = RgbImage::new(total_width, height);
+        self.width += image.width;
+        self.thumbnails.push(std::mem::take(&mut image.thumbnail));
 
-        let mut x_offset = 0u32;
+        thumbnail
+    }
 
-        for image in &self.images {
-            for y in 0..image.thumbnail.height() {
-                for x in 0..image.thumbnail.width() {
-                    thumbs.put_pixel(x + x_offset, y, *image.thumbnail.get_pixel(x, y));
-                }
-            }
+    /// Writes the thumbnails side by side into the sprite.
+    pub fn write(self) {
+        let start = Instant::now();
+        let mut sprite = RgbImage::new(self.width.max(1), self.height);
+        let mut x_offset = 0i64;
 
-            debug!("  thumb {:?}", start.elapsed());
+        for thumbnail in &self.thumbnails {
+            imageops::replace(&mut sprite, thumbnail, x_offset, 0);
 
-            x_offset += image.thumbnail.width();
+            x_offset += thumbnail.width() as i64;
         }
 
         let file_path = self.absolute_path.join(&self.file_name);
-        thumbs.save(&file_path).unwrap();
 
-        debug!("Saved {:?}", start.elapsed());
+        if let Err(e) = sprite.save(&file_path) {
+            error!("Cannot write the sprite {file_path:?}: {e}");
+        }
+
+        debug!("Saved bundle {} {:?}", self.id, start.elapsed());
     }
+}
 
-    pub fn extract_metadata(&self, id: &OsString) -> Option<Thumbnail> {
-        let mut offset_x = 0u32;
-
-        for (i, image) in self.images.iter().enumerate() {
-            if i != 0 {
-                offset_x += image.width;
-            }
-
-            if image.id == *id {
-                return Some(Thumbnail {
-                    absolute_base_path: self.absolute_path.to_str().unwrap().to_owned(),
-                    relative_base_path: self.relative_path.to_str().unwrap().to_owned(),
-                    thumbnail_name: self.file_name.clone(),
-                    position_x: offset_x,
-                    width: image.width,
-                    height: self.height,
-                    original_name: image
-                        .file_path
-                        .file_name()
-                        .unwrap()
-                        .to_str()
-                        .unwrap()
-                        .to_owned(),
-                    file_size: image.size as u32,
-                });
-            }
-        }
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        AppState,
+        api::{
+            SyncCommand,
+            test_helpers::{TempDir, get},
+        },
+        config::Config,
+    };
+
+    #[tokio::test]
+    async fn broken_bundles_are_synced_again() {
+        let dir = TempDir::new("broken-bundles");
+
+        dir.image("a/one.jpg");
+        std::fs::write(dir.join("a/bundles.json"), "[{\"original_name\":").unwrap();
+
+        assert!(Thumbnail::read_bundles(&dir.join("a")).is_none());
+
+        let config = Config {
+            root_directory: dir.path().to_string_lossy().into_owned(),
+            ..Config::default()
+        };
+        let (state, mut commands) = AppState::new(config).unwrap();
+        let app = crate::app(Arc::new(state));
+        let response = app
+            .oneshot(get("/serve/main/a/bundles.json", None))
+            .await
+            .unwrap();
 
-        None
+        assert_eq!(response.status(), 404);
+        assert!(matches!(
+            commands.try_recv(),
+            Ok(SyncCommand::SyncDirectory(library, dir)) if library == "main" && dir == "a"
+        ));
     }
 }
@@ -0,0 +1,95 @@
+use std::{fs::File, io::BufReader, path::Path};
+
+use exif::{DateTime, Field, In, Reader, Tag, Value};
+use serde::{Deserialize, Serialize};
+
+/// How and when the image was taken, read from the EXIF data.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct CaptureInfo {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub camera: Option<String>,
+    /// The original date time as `YYYY-MM-DDTHH:MM:SS`, so it can be compared
+    /// as a string.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub taken_at: Option<String>,
+}
+
+impl CaptureInfo {
+    pub fn from_path(path: &Path) -> Option<CaptureInfo> {
+        let mut reader = BufReader::new(File::open(path).ok()?);
+        let exif = Reader::new().read_from_container(&mut reader).ok()?;
+
+        let make = exif.get_field(Tag::Make, In::PRIMARY).and_then(ascii_value);
+        let model = exif
+            .get_field(Tag::Model, In::PRIMARY)
+            .and_then(ascii_value);
+
+        // The model usually contains the make already
+        let camera = match (make, model) {
+            (Some(make), Some(model)) if !model.starts_with(&make) => {
+                Some(format!("{make} {model}"))
+            }
+            (_, Some(model)) => Some(model),
+            (make, None) => make,
+        };
+
+        let taken_at = [Tag::DateTimeOriginal, Tag::DateTime]
+            .into_iter()
+            .filter_map(|tag| exif.get_field(tag, In::PRIMARY))
+            .find_map(date_value);
+
+        Some(CaptureInfo { camera, taken_at })
+    }
+}
+
+fn ascii_value(field: &Field) -> Option<String> {
+    match &field.value {
+        Value::Ascii(values) => {
+            let value = String::from_utf8_lossy(values.first()?).trim().to_owned();
+
+            (!value.is_empty()).then_some(value)
+        }
+        _ => None,
+    }
+}
+
+fn date_value(field: &Field) -> Option<String> {
+    match &field.value {
+        Value::Ascii(values) => {
+            let dt = DateTime::from_ascii(values.first()?).ok()?;
+
+            Some(format!(
+                "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+                dt.year, dt.month, dt.day, dt.hour, dt.minute, dt.second
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Formats seconds since the Unix epoch as `YYYY-MM-DDTHH:MM:SS` in UTC, the
+/// same format as the EXIF dates.
+pub fn format_timestamp(seconds: i64) -> String {
+    let days = seconds.div_euclid(86400);
+    let time = seconds.rem_euclid(86400);
+
+    // Converts the days to a civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}",
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
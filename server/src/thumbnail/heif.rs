@@ -0,0 +1,581 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, Cursor, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use image::{DynamicImage, ImageFormat, codecs::jpeg::JpegEncoder};
+use log::debug;
+
+use crate::thumbnail::{
+    bmff::{be_u32, boxes, find_box, read_box_header},
+    raw::RawPreview,
+};
+
+/// The `meta` box and the items larger than this are not read.
+const MAX_ITEM_SIZE: u64 = 64 * 1024 * 1024;
+
+/// The structure of a HEIF file, read from its `meta` box. The image data is
+/// only read when an item is requested.
+pub struct HeifFile {
+    path: PathBuf,
+    primary: u32,
+    /// The item ids with their types, like `hvc1`, `jpeg` or `Exif`.
+    items: Vec<(u32, [u8; 4])>,
+    locations: HashMap<u32, Location>,
+    /// The references between the items as type, from and to ids.
+    references: Vec<([u8; 4], u32, Vec<u32>)>,
+    properties: Vec<([u8; 4], Vec<u8>)>,
+    /// The 1-based indices of the properties of the items.
+    associations: HashMap<u32, Vec<usize>>,
+    idat: Vec<u8>,
+}
+
+struct Location {
+    /// 0 for the offsets in the file, 1 for the offsets in the `idat` box.
+    construction_method: u8,
+    extents: Vec<(u64, u64)>,
+}
+
+impl HeifFile {
+    pub fn from_path(path: &Path) -> Option<HeifFile> {
+        let mut reader = BufReader::new(File::open(path).ok()?);
+
+        let meta = loop {
+            let header = read_box_header(&mut reader)?;
+            let size = header.size?;
+
+            if &header.kind == b"meta" && size <= MAX_ITEM_SIZE {
+                let mut meta = vec![0u8; size as usize];
+                reader.read_exact(&mut meta).ok()?;
+
+                break meta;
+            }
+
+            reader.seek(SeekFrom::Current(size as i64)).ok()?;
+        };
+
+        // The meta box is a full box, the children start after the version
+        let meta = meta.get(4..)?;
+
+        let pitm = find_box(meta, &[b"pitm"])?;
+        let primary = if pitm.first()? == &0 {
+            read_be(pitm, &mut 4, 2)?
+        } else {
+            read_be(pitm, &mut 4, 4)?
+        } as u32;
+
+        let mut heif = HeifFile {
+            path: path.to_path_buf(),
+            primary,
+            items: find_box(meta, &[b"iinf"])
+                .map(parse_iinf)
+                .unwrap_or_default(),
+            locations: find_box(meta, &[b"iloc"])
+                .and_then(parse_iloc)
+                .unwrap_or_default(),
+            references: find_box(meta, &[b"iref"])
+                .map(parse_iref)
+                .unwrap_or_default(),
+            properties: vec![],
+            associations: HashMap::new(),
+            idat: find_box(meta, &[b"idat"]).unwrap_or_default().to_vec(),
+        };
+
+        if let Some(iprp) = find_box(meta, &[b"iprp"]) {
+            if let Some(ipco) = find_box(iprp, &[b"ipco"]) {
+                heif.properties = boxes(ipco)
+                    .map(|(kind, content)| (kind, content.to_vec()))
+                    .collect();
+            }
+
+            for (kind, ipma) in boxes(iprp) {
+                if &kind == b"ipma" {
+                    parse_ipma(ipma, &mut heif.associations);
+                }
+            }
+        }
+
+        debug!(
+            "HEIF {path:?}: primary item {}, {} items",
+            heif.primary,
+            heif.items.len()
+        );
+
+        Some(heif)
+    }
+
+    /// The dimensions of the primary image after the rotation.
+    pub fn dimensions(&self) -> Option<(u32, u32)> {
+        let ispe = self.property(self.primary, b"ispe")?;
+        let (width, height) = (be_u32(ispe, 4)?, be_u32(ispe, 8)?);
+
+        Some(if self.rotation() % 2 == 1 {
+            (height, width)
+        } else {
+            (width, height)
+        })
+    }
+
+    /// Decodes the best preview which doesn't need an HEVC decoder: the
+    /// primary image or a thumbnail coded as JPEG, or the thumbnail in the
+    /// EXIF data.
+    pub fn preview(&self) -> Option<DynamicImage> {
+        let mut jpegs: Vec<u32> = self
+            .references
+            .iter()
+            .filter(|(kind, _, to)| kind == b"thmb" && to.contains(&self.primary))
+            .map(|(_, from, _)| *from)
+            .filter(|id| self.item_type(*id) == Some(*b"jpeg"))
+            .collect();
+
+        // The largest thumbnail first
+        jpegs.sort_by_key(|id| {
+            std::cmp::Reverse(
+                self.property(*id, b"ispe")
+                    .and_then(|ispe| Some(be_u32(ispe, 4)? as u64 * be_u32(ispe, 8)? as u64))
+                    .unwrap_or(0),
+            )
+        });
+
+        if self.item_type(self.primary) == Some(*b"jpeg") {
+            jpegs.insert(0, self.primary);
+        }
+
+        let img = jpegs
+            .into_iter()
+            .filter_map(|id| self.item_data(id))
+            .find_map(|data| image::load_from_memory_with_format(&data, ImageFormat::Jpeg).ok())
+            .or_else(|| {
+                let exif = self.exif()?;
+                let jpeg = RawPreview::from_tiff(Cursor::new(exif))?.jpeg;
+
+                image::load_from_memory_with_format(&jpeg, ImageFormat::Jpeg).ok()
+            })?;
+
+        // The rotation is counter-clockwise
+        Some(match self.rotation() {
+            1 => img.rotate270(),
+            2 => img.rotate180(),
+            3 => img.rotate90(),
+            _ => img,
+        })
+    }
+
+    /// The TIFF structured EXIF data describing the primary image.
+    pub fn exif(&self) -> Option<Vec<u8>> {
+        let id = self
+            .references
+            .iter()
+            .filter(|(kind, _, to)| kind == b"cdsc" && to.contains(&self.primary))
+            .map(|(_, from, _)| *from)
+            .find(|id| self.item_type(*id) == Some(*b"Exif"))?;
+
+        let data = self.item_data(id)?;
+
+        // The data starts with the offset of the TIFF header
+        let offset = be_u32(&data, 0)? as usize;
+
+        data.get(4 + offset..).map(<[u8]>::to_vec)
+    }
+
+    fn item_type(&self, id: u32) -> Option<[u8; 4]> {
+        self.items.iter().find(|(i, _)| *i == id).map(|(_, t)| *t)
+    }
+
+    fn property(&self, id: u32, kind: &[u8; 4]) -> Option<&[u8]> {
+        self.associations
+            .get(&id)?
+            .iter()
+            .filter_map(|index| self.properties.get(index.checked_sub(1)?))
+            .find(|(k, _)| k == kind)
+            .map(|(_, content)| content.as_slice())
+    }
+
+    /// The rotation of the primary image in quarter turns.
+    fn rotation(&self) -> u8 {
+        self.property(self.primary, b"irot")
+            .and_then(|irot| irot.first())
+            .map_or(0, |angle| angle & 3)
+    }
+
+    fn item_data(&self, id: u32) -> Option<Vec<u8>> {
+        let location = self.locations.get(&id)?;
+        let total = location
+            .extents
+            .iter()
+            .fold(0u64, |total, (_, length)| total.saturating_add(*length));
+
+        if total > MAX_ITEM_SIZE {
+            return None;
+        }
+
+        let mut data = Vec::with_capacity(total as usize);
+
+        match location.construction_method {
+            0 => {
+                let mut file = File::open(&self.path).ok()?;
+
+                for (offset, length) in &location.extents {
+                    file.seek(SeekFrom::Start(*offset)).ok()?;
+                    file.by_ref().take(*length).read_to_end(&mut data).ok()?;
+                }
+            }
+            1 => {
+                for (offset, length) in &location.extents {
+                    let end = usize::try_from(offset.checked_add(*length)?).ok()?;
+
+                    data.extend_from_slice(self.idat.get(*offset as usize..end)?);
+                }
+            }
+            _ => return None,
+        }
+
+        Some(data)
+    }
+}
+
+/// Decodes the full HEIF image with libheif.
+#[cfg(feature = "heif")]
+pub fn decode(path: &Path) -> Option<DynamicImage> {
+    use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+    let context = HeifContext::read_from_file(path.to_str()?).ok()?;
+    let handle = context.primary_image_handle().ok()?;
+    let image = LibHeif::new()
+        .decode(&handle, ColorSpace::Rgb(RgbChroma::Rgb), None)
+        .ok()?;
+
+    let plane = image.planes().interleaved?;
+    let row_size = plane.width as usize * 3;
+    let pixels = plane
+        .data
+        .chunks(plane.stride)
+        .take(plane.height as usize)
+        .flat_map(|row| &row[..row_size])
+        .copied()
+        .collect();
+
+    image::RgbImage::from_raw(plane.width, plane.height, pixels).map(DynamicImage::ImageRgb8)
+}
+
+/// The full decoding needs the `heif` feature.
+#[cfg(not(feature = "heif"))]
+pub fn decode(_path: &Path) -> Option<DynamicImage> {
+    None
+}
+
+/// Decodes the HEIF image, or its preview without the `heif` feature, and
+/// encodes it as a JPEG for the browsers which cannot show HEIF.
+pub fn transcode_to_jpeg(path: &Path) -> Option<Vec<u8>> {
+    let img = decode(path).or_else(|| HeifFile::from_path(path)?.preview())?;
+    let mut jpeg = vec![];
+
+    JpegEncoder::new_with_quality(&mut jpeg, 90)
+        .encode_image(&img.to_rgb8())
+        .ok()?;
+
+    Some(jpeg)
+}
+
+/// Reads a big-endian number of `size` bytes and advances the position.
+fn read_be(data: &[u8], pos: &mut usize, size: u8) -> Option<u64> {
+    let bytes = data.get(*pos..*pos + size as usize)?;
+
+    *pos += size as usize;
+
+    Some(bytes.iter().fold(0, |acc, b| (acc << 8) | *b as u64))
+}
+
+fn parse_iinf(iinf: &[u8]) -> Vec<(u32, [u8; 4])> {
+    let Some(&version) = iinf.first() else {
+        return vec![];
+    };
+
+    let start = if version == 0 { 6 } else { 8 };
+
+    boxes(iinf.get(start..).unwrap_or_default())
+        .filter(|(kind, _)| kind == b"infe")
+        .filter_map(|(_, infe)| {
+            let version = *infe.first()?;
+            let mut pos = 4;
+
+            // The older versions don't have item types
+            if version < 2 {
+                return None;
+            }
+
+            let id = read_be(infe, &mut pos, if version == 2 { 2 } else { 4 })? as u32;
+            pos += 2;
+
+            Some((id, infe.get(pos..pos + 4)?.try_into().ok()?))
+        })
+        .collect()
+}
+
+fn parse_iloc(iloc: &[u8]) -> Option<HashMap<u32, Location>> {
+    let version = *iloc.first()?;
+    let mut pos = 4;
+
+    let sizes = read_be(iloc, &mut pos, 2)? as u16;
+    let offset_size = (sizes >> 12) as u8;
+    let length_size = ((sizes >> 8) & 15) as u8;
+    let base_offset_size = ((sizes >> 4) & 15) as u8;
+    let index_size = if version >= 1 { (sizes & 15) as u8 } else { 0 };
+    let id_size = if version < 2 { 2 } else { 4 };
+
+    let count = read_be(iloc, &mut pos, id_size)?;
+    let mut locations = HashMap::new();
+
+    for _ in 0..count {
+        let id = read_be(iloc, &mut pos, id_size)? as u32;
+        let construction_method = if version >= 1 {
+            (read_be(iloc, &mut pos, 2)? & 15) as u8
+        } else {
+            0
+        };
+
+        // The data reference index
+        read_be(iloc, &mut pos, 2)?;
+
+        let base_offset = read_be(iloc, &mut pos, base_offset_size)?;
+        let extent_count = read_be(iloc, &mut pos, 2)?;
+        let mut extents = vec![];
+
+        for _ in 0..extent_count {
+            read_be(iloc, &mut pos, index_size)?;
+
+            let offset = read_be(iloc, &mut pos, offset_size)?;
+            let length = read_be(iloc, &mut pos, length_size)?;
+
+            extents.push((base_offset.checked_add(offset)?, length));
+        }
+
+        locations.insert(
+            id,
+            Location {
+                construction_method,
+                extents,
+            },
+        );
+    }
+
+    Some(locations)
+}
+
+fn parse_iref(iref: &[u8]) -> Vec<([u8; 4], u32, Vec<u32>)> {
+    let id_size = if iref.first() == Some(&0) { 2 } else { 4 };
+
+    boxes(iref.get(4..).unwrap_or_default())
+        .filter_map(|(kind, content)| {
+            let mut pos = 0;
+            let from = read_be(content, &mut pos, id_size)? as u32;
+            let count = read_be(content, &mut pos, 2)?;
+            let to = (0..count)
+                .map_while(|_| read_be(content, &mut pos, id_size).map(|id| id as u32))
+                .collect();
+
+            Some((kind, from, to))
+        })
+        .collect()
+}
+
+fn parse_ipma(ipma: &[u8], associations: &mut HashMap<u32, Vec<usize>>) -> Option<()> {
+    let version = *ipma.first()?;
+    let large_indices = ipma.get(3)? & 1 == 1;
+    let mut pos = 4;
+
+    let count = read_be(ipma, &mut pos, 4)?;
+
+    for _ in 0..count {
+        let id = read_be(ipma, &mut pos, if version < 1 { 2 } else { 4 })? as u32;
+        let association_count = read_be(ipma, &mut pos, 1)?;
+
+        for _ in 0..association_count {
+            // The highest bit tells if the property is essential
+            let index = if large_indices {
+                read_be(ipma, &mut pos, 2)? & 0x7FFF
+            } else {
+                read_be(ipma, &mut pos, 1)? & 0x7F
+            };
+
+            associations.entry(id).or_default().push(index as usize);
+        }
+    }
+
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The version and the flags of a full box.
+    fn full_box(version: u8, flags: u8) -> Vec<u8> {
+        vec![version, 0, 0, flags]
+    }
+
+    #[test]
+    fn iloc_with_idat_offsets() {
+        let iloc = [
+            full_box(1, 0),
+            // 4 byte offsets and lengths, no base offsets and indices
+            0x4400u16.to_be_bytes().to_vec(),
+            1u16.to_be_bytes().to_vec(),
+            // Item 1 in the idat box
+            [1u16, 1, 0, 1]
+                .iter()
+                .flat_map(|v| v.to_be_bytes())
+                .collect(),
+            10u32.to_be_bytes().to_vec(),
+            20u32.to_be_bytes().to_vec(),
+        ]
+        .concat();
+
+        let locations = parse_iloc(&iloc).unwrap();
+
+        assert_eq!(locations[&1].construction_method, 1);
+        assert_eq!(locations[&1].extents, vec![(10, 20)]);
+    }
+
+    #[test]
+    fn iloc_with_64_bit_offsets() {
+        let iloc = [
+            full_box(0, 0),
+            // 8 byte offsets and lengths, 4 byte base offsets
+            0x8840u16.to_be_bytes().to_vec(),
+            1u16.to_be_bytes().to_vec(),
+            2u16.to_be_bytes().to_vec(),
+            0u16.to_be_bytes().to_vec(),
+            100u32.to_be_bytes().to_vec(),
+            2u16.to_be_bytes().to_vec(),
+            (1u64 << 33).to_be_bytes().to_vec(),
+            5u64.to_be_bytes().to_vec(),
+            0u64.to_be_bytes().to_vec(),
+            1u64.to_be_bytes().to_vec(),
+        ]
+        .concat();
+
+        let locations = parse_iloc(&iloc).unwrap();
+
+        assert_eq!(locations[&2].construction_method, 0);
+        assert_eq!(locations[&2].extents, vec![((1 << 33) + 100, 5), (100, 1)]);
+    }
+
+    #[test]
+    fn iloc_with_32_bit_item_ids() {
+        let iloc = [
+            full_box(2, 0),
+            0x4400u16.to_be_bytes().to_vec(),
+            1u32.to_be_bytes().to_vec(),
+            70_000u32.to_be_bytes().to_vec(),
+            [0u16, 0, 1].iter().flat_map(|v| v.to_be_bytes()).collect(),
+            [3u32, 4].iter().flat_map(|v| v.to_be_bytes()).collect(),
+        ]
+        .concat();
+
+        assert_eq!(parse_iloc(&iloc).unwrap()[&70_000].extents, vec![(3, 4)]);
+    }
+
+    #[test]
+    fn broken_iloc() {
+        let iloc = [
+            full_box(0, 0),
+            0x8880u16.to_be_bytes().to_vec(),
+            1u16.to_be_bytes().to_vec(),
+            [1u16, 0].iter().flat_map(|v| v.to_be_bytes()).collect(),
+            u64::MAX.to_be_bytes().to_vec(),
+            1u16.to_be_bytes().to_vec(),
+            1u64.to_be_bytes().to_vec(),
+            1u64.to_be_bytes().to_vec(),
+        ]
+        .concat();
+
+        // The base offset and the offset overflow
+        assert!(parse_iloc(&iloc).is_none());
+
+        for length in [0, 3, 7, 12, iloc.len() - 1] {
+            assert!(parse_iloc(&iloc[..length]).is_none());
+        }
+    }
+
+    #[test]
+    fn ipma_with_small_and_large_indices() {
+        let ipma = [
+            full_box(0, 0),
+            2u32.to_be_bytes().to_vec(),
+            // The highest bit marks the essential properties
+            vec![0, 1, 2, 0x81, 0x02],
+            vec![0, 2, 1, 0x03],
+        ]
+        .concat();
+        let mut associations = HashMap::new();
+
+        assert!(parse_ipma(&ipma, &mut associations).is_some());
+        assert_eq!(associations[&1], vec![1, 2]);
+        assert_eq!(associations[&2], vec![3]);
+
+        let ipma = [
+            full_box(1, 1),
+            1u32.to_be_bytes().to_vec(),
+            7u32.to_be_bytes().to_vec(),
+            vec![1],
+            0x8123u16.to_be_bytes().to_vec(),
+        ]
+        .concat();
+        let mut associations = HashMap::new();
+
+        assert!(parse_ipma(&ipma, &mut associations).is_some());
+        assert_eq!(associations[&7], vec![0x123]);
+    }
+
+    #[test]
+    fn truncated_ipma_keeps_the_complete_items() {
+        let ipma = [
+            full_box(0, 0),
+            // Claims more items than there are
+            1000u32.to_be_bytes().to_vec(),
+            vec![0, 1, 1, 0x01],
+            vec![0, 2, 5, 0x01],
+        ]
+        .concat();
+        let mut associations = HashMap::new();
+
+        assert!(parse_ipma(&ipma, &mut associations).is_none());
+        assert_eq!(associations[&1], vec![1]);
+        assert!(parse_ipma(&ipma[..6], &mut HashMap::new()).is_none());
+    }
+
+    #[test]
+    fn idat_extents_past_the_end() {
+        let heif = HeifFile {
+            path: PathBuf::new(),
+            primary: 1,
+            items: vec![(1, *b"jpeg"), (2, *b"jpeg")],
+            locations: HashMap::from([
+                (
+                    1,
+                    Location {
+                        construction_method: 1,
+                        extents: vec![(2, 2)],
+                    },
+                ),
+                (
+                    2,
+                    Location {
+                        construction_method: 1,
+                        extents: vec![(u64::MAX, 2), (2, 8)],
+                    },
+                ),
+            ]),
+            references: vec![],
+            properties: vec![],
+            associations: HashMap::new(),
+            idat: vec![1, 2, 3, 4],
+        };
+
+        assert_eq!(heif.item_data(1), Some(vec![3, 4]));
+        assert_eq!(heif.item_data(2), None);
+    }
+}
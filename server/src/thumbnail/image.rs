@@ -1,13 +1,29 @@
 use std::{
     ffi::OsString,
-    fs::DirEntry,
+    fs::{DirEntry, File},
     os::unix::fs::MetadataExt,
     path::{Path, PathBuf},
     time::Instant,
 };
 
-use image::{DynamicImage, ImageDecoder, ImageReader, RgbImage, buffer::ConvertBuffer};
-use log::debug;
+use image::{DynamicImage, ImageDecoder, ImageReader, Rgb, RgbImage, buffer::ConvertBuffer};
+use log::{debug, warn};
+
+use crate::{
+    catalog::xmp::{self, XmpData},
+    scanner::directory::{Directory, MediaType},
+    thumbnail::{
+        animation::{Animation, AnimationInfo},
+        capture::CaptureInfo,
+        heif::{self, HeifFile},
+        jpeg::ScaledJpeg,
+        palette,
+        phash::PerceptualHashes,
+        quality::{ANALYSIS_SIZE, QualityScores},
+        raw::RawPreview,
+        video::{Video, VideoInfo},
+    },
+};
 
 #[derive(Debug)]
 pub struct Image {
@@ -16,46 +32,358 @@ pub struct Image {
     pub width: u32,
     pub height: u32,
     pub size: u64,
+    /// The dimensions of the original image after applying the orientation.
+    pub original_width: u32,
+    pub original_height: u32,
+    pub media_type: MediaType,
+    /// The container metadata of a video.
+    pub video: Option<VideoInfo>,
     pub thumbnail: RgbImage,
+    pub capture: Option<CaptureInfo>,
+    /// The BLAKE3 hash of the file content as a hex string.
+    pub hash: Option<String>,
+    /// The perceptual hashes, videos without a poster don't have them.
+    pub perceptual: Option<PerceptualHashes>,
+    /// The quality scores, only images have them.
+    pub quality: Option<QualityScores>,
+    /// The main colors of the thumbnail, the most common first.
+    pub palette: Vec<String>,
+    pub dominant_color: String,
+    pub blurhash: Option<String>,
+    /// The metadata from the XMP sidecar or the embedded XMP packet.
+    pub xmp: Option<XmpData>,
+    /// The file name of the RAW file shot together with the JPEG.
+    pub raw_name: Option<String>,
+    /// The frame count and the length of an animated GIF or WebP.
+    pub animation: Option<AnimationInfo>,
+    /// A short animated GIF of thumbnail size.
+    pub animated_preview: Option<Vec<u8>>,
+    /// Why the file could not be read, it has a placeholder thumbnail then.
+    pub error: Option<String>,
 }
 
+/// The thumbnail, the dimensions of the oriented original image and its
+/// quality scores.
+type FrameThumbnail = (RgbImage, (u32, u32), QualityScores);
+
 impl Image {
-    pub fn from_path(entry: &DirEntry) -> Self {
+    pub fn from_path(entry: &DirEntry) -> Result<Self, String> {
         let path = entry.path();
-        let thumbnail = Image::create_thumbnail(&path);
+        let size = entry.metadata().map_err(|e| e.to_string())?.size();
+        let media_type = MediaType::from_path(&path).unwrap_or_default();
+        let animation = Directory::has_animation_extension(&path)
+            .then(|| Animation::from_path(&path))
+            .flatten();
 
-        Image {
+        let mut error = None;
+        let (thumbnail, (original_width, original_height), quality, video, has_poster) =
+            match media_type {
+                MediaType::Image => {
+                    let (thumbnail, dimensions, quality) = match &animation {
+                        Some(animation) => Image::create_frame_thumbnail(&animation.frame, None),
+                        None => {
+                            let (thumbnail, warning) = Image::create_thumbnail(&path)?;
+
+                            error = warning;
+                            thumbnail
+                        }
+                    };
+
+                    (thumbnail, dimensions, Some(quality), None, true)
+                }
+                MediaType::Video => {
+                    let (thumbnail, info, has_poster) = Image::create_video_thumbnail(&path);
+
+                    (
+                        thumbnail,
+                        (info.width, info.height),
+                        None,
+                        Some(info),
+                        has_poster,
+                    )
+                }
+            };
+
+        let capture = match &video {
+            Some(info) => Some(CaptureInfo {
+                camera: None,
+                taken_at: info.created_at.clone(),
+            }),
+            None => CaptureInfo::from_path(&path),
+        };
+        let hash = Image::content_hash(&path);
+        let perceptual = has_poster.then(|| PerceptualHashes::from_thumbnail(&thumbnail));
+        let palette = palette::extract_palette(&thumbnail);
+        let dominant_color = palette
+            .first()
+            .cloned()
+            .unwrap_or_else(|| palette::average_color(&thumbnail));
+        let blurhash = palette::blurhash(&thumbnail);
+        let xmp = xmp::read_for_image(&path);
+        let (animation, animated_preview) = match animation {
+            Some(animation) => (Some(animation.info), animation.preview),
+            None => (None, None),
+        };
+
+        Ok(Image {
             id: path.file_name().unwrap().to_os_string(),
             file_path: path,
             width: thumbnail.width(),
             height: thumbnail.height(),
-            size: entry.metadata().unwrap().size(),
+            size,
+            original_width,
+            original_height,
+            media_type,
+            video,
+            thumbnail,
+            capture,
+            hash,
+            perceptual,
+            quality,
+            palette,
+            dominant_color,
+            blurhash,
+            xmp,
+            raw_name: None,
+            animation,
+            animated_preview,
+            error,
+        })
+    }
+
+    /// An image which could not be read, it is shown with a placeholder
+    /// thumbnail and the error.
+    pub fn failed(path: &Path, error: String) -> Self {
+        let thumbnail = Image::error_placeholder(256, 192);
+
+        Image {
+            id: path.file_name().unwrap_or_default().to_os_string(),
+            file_path: path.to_path_buf(),
+            width: thumbnail.width(),
+            height: thumbnail.height(),
+            size: path.metadata().map(|m| m.size()).unwrap_or(0),
+            original_width: 0,
+            original_height: 0,
+            media_type: MediaType::from_path(path).unwrap_or_default(),
+            video: None,
+            capture: None,
+            hash: Image::content_hash(path),
+            perceptual: None,
+            quality: None,
+            palette: vec![],
+            dominant_color: palette::average_color(&thumbnail),
+            blurhash: None,
             thumbnail,
+            xmp: xmp::read_for_image(path),
+            raw_name: None,
+            animation: None,
+            animated_preview: None,
+            error: Some(error),
         }
     }
 
+    /// A gray thumbnail crossed out.
+    fn error_placeholder(width: u32, height: u32) -> RgbImage {
+        let mut thumbnail = RgbImage::from_pixel(width, height, Rgb([96, 96, 96]));
+
+        for x in 0..width {
+            let y = x * height / width;
+
+            for dy in 0..3 {
+                let y = (y + dy).min(height - 1);
+
+                thumbnail.put_pixel(x, y, Rgb([200, 60, 60]));
+                thumbnail.put_pixel(x, height - 1 - y, Rgb([200, 60, 60]));
+            }
+        }
+
+        thumbnail
+    }
+
+    /// The name of the animated preview file, which is next to the
+    /// thumbnail sprites.
+    pub fn animated_preview_name(&self) -> String {
+        format!("thumbs_anim_{}.gif", self.id.to_string_lossy())
+    }
+
+    /// Returns the thumbnail of the poster of the video, or a placeholder if the
+    /// video has no poster which can be decoded. The flag tells if the poster
+    /// was found.
+    pub fn create_video_thumbnail(path: &Path) -> (RgbImage, VideoInfo, bool) {
+        let (mut info, poster) = match Video::from_path(path) {
+            Some(video) => (video.info, video.poster),
+            None => (VideoInfo::default(), None),
+        };
+
+        let poster = poster.and_then(|data| image::load_from_memory(&data).ok());
+
+        match poster {
+            Some(poster) => {
+                if info.width == 0 || info.height == 0 {
+                    (info.width, info.height) = (poster.width(), poster.height());
+                }
+
+                (poster.thumbnail(256, 256).to_rgb8(), info, true)
+            }
+            None => (Video::placeholder(256, 144), info, false),
+        }
+    }
+
+    pub fn content_hash(path: &Path) -> Option<String> {
+        let mut hasher = blake3::Hasher::new();
+
+        hasher.update_reader(File::open(path).ok()?).ok()?;
+
+        Some(hasher.finalize().to_hex().to_string())
+    }
+
     // TODO
     // We need to follow a different method. We need to read the images, apply orientation,
     // and get the dimensions, create thumbnail and start to collect them into different
     // bundles.
-    pub fn create_thumbnail(path: impl AsRef<Path>) -> RgbImage {
+    /// Returns the thumbnail with the dimensions and the quality scores, and
+    /// why the thumbnail is only a placeholder.
+    pub fn create_thumbnail(
+        path: impl AsRef<Path>,
+    ) -> Result<(FrameThumbnail, Option<String>), String> {
         let start = Instant::now();
-        let mut decoder = ImageReader::open(&path).unwrap().into_decoder().unwrap();
-        let orientation = decoder.orientation().unwrap();
-        let mut img = DynamicImage::from_decoder(decoder).unwrap();
+        let mut dimensions = None;
+        let mut warning = None;
+
+        let img = if Directory::has_raw_extension(path.as_ref()) {
+            let (img, raw_warning) = Image::decode_raw_preview(path.as_ref());
+
+            warning = raw_warning;
+            img
+        } else if Directory::has_heif_extension(path.as_ref()) {
+            let (img, heif_dimensions, heif_warning) = Image::decode_heif(path.as_ref());
+
+            dimensions = heif_dimensions;
+            warning = heif_warning;
+            img
+        } else if let Some(scaled) = Directory::has_image_extension(path.as_ref())
+            .then(|| ScaledJpeg::from_path(path.as_ref(), ANALYSIS_SIZE))
+            .flatten()
+        {
+            dimensions = Some(scaled.dimensions);
+            scaled.image
+        } else {
+            let mut decoder = ImageReader::open(&path)
+                .map_err(|e| e.to_string())?
+                .with_guessed_format()
+                .map_err(|e| e.to_string())?
+                .into_decoder()
+                .map_err(|e| e.to_string())?;
+            let orientation = decoder.orientation().map_err(|e| e.to_string())?;
+            let mut img = DynamicImage::from_decoder(decoder).map_err(|e| e.to_string())?;
 
-        img.apply_orientation(orientation);
+            img.apply_orientation(orientation);
+            img
+        };
 
-        let thumb = img.thumbnail(256, 256);
+        let result = Image::create_frame_thumbnail(&img, dimensions);
 
         debug!("{:?} {:?}", path.as_ref(), start.elapsed());
 
-        match thumb {
+        Ok((result, warning))
+    }
+
+    /// Returns the thumbnail and the quality scores of a decoded image. The
+    /// dimensions are the ones of the decoded image if not given, the
+    /// previews can be smaller than the original image.
+    fn create_frame_thumbnail(
+        img: &DynamicImage,
+        dimensions: Option<(u32, u32)>,
+    ) -> FrameThumbnail {
+        let dimensions = dimensions.unwrap_or((img.width(), img.height()));
+        // The small thumbnail is scaled from the analysis image, the full
+        // image is only scaled once
+        let analysis = img.thumbnail(ANALYSIS_SIZE, ANALYSIS_SIZE);
+        let quality = QualityScores::from_luma(&analysis.to_luma8());
+        let thumb = analysis.thumbnail(256, 256);
+
+        let thumb = match thumb {
             DynamicImage::ImageLuma8(gray_image) => gray_image.convert(),
             DynamicImage::ImageRgb8(rgb_image) => rgb_image,
-            _ => {
-                panic!("Image is not an RGB8 image");
+            thumb => thumb.to_rgb8(),
+        };
+
+        (thumb, dimensions, quality)
+    }
+
+    /// Decodes the embedded preview of a RAW file. The RAW data itself is not
+    /// decoded, so the files without a usable preview get a gray placeholder,
+    /// also returns why.
+    fn decode_raw_preview(path: &Path) -> (DynamicImage, Option<String>) {
+        match RawPreview::from_path(path).and_then(|p| p.decode()) {
+            Some(img) => (img, None),
+            None => {
+                let warning = "No usable preview in the RAW file";
+
+                warn!("{warning}: {path:?}");
+
+                (
+                    DynamicImage::ImageRgb8(RgbImage::from_pixel(256, 170, Rgb([128, 128, 128]))),
+                    Some(warning.to_owned()),
+                )
             }
         }
     }
+
+    /// Decodes a HEIF image, or its preview if the `heif` feature is not
+    /// enabled. Also returns the dimensions of the full image from the
+    /// container, and why the image got a gray placeholder.
+    fn decode_heif(path: &Path) -> (DynamicImage, Option<(u32, u32)>, Option<String>) {
+        let file = HeifFile::from_path(path);
+        let dimensions = file.as_ref().and_then(HeifFile::dimensions);
+        let readable = file.is_some();
+
+        match heif::decode(path).or_else(|| file?.preview()) {
+            Some(img) => (img, dimensions, None),
+            None => {
+                // Without libheif only the JPEG previews can be shown
+                let warning = if readable && !cfg!(feature = "heif") {
+                    "HEVC thumbnail needs the heif feature"
+                } else {
+                    "No decodable image in the HEIF file"
+                };
+
+                warn!("{warning}: {path:?}");
+
+                let (width, height) = dimensions.unwrap_or((256, 192));
+                let height = (256 * height / width.max(1)).clamp(1, 256);
+
+                (
+                    DynamicImage::ImageRgb8(RgbImage::from_pixel(
+                        256,
+                        height,
+                        Rgb([128, 128, 128]),
+                    )),
+                    dimensions,
+                    Some(warning.to_owned()),
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::test_helpers::TempDir;
+
+    #[test]
+    fn raw_files_without_preview_have_an_error() {
+        let dir = TempDir::new("raw-placeholder");
+
+        std::fs::write(dir.join("broken.cr2"), b"II*\0not a raw file").unwrap();
+
+        let entry = dir.path().read_dir().unwrap().next().unwrap().unwrap();
+        let image = Image::from_path(&entry).unwrap();
+
+        assert_eq!(
+            image.error.as_deref(),
+            Some("No usable preview in the RAW file")
+        );
+    }
 }
@@ -0,0 +1,72 @@
+use std::{fs::File, io::BufReader, path::Path};
+
+use exif::{In, Reader, Tag};
+use image::{DynamicImage, GrayImage, RgbImage, metadata::Orientation};
+use jpeg_decoder::{Decoder, PixelFormat};
+
+/// A JPEG decoded at a reduced size with the orientation applied. The full
+/// decoding dominates the scanning time of the large photos, while the
+/// thumbnails and the quality analysis need only a fraction of the pixels.
+pub struct ScaledJpeg {
+    pub image: DynamicImage,
+    /// The dimensions of the original image after the orientation.
+    pub dimensions: (u32, u32),
+}
+
+impl ScaledJpeg {
+    /// Decodes the JPEG with DCT scaling at 1/2, 1/4 or 1/8 of its size, the
+    /// smallest which is at least `min_size` on its longer side. The EXIF
+    /// thumbnails are not used, they are far smaller than the analysis size.
+    /// `None` if the image needs the full decoder, like the CMYK and the 16
+    /// bit ones.
+    pub fn from_path(path: &Path, min_size: u32) -> Option<ScaledJpeg> {
+        let mut decoder = Decoder::new(BufReader::new(File::open(path).ok()?));
+
+        // The EXIF data is before the frame header, so it is read as well
+        decoder.read_info().ok()?;
+
+        let info = decoder.info()?;
+        let orientation = decoder
+            .exif_data()
+            .and_then(read_orientation)
+            .unwrap_or(Orientation::NoTransforms);
+
+        let mut image = decode_scaled(&mut decoder, min_size)?;
+
+        image.apply_orientation(orientation);
+
+        let (width, height) = (info.width as u32, info.height as u32);
+        let dimensions = match orientation {
+            Orientation::Rotate90
+            | Orientation::Rotate270
+            | Orientation::Rotate90FlipH
+            | Orientation::Rotate270FlipH => (height, width),
+            _ => (width, height),
+        };
+
+        Some(ScaledJpeg { image, dimensions })
+    }
+}
+
+fn decode_scaled(decoder: &mut Decoder<impl std::io::Read>, min_size: u32) -> Option<DynamicImage> {
+    let min_size = min_size.min(u16::MAX as u32) as u16;
+    let (width, height) = decoder.scale(min_size, min_size).ok()?;
+    let pixels = decoder.decode().ok()?;
+    let (width, height) = (width as u32, height as u32);
+
+    match decoder.info()?.pixel_format {
+        PixelFormat::L8 => GrayImage::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8),
+        PixelFormat::RGB24 => RgbImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8),
+        PixelFormat::L16 | PixelFormat::CMYK32 => None,
+    }
+}
+
+fn read_orientation(exif: &[u8]) -> Option<Orientation> {
+    let exif = Reader::new().read_raw(exif.to_vec()).ok()?;
+    let value = exif
+        .get_field(Tag::Orientation, In::PRIMARY)?
+        .value
+        .get_uint(0)?;
+
+    Orientation::from_exif(value as u8)
+}
@@ -1,2 +1,12 @@
+pub mod animation;
+mod bmff;
 pub mod bundle;
+pub mod capture;
+pub mod heif;
 pub mod image;
+pub mod jpeg;
+pub mod palette;
+pub mod phash;
+pub mod quality;
+pub mod raw;
+pub mod video;
@@ -0,0 +1,127 @@
+use image::{RgbImage, buffer::ConvertBuffer};
+
+/// The colors covering less of the image than this are not in the palette.
+const MIN_SHARE: f32 = 0.05;
+const MAX_COLORS: usize = 5;
+/// The maximum distance of a palette color to match a searched color.
+const MATCH_DISTANCE: f32 = 100.0;
+
+const NAMED_COLORS: [(&str, [u8; 3]); 11] = [
+    ("red", [200, 40, 40]),
+    ("orange", [235, 140, 40]),
+    ("yellow", [230, 210, 60]),
+    ("green", [60, 150, 60]),
+    ("blue", [50, 90, 200]),
+    ("purple", [130, 60, 160]),
+    ("pink", [230, 130, 170]),
+    ("brown", [120, 80, 50]),
+    ("black", [15, 15, 15]),
+    ("white", [240, 240, 240]),
+    ("gray", [128, 128, 128]),
+];
+
+/// The main colors of the thumbnail as `#rrggbb`, the most common first. The
+/// colors are quantized to 2 bits per channel and averaged in their bins, the
+/// most common color is always in the palette.
+pub fn extract_palette(thumbnail: &RgbImage) -> Vec<String> {
+    let mut bins = vec![(0u32, [0u64; 3]); 64];
+
+    for pixel in thumbnail.pixels() {
+        let [r, g, b] = pixel.0;
+        let bin = ((r as usize >> 6) << 4) | ((g as usize >> 6) << 2) | (b as usize >> 6);
+        let (count, sum) = &mut bins[bin];
+
+        *count += 1;
+        sum[0] += r as u64;
+        sum[1] += g as u64;
+        sum[2] += b as u64;
+    }
+
+    let total = (thumbnail.width() * thumbnail.height()).max(1) as f32;
+
+    bins.sort_by_key(|(count, _)| std::cmp::Reverse(*count));
+
+    bins.iter()
+        .take(MAX_COLORS)
+        .enumerate()
+        .filter(|(i, (count, _))| *i == 0 || *count as f32 / total >= MIN_SHARE)
+        .map(|(_, bin)| bin)
+        .filter(|(count, _)| *count > 0)
+        .map(|(count, sum)| {
+            let count = *count as u64;
+
+            to_hex([
+                (sum[0] / count) as u8,
+                (sum[1] / count) as u8,
+                (sum[2] / count) as u8,
+            ])
+        })
+        .collect()
+}
+
+/// The average color of the thumbnail as `#rrggbb`.
+pub fn average_color(thumbnail: &RgbImage) -> String {
+    let mut sum = [0u64; 3];
+
+    for pixel in thumbnail.pixels() {
+        for (s, c) in sum.iter_mut().zip(pixel.0) {
+            *s += c as u64;
+        }
+    }
+
+    let count = (thumbnail.width() * thumbnail.height()).max(1) as u64;
+
+    to_hex(sum.map(|s| (s / count) as u8))
+}
+
+/// A compact blurred version of the thumbnail which the gallery can render
+/// before the sprites are loaded.
+pub fn blurhash(thumbnail: &RgbImage) -> Option<String> {
+    let rgba: image::RgbaImage = thumbnail.convert();
+
+    blurhash::encode(4, 3, rgba.width(), rgba.height(), rgba.as_raw()).ok()
+}
+
+/// Parses a color given as `#rrggbb`, `rrggbb` or one of the basic color
+/// names.
+pub fn parse_color(color: &str) -> Option<[u8; 3]> {
+    let color = color.trim().to_lowercase();
+
+    if let Some((_, rgb)) = NAMED_COLORS.iter().find(|(name, _)| *name == color) {
+        return Some(*rgb);
+    }
+
+    let hex = color.strip_prefix('#').unwrap_or(&color);
+
+    if hex.len() != 6 {
+        return None;
+    }
+
+    let value = u32::from_str_radix(hex, 16).ok()?;
+
+    Some([(value >> 16) as u8, (value >> 8) as u8, value as u8])
+}
+
+/// Checks if any of the palette colors is close to the color. The distance
+/// is weighted by the mean red value, which is closer to the perceived
+/// difference than the plain RGB distance.
+pub fn palette_matches(palette: &[String], color: [u8; 3]) -> bool {
+    palette
+        .iter()
+        .filter_map(|c| parse_color(c))
+        .any(|c| distance(c, color) <= MATCH_DISTANCE)
+}
+
+fn distance(a: [u8; 3], b: [u8; 3]) -> f32 {
+    let mean_red = (a[0] as f32 + b[0] as f32) / 2.0;
+    let [dr, dg, db] = [0, 1, 2].map(|i| a[i] as f32 - b[i] as f32);
+
+    ((2.0 + mean_red / 256.0) * dr * dr
+        + 4.0 * dg * dg
+        + (2.0 + (255.0 - mean_red) / 256.0) * db * db)
+        .sqrt()
+}
+
+fn to_hex([r, g, b]: [u8; 3]) -> String {
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
@@ -0,0 +1,113 @@
+use std::f64::consts::PI;
+
+use image::{
+    GrayImage, RgbImage,
+    imageops::{self, FilterType},
+};
+use serde::{Deserialize, Serialize};
+
+/// Perceptual hashes of an image, similar images have hashes with a small
+/// Hamming distance. They are computed from the thumbnail, the full image is
+/// not needed.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct PerceptualHashes {
+    /// Average hash: the pixels compared to the mean.
+    pub ahash: u64,
+    /// Difference hash: the horizontal gradients.
+    pub dhash: u64,
+    /// DCT hash: the low frequencies compared to their median.
+    pub phash: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashKind {
+    Ahash,
+    Dhash,
+    #[default]
+    Phash,
+}
+
+impl PerceptualHashes {
+    pub fn from_thumbnail(thumbnail: &RgbImage) -> Self {
+        let gray = imageops::grayscale(thumbnail);
+
+        PerceptualHashes {
+            ahash: average_hash(&gray),
+            dhash: difference_hash(&gray),
+            phash: dct_hash(&gray),
+        }
+    }
+
+    pub fn distance(&self, other: &PerceptualHashes, kind: HashKind) -> u32 {
+        match kind {
+            HashKind::Ahash => (self.ahash ^ other.ahash).count_ones(),
+            HashKind::Dhash => (self.dhash ^ other.dhash).count_ones(),
+            HashKind::Phash => (self.phash ^ other.phash).count_ones(),
+        }
+    }
+}
+
+fn average_hash(gray: &GrayImage) -> u64 {
+    let small = imageops::resize(gray, 8, 8, FilterType::Triangle);
+    let mean = small.pixels().map(|p| p.0[0] as u32).sum::<u32>() / 64;
+
+    to_bits(small.pixels().map(|p| p.0[0] as u32 > mean))
+}
+
+fn difference_hash(gray: &GrayImage) -> u64 {
+    let small = imageops::resize(gray, 9, 8, FilterType::Triangle);
+
+    to_bits((0..8).flat_map(|y| {
+        let small = &small;
+
+        (0..8).map(move |x| small.get_pixel(x, y).0[0] < small.get_pixel(x + 1, y).0[0])
+    }))
+}
+
+fn dct_hash(gray: &GrayImage) -> u64 {
+    const SIZE: usize = 32;
+
+    let small = imageops::resize(gray, SIZE as u32, SIZE as u32, FilterType::Triangle);
+    let pixels: Vec<f64> = small.pixels().map(|p| p.0[0] as f64).collect();
+
+    // Only the 8x8 lowest frequencies are needed from the 2D DCT-II
+    let cosines: Vec<f64> = (0..8)
+        .flat_map(|u| {
+            (0..SIZE).map(move |x| ((2 * x + 1) as f64 * u as f64 * PI / (2 * SIZE) as f64).cos())
+        })
+        .collect();
+
+    let mut rows = vec![0f64; SIZE * 8];
+
+    for y in 0..SIZE {
+        for u in 0..8 {
+            rows[y * 8 + u] = (0..SIZE)
+                .map(|x| pixels[y * SIZE + x] * cosines[u * SIZE + x])
+                .sum();
+        }
+    }
+
+    let mut coefficients = [0f64; 64];
+
+    for v in 0..8 {
+        for u in 0..8 {
+            coefficients[v * 8 + u] = (0..SIZE)
+                .map(|y| rows[y * 8 + u] * cosines[v * SIZE + y])
+                .sum();
+        }
+    }
+
+    // The DC coefficient is the average brightness, it is left out of the
+    // median
+    let mut sorted = coefficients[1..].to_vec();
+    sorted.sort_by(f64::total_cmp);
+
+    let median = sorted[sorted.len() / 2];
+
+    to_bits(coefficients.iter().map(|c| *c > median))
+}
+
+fn to_bits(bits: impl Iterator<Item = bool>) -> u64 {
+    bits.fold(0u64, |hash, bit| (hash << 1) | bit as u64)
+}
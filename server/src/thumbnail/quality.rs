@@ -0,0 +1,159 @@
+use image::GrayImage;
+use serde::{Deserialize, Serialize};
+
+/// The images are analyzed on this size, so the scores of images with
+/// different resolutions can be compared.
+pub const ANALYSIS_SIZE: u32 = 1024;
+
+/// Below this variance of the Laplacian the image is considered blurry.
+const BLURRY_SHARPNESS: f32 = 100.0;
+/// Above this fraction of clipped highlights the image is overexposed.
+const OVEREXPOSED_RATIO: f32 = 0.1;
+/// Above this fraction of crushed shadows the image is underexposed.
+const UNDEREXPOSED_RATIO: f32 = 0.3;
+/// Above this estimated standard deviation of the noise the image is noisy.
+const NOISY_SIGMA: f32 = 8.0;
+
+/// Technical quality scores of an image, computed from its luminance.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct QualityScores {
+    /// The variance of the Laplacian, the higher the sharper.
+    pub sharpness: f32,
+    /// The fraction of the pixels with clipped highlights.
+    pub overexposed: f32,
+    /// The fraction of the pixels with crushed shadows.
+    pub underexposed: f32,
+    /// The estimated standard deviation of the noise.
+    pub noise: f32,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QualityIssue {
+    Blurry,
+    Overexposed,
+    Underexposed,
+    Noisy,
+}
+
+impl QualityScores {
+    pub fn from_luma(gray: &GrayImage) -> Self {
+        let (width, height) = gray.dimensions();
+
+        if width < 3 || height < 3 {
+            return QualityScores::default();
+        }
+
+        let pixel = |x: u32, y: u32| gray.get_pixel(x, y).0[0] as f64;
+        let mut laplacian_sum = 0f64;
+        let mut laplacian_square_sum = 0f64;
+        let mut noise_sum = 0f64;
+
+        for y in 1..height - 1 {
+            for x in 1..width - 1 {
+                let laplacian =
+                    pixel(x - 1, y) + pixel(x + 1, y) + pixel(x, y - 1) + pixel(x, y + 1)
+                        - 4.0 * pixel(x, y);
+
+                laplacian_sum += laplacian;
+                laplacian_square_sum += laplacian * laplacian;
+
+                // Immerkær's mask, which cancels out the edges and leaves
+                // the noise
+                let noise = pixel(x - 1, y - 1)
+                    + pixel(x + 1, y - 1)
+                    + pixel(x - 1, y + 1)
+                    + pixel(x + 1, y + 1)
+                    - 2.0 * (pixel(x - 1, y) + pixel(x + 1, y) + pixel(x, y - 1) + pixel(x, y + 1))
+                    + 4.0 * pixel(x, y);
+
+                noise_sum += noise.abs();
+            }
+        }
+
+        let inner = ((width - 2) * (height - 2)) as f64;
+        let mean = laplacian_sum / inner;
+        let total = (width * height) as f32;
+        let clipped = gray.pixels().filter(|p| p.0[0] >= 250).count() as f32;
+        let crushed = gray.pixels().filter(|p| p.0[0] <= 5).count() as f32;
+
+        QualityScores {
+            sharpness: (laplacian_square_sum / inner - mean * mean) as f32,
+            overexposed: clipped / total,
+            underexposed: crushed / total,
+            noise: (noise_sum * (std::f64::consts::FRAC_PI_2).sqrt() / (6.0 * inner)) as f32,
+        }
+    }
+
+    pub fn issues(&self) -> Vec<QualityIssue> {
+        let mut issues = vec![];
+
+        if self.sharpness < BLURRY_SHARPNESS {
+            issues.push(QualityIssue::Blurry);
+        }
+
+        if self.overexposed > OVEREXPOSED_RATIO {
+            issues.push(QualityIssue::Overexposed);
+        }
+
+        if self.underexposed > UNDEREXPOSED_RATIO {
+            issues.push(QualityIssue::Underexposed);
+        }
+
+        if self.noise > NOISY_SIGMA {
+            issues.push(QualityIssue::Noisy);
+        }
+
+        issues
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum QualitySort {
+    Sharpness,
+    Exposure,
+    Noise,
+}
+
+/// Filters and orders the images of a directory by their quality scores. The
+/// order is ascending, so the worst images come first, unless `desc` is set.
+#[derive(Debug, Default, Deserialize)]
+pub struct QualityFilter {
+    pub min_sharpness: Option<f32>,
+    pub max_noise: Option<f32>,
+    pub issue: Option<QualityIssue>,
+    pub sort: Option<QualitySort>,
+    #[serde(default)]
+    pub desc: bool,
+}
+
+impl QualityFilter {
+    pub fn matches(&self, scores: Option<&QualityScores>) -> bool {
+        let filtered =
+            self.min_sharpness.is_some() || self.max_noise.is_some() || self.issue.is_some();
+
+        let Some(scores) = scores else {
+            return !filtered;
+        };
+
+        self.min_sharpness.is_none_or(|s| scores.sharpness >= s)
+            && self.max_noise.is_none_or(|n| scores.noise <= n)
+            && self.issue.is_none_or(|i| scores.issues().contains(&i))
+    }
+
+    /// The value to order by, the images without scores come last.
+    pub fn sort_key(&self, scores: Option<&QualityScores>) -> Option<f32> {
+        let sort = self.sort?;
+        let scores = scores?;
+
+        let value = match sort {
+            QualitySort::Sharpness => scores.sharpness,
+            // The badly exposed and the noisy images have high values
+            QualitySort::Exposure => -(scores.overexposed + scores.underexposed),
+            QualitySort::Noise => -scores.noise,
+        };
+
+        Some(if self.desc { -value } else { value })
+    }
+}
@@ -0,0 +1,437 @@
+use std::{
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use image::{DynamicImage, ImageFormat, codecs::jpeg::JpegEncoder, metadata::Orientation};
+use log::debug;
+
+/// The embedded previews larger than this are ignored.
+const MAX_PREVIEW_SIZE: u32 = 64 * 1024 * 1024;
+
+const TAG_COMPRESSION: u16 = 0x0103;
+const TAG_STRIP_OFFSETS: u16 = 0x0111;
+const TAG_ORIENTATION: u16 = 0x0112;
+const TAG_STRIP_BYTE_COUNTS: u16 = 0x0117;
+const TAG_SUB_IFDS: u16 = 0x014A;
+const TAG_JPEG_OFFSET: u16 = 0x0201;
+const TAG_JPEG_LENGTH: u16 = 0x0202;
+
+/// The largest JPEG preview embedded in a TIFF based RAW file (CR2, NEF, ARW,
+/// DNG), with the orientation of the RAW file. The previews usually don't
+/// have an orientation of their own.
+pub struct RawPreview {
+    pub jpeg: Vec<u8>,
+    pub orientation: u16,
+}
+
+struct TiffReader<R> {
+    reader: R,
+    little_endian: bool,
+}
+
+struct IfdEntry {
+    tag: u16,
+    kind: u16,
+    count: u32,
+    value: u32,
+}
+
+impl RawPreview {
+    pub fn from_path(path: &Path) -> Option<RawPreview> {
+        let preview = RawPreview::from_tiff(BufReader::new(File::open(path).ok()?));
+
+        if let Some(preview) = &preview {
+            debug!("RAW preview of {path:?}: {} bytes", preview.jpeg.len());
+        }
+
+        preview
+    }
+
+    /// Reads the largest JPEG from TIFF structured data. The EXIF blocks have
+    /// the same structure, their thumbnail is found the same way.
+    pub fn from_tiff(reader: impl Read + Seek) -> Option<RawPreview> {
+        let mut tiff = TiffReader::open(reader)?;
+        let mut candidates = vec![];
+        let mut orientation = 1;
+
+        let first = tiff.read_u32()?;
+        tiff.collect(first, 0, &mut candidates, &mut orientation);
+
+        candidates.sort_by_key(|(_, length)| std::cmp::Reverse(*length));
+
+        for (offset, length) in candidates {
+            if length > MAX_PREVIEW_SIZE {
+                continue;
+            }
+
+            let mut jpeg = vec![0u8; length as usize];
+
+            tiff.reader.seek(SeekFrom::Start(offset as u64)).ok()?;
+
+            if tiff.reader.read_exact(&mut jpeg).is_err() || !is_decodable_jpeg(&jpeg) {
+                continue;
+            }
+
+            return Some(RawPreview { jpeg, orientation });
+        }
+
+        None
+    }
+
+    /// Decodes the preview and applies the orientation.
+    pub fn decode(&self) -> Option<DynamicImage> {
+        let mut img = image::load_from_memory_with_format(&self.jpeg, ImageFormat::Jpeg).ok()?;
+
+        if let Some(orientation) = Orientation::from_exif(self.orientation as u8) {
+            img.apply_orientation(orientation);
+        }
+
+        Some(img)
+    }
+
+    /// The preview as a JPEG which browsers show the right way up.
+    pub fn into_oriented_jpeg(self) -> Option<Vec<u8>> {
+        if self.orientation <= 1 {
+            return Some(self.jpeg);
+        }
+
+        let img = self.decode()?;
+        let mut jpeg = vec![];
+
+        JpegEncoder::new_with_quality(&mut jpeg, 90)
+            .encode_image(&img.to_rgb8())
+            .ok()?;
+
+        Some(jpeg)
+    }
+}
+
+impl<R: Read + Seek> TiffReader<R> {
+    fn open(mut reader: R) -> Option<Self> {
+        let mut header = [0u8; 4];
+        reader.read_exact(&mut header).ok()?;
+
+        let little_endian = match &header {
+            b"II*\0" => true,
+            b"MM\0*" => false,
+            _ => return None,
+        };
+
+        Some(TiffReader {
+            reader,
+            little_endian,
+        })
+    }
+
+    fn read_u16(&mut self) -> Option<u16> {
+        let mut bytes = [0u8; 2];
+        self.reader.read_exact(&mut bytes).ok()?;
+
+        Some(if self.little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    }
+
+    fn read_u32(&mut self) -> Option<u32> {
+        let mut bytes = [0u8; 4];
+        self.reader.read_exact(&mut bytes).ok()?;
+
+        Some(if self.little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    }
+
+    fn read_ifd(&mut self, offset: u32) -> Option<(Vec<IfdEntry>, u32)> {
+        self.reader.seek(SeekFrom::Start(offset as u64)).ok()?;
+
+        let count = self.read_u16()?;
+        let mut entries = Vec::with_capacity(count as usize);
+
+        for _ in 0..count {
+            let tag = self.read_u16()?;
+            let kind = self.read_u16()?;
+            let count = self.read_u32()?;
+
+            // The short values are left aligned in the value field
+            let value = if kind == 3 && count == 1 {
+                let value = self.read_u16()? as u32;
+                self.read_u16()?;
+                value
+            } else {
+                self.read_u32()?
+            };
+
+            entries.push(IfdEntry {
+                tag,
+                kind,
+                count,
+                value,
+            });
+        }
+
+        let next = self.read_u32()?;
+
+        Some((entries, next))
+    }
+
+    /// Collects the offsets and the lengths of the JPEG streams in the IFD
+    /// chain and in their sub IFDs.
+    fn collect(
+        &mut self,
+        mut offset: u32,
+        depth: u32,
+        candidates: &mut Vec<(u32, u32)>,
+        orientation: &mut u16,
+    ) {
+        // Guards against loops in broken files
+        let mut remaining = 16;
+
+        while offset != 0 && remaining > 0 {
+            let Some((entries, next)) = self.read_ifd(offset) else {
+                return;
+            };
+
+            let value = |tag: u16| entries.iter().find(|e| e.tag == tag && e.count == 1);
+
+            if let (Some(jpeg), Some(length)) = (value(TAG_JPEG_OFFSET), value(TAG_JPEG_LENGTH)) {
+                candidates.push((jpeg.value, length.value));
+            }
+
+            if let (Some(compression), Some(strip), Some(length)) = (
+                value(TAG_COMPRESSION),
+                value(TAG_STRIP_OFFSETS),
+                value(TAG_STRIP_BYTE_COUNTS),
+            ) && matches!(compression.value, 6 | 7)
+            {
+                candidates.push((strip.value, length.value));
+            }
+
+            if depth == 0
+                && let Some(o) = value(TAG_ORIENTATION)
+            {
+                *orientation = o.value as u16;
+            }
+
+            if depth < 2
+                && let Some(sub_ifds) = entries.iter().find(|e| e.tag == TAG_SUB_IFDS)
+            {
+                for sub_ifd in self.read_offsets(sub_ifds) {
+                    self.collect(sub_ifd, depth + 1, candidates, orientation);
+                }
+            }
+
+            // Only the main chain is followed
+            if depth > 0 {
+                return;
+            }
+
+            offset = next;
+            remaining -= 1;
+        }
+    }
+
+    fn read_offsets(&mut self, entry: &IfdEntry) -> Vec<u32> {
+        if entry.count == 1 || !matches!(entry.kind, 4 | 13) {
+            return vec![entry.value];
+        }
+
+        if self
+            .reader
+            .seek(SeekFrom::Start(entry.value as u64))
+            .is_err()
+        {
+            return vec![];
+        }
+
+        (0..entry.count.min(16))
+            .map_while(|_| self.read_u32())
+            .collect()
+    }
+}
+
+/// Checks that the data is a baseline or progressive JPEG. The RAW data itself
+/// is often a lossless JPEG, which cannot be decoded.
+fn is_decodable_jpeg(data: &[u8]) -> bool {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return false;
+    }
+
+    let mut pos = 2;
+
+    while pos + 4 <= data.len() {
+        if data[pos] != 0xFF {
+            return false;
+        }
+
+        let marker = data[pos + 1];
+
+        match marker {
+            0xC0..=0xC2 => return true,
+            0xC3 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF => return false,
+            0xDA => return false,
+            _ => {}
+        }
+
+        let length = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        pos += 2 + length;
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// The shortest data which passes as a baseline JPEG.
+    const JPEG: &[u8] = &[0xFF, 0xD8, 0xFF, 0xC0, 0x00, 0x02];
+
+    /// A little endian IFD of tag, type, count and value entries.
+    fn ifd(entries: &[(u16, u16, u32, u32)], next: u32) -> Vec<u8> {
+        let mut data = (entries.len() as u16).to_le_bytes().to_vec();
+
+        for (tag, kind, count, value) in entries {
+            data.extend(tag.to_le_bytes());
+            data.extend(kind.to_le_bytes());
+            data.extend(count.to_le_bytes());
+            data.extend(value.to_le_bytes());
+        }
+
+        data.extend(next.to_le_bytes());
+        data
+    }
+
+    /// The TIFF header with the first IFD right after it.
+    fn tiff(ifd: &[u8]) -> Vec<u8> {
+        let mut data = b"II*\0".to_vec();
+
+        data.extend(8u32.to_le_bytes());
+        data.extend(ifd);
+        data
+    }
+
+    fn preview(data: Vec<u8>) -> Option<RawPreview> {
+        RawPreview::from_tiff(Cursor::new(data))
+    }
+
+    #[test]
+    fn the_largest_jpeg_is_taken() {
+        let large = [JPEG, &[0; 10]].concat();
+        // The header, the IFD with 6 entries, then the two JPEGs
+        let data_start = 8 + 2 + 6 * 12 + 4;
+        let mut data = tiff(&ifd(
+            &[
+                (TAG_COMPRESSION, 3, 1, 6),
+                (TAG_STRIP_OFFSETS, 4, 1, data_start + JPEG.len() as u32),
+                (TAG_ORIENTATION, 3, 1, 6),
+                (TAG_STRIP_BYTE_COUNTS, 4, 1, large.len() as u32),
+                (TAG_JPEG_OFFSET, 4, 1, data_start),
+                (TAG_JPEG_LENGTH, 4, 1, JPEG.len() as u32),
+            ],
+            0,
+        ));
+
+        data.extend(JPEG);
+        data.extend(&large);
+
+        let preview = preview(data).unwrap();
+
+        assert_eq!(preview.jpeg, large);
+        assert_eq!(preview.orientation, 6);
+    }
+
+    #[test]
+    fn sub_ifds_are_read() {
+        let sub_ifd_offset = 8 + 2 + 12 + 4;
+        let data_start = sub_ifd_offset + 2 + 2 * 12 + 4;
+        let mut data = tiff(&ifd(&[(TAG_SUB_IFDS, 4, 1, sub_ifd_offset)], 0));
+
+        data.extend(ifd(
+            &[
+                (TAG_JPEG_OFFSET, 4, 1, data_start),
+                (TAG_JPEG_LENGTH, 4, 1, JPEG.len() as u32),
+            ],
+            0,
+        ));
+        data.extend(JPEG);
+
+        assert_eq!(preview(data).unwrap().jpeg, JPEG);
+    }
+
+    #[test]
+    fn a_loop_in_the_ifd_chain_ends() {
+        let data_start = 8 + 2 + 2 * 12 + 4;
+        // The next IFD is the same one
+        let mut data = tiff(&ifd(
+            &[
+                (TAG_JPEG_OFFSET, 4, 1, data_start),
+                (TAG_JPEG_LENGTH, 4, 1, JPEG.len() as u32),
+            ],
+            8,
+        ));
+
+        data.extend(JPEG);
+
+        assert_eq!(preview(data).unwrap().jpeg, JPEG);
+    }
+
+    #[test]
+    fn truncated_ifds_are_ignored() {
+        let mut data = tiff(&ifd(
+            &[(TAG_JPEG_OFFSET, 4, 1, 8), (TAG_JPEG_LENGTH, 4, 1, 6)],
+            0,
+        ));
+
+        // Cut in the middle of the second entry
+        data.truncate(8 + 2 + 12 + 5);
+
+        assert!(preview(data).is_none());
+        assert!(preview(b"II*\0\x08\0".to_vec()).is_none());
+        assert!(preview(b"II".to_vec()).is_none());
+    }
+
+    #[test]
+    fn offsets_past_the_end_are_skipped() {
+        let data = tiff(&ifd(
+            &[
+                (TAG_JPEG_OFFSET, 4, 1, 10_000),
+                (TAG_JPEG_LENGTH, 4, 1, JPEG.len() as u32),
+                (TAG_SUB_IFDS, 4, 1, 20_000),
+            ],
+            30_000,
+        ));
+
+        assert!(preview(data).is_none());
+    }
+
+    #[test]
+    fn huge_previews_are_skipped() {
+        let mut data = tiff(&ifd(
+            &[
+                (TAG_JPEG_OFFSET, 4, 1, 8 + 2 + 2 * 12 + 4),
+                (TAG_JPEG_LENGTH, 4, 1, u32::MAX),
+            ],
+            0,
+        ));
+
+        data.extend(JPEG);
+
+        assert!(preview(data).is_none());
+    }
+
+    #[test]
+    fn lossless_jpegs_are_not_previews() {
+        assert!(is_decodable_jpeg(JPEG));
+        assert!(!is_decodable_jpeg(&[0xFF, 0xD8, 0xFF, 0xC3, 0x00, 0x02]));
+        assert!(!is_decodable_jpeg(&[0xFF, 0xD8, 0xFF]));
+        assert!(!is_decodable_jpeg(b"II*\0"));
+    }
+}
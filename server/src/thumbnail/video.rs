@@ -0,0 +1,410 @@
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use image::{Rgb, RgbImage};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::thumbnail::{
+    bmff::{be_u32, be_u64, boxes, find_box, read_box_header},
+    capture::format_timestamp,
+};
+
+/// Seconds between 1904-01-01, the epoch of the MP4 times, and 1970-01-01.
+const MP4_EPOCH_OFFSET: i64 = 2_082_844_800;
+/// Seconds between 1970-01-01 and 2001-01-01, the epoch of the Matroska dates.
+const MATROSKA_EPOCH_OFFSET: i64 = 978_307_200;
+/// The metadata boxes are read into memory, larger ones are skipped.
+const MAX_BOX_SIZE: u64 = 64 * 1024 * 1024;
+
+/// The container metadata of a video.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct VideoInfo {
+    /// In seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+    pub width: u32,
+    pub height: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub codec: Option<String>,
+    /// As `YYYY-MM-DDTHH:MM:SS` in UTC.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<String>,
+}
+
+/// The result of reading a video: its metadata and the encoded poster image
+/// if the file has one which can be decoded.
+pub struct Video {
+    pub info: VideoInfo,
+    pub poster: Option<Vec<u8>>,
+}
+
+impl Video {
+    pub fn from_path(path: &Path) -> Option<Video> {
+        Video::read(path, path.extension()?)
+    }
+
+    /// Reads the file as a video in the container of the extension, the
+    /// uploads are checked before they get their name.
+    pub fn read(path: &Path, extension: &OsStr) -> Option<Video> {
+        let ext = extension.to_ascii_lowercase();
+        let mut reader = BufReader::new(File::open(path).ok()?);
+
+        let video = match ext.to_str()? {
+            "mp4" | "mov" | "m4v" => read_mp4(&mut reader),
+            "webm" | "mkv" => read_matroska(&mut reader),
+            _ => None,
+        };
+
+        debug!("Video {path:?}: {:?}", video.as_ref().map(|v| &v.info));
+
+        video
+    }
+
+    /// A generic poster for the videos without one: a play sign on a dark
+    /// background.
+    pub fn placeholder(width: u32, height: u32) -> RgbImage {
+        let (cx, cy) = (width as f32 / 2.0, height as f32 / 2.0);
+        let size = width.min(height) as f32 / 4.0;
+
+        RgbImage::from_fn(width, height, |x, y| {
+            let (dx, dy) = (x as f32 - cx + size / 3.0, y as f32 - cy);
+            let inside = dx >= 0.0 && dx <= size && dy.abs() <= (size - dx) / 2.0 * 1.2;
+
+            if inside {
+                Rgb([230, 230, 230])
+            } else {
+                Rgb([48, 48, 48])
+            }
+        })
+    }
+}
+
+/// Reads the `moov` box of an MP4 or QuickTime file, the media data is
+/// skipped.
+fn read_mp4(reader: &mut (impl Read + Seek)) -> Option<Video> {
+    let moov = loop {
+        let header = read_box_header(reader)?;
+        let size = header.size?;
+
+        if &header.kind == b"moov" && size <= MAX_BOX_SIZE {
+            let mut moov = vec![0u8; size as usize];
+            reader.read_exact(&mut moov).ok()?;
+
+            break moov;
+        }
+
+        reader.seek(SeekFrom::Current(size as i64)).ok()?;
+    };
+
+    let mut info = VideoInfo::default();
+
+    if let Some(mvhd) = find_box(&moov, &[b"mvhd"]) {
+        let (created, timescale, duration) = if mvhd.first() == Some(&1) {
+            (be_u64(mvhd, 4)?, be_u32(mvhd, 20)?, be_u64(mvhd, 24)?)
+        } else {
+            (
+                be_u32(mvhd, 4)? as u64,
+                be_u32(mvhd, 12)?,
+                be_u32(mvhd, 16)? as u64,
+            )
+        };
+
+        if timescale > 0 {
+            info.duration = Some(duration as f64 / timescale as f64);
+        }
+
+        if created > 0 {
+            info.created_at = Some(format_timestamp(created as i64 - MP4_EPOCH_OFFSET));
+        }
+    }
+
+    let video_track = boxes(&moov)
+        .filter(|(kind, _)| kind == b"trak")
+        .map(|(_, trak)| trak)
+        .find(|trak| {
+            find_box(trak, &[b"mdia", b"hdlr"]).and_then(|hdlr| hdlr.get(8..12))
+                == Some(b"vide".as_slice())
+        });
+
+    let mut poster = None;
+
+    if let Some(trak) = video_track {
+        if let Some(tkhd) = find_box(trak, &[b"tkhd"]) {
+            let offset = if tkhd.first() == Some(&1) { 36 } else { 24 };
+            // The matrix follows the reserved fields, layer, group and volume
+            let matrix = offset + 16;
+            let width = be_u32(tkhd, matrix + 36)? >> 16;
+            let height = be_u32(tkhd, matrix + 40)? >> 16;
+            // Phone videos are rotated by the matrix
+            let rotated = be_u32(tkhd, matrix)? == 0;
+
+            (info.width, info.height) = if rotated {
+                (height, width)
+            } else {
+                (width, height)
+            };
+        }
+
+        if let Some(stbl) = find_box(trak, &[b"mdia", b"minf", b"stbl"]) {
+            let codec = find_box(stbl, &[b"stsd"])
+                .and_then(|stsd| stsd.get(12..16))
+                .map(|c| String::from_utf8_lossy(c).trim().to_owned());
+
+            // Motion JPEG frames can be decoded, they are used as poster
+            if matches!(codec.as_deref(), Some("jpeg" | "mjpa" | "mjpb")) {
+                poster = first_sample(stbl).and_then(|(offset, size)| {
+                    reader.seek(SeekFrom::Start(offset)).ok()?;
+
+                    let mut frame = vec![0u8; size as usize];
+                    reader.read_exact(&mut frame).ok()?;
+
+                    Some(frame)
+                });
+            }
+
+            info.codec = codec;
+        }
+    }
+
+    // The cover art of the file has priority over the first frame
+    if let Some(cover) = read_mp4_cover(&moov) {
+        poster = Some(cover);
+    }
+
+    Some(Video { info, poster })
+}
+
+/// The file offset and the size of the first sample of a track.
+fn first_sample(stbl: &[u8]) -> Option<(u64, u64)> {
+    let offset = match find_box(stbl, &[b"stco"]) {
+        Some(stco) => be_u32(stco, 8)? as u64,
+        None => be_u64(find_box(stbl, &[b"co64"])?, 8)?,
+    };
+
+    let stsz = find_box(stbl, &[b"stsz"])?;
+    let size = match be_u32(stsz, 4)? {
+        0 => be_u32(stsz, 12)?,
+        size => size,
+    } as u64;
+
+    (size <= MAX_BOX_SIZE).then_some((offset, size))
+}
+
+/// The `covr` item of the iTunes style metadata.
+fn read_mp4_cover(moov: &[u8]) -> Option<Vec<u8>> {
+    let meta = find_box(moov, &[b"udta", b"meta"])?;
+
+    // The meta box is a full box in MP4 but not in QuickTime files
+    let meta = if meta.get(4..8) == Some(b"hdlr".as_slice()) {
+        meta
+    } else {
+        meta.get(4..)?
+    };
+
+    let data = find_box(meta, &[b"ilst", b"covr", b"data"])?;
+
+    // The type indicator and the locale precede the image
+    Some(data.get(8..)?.to_vec())
+}
+
+mod ebml {
+    pub const HEADER: u64 = 0x1A45DFA3;
+    pub const SEGMENT: u64 = 0x18538067;
+    pub const INFO: u64 = 0x1549A966;
+    pub const TIMECODE_SCALE: u64 = 0x2AD7B1;
+    pub const DURATION: u64 = 0x4489;
+    pub const DATE_UTC: u64 = 0x4461;
+    pub const TRACKS: u64 = 0x1654AE6B;
+    pub const TRACK_ENTRY: u64 = 0xAE;
+    pub const TRACK_TYPE: u64 = 0x83;
+    pub const CODEC_ID: u64 = 0x86;
+    pub const VIDEO: u64 = 0xE0;
+    pub const PIXEL_WIDTH: u64 = 0xB0;
+    pub const PIXEL_HEIGHT: u64 = 0xBA;
+    pub const ATTACHMENTS: u64 = 0x1941A469;
+    pub const ATTACHED_FILE: u64 = 0x61A7;
+    pub const FILE_MIME_TYPE: u64 = 0x4660;
+    pub const FILE_DATA: u64 = 0x465C;
+    pub const CLUSTER: u64 = 0x1F43B675;
+}
+
+/// Reads a variable length integer, the ids keep their length marker.
+fn read_vint(reader: &mut impl Read, keep_marker: bool) -> Option<(u64, bool)> {
+    let mut first = [0u8; 1];
+    reader.read_exact(&mut first).ok()?;
+
+    let length = first[0].leading_zeros() as usize + 1;
+
+    if length > 8 {
+        return None;
+    }
+
+    let mut value = if keep_marker {
+        first[0] as u64
+    } else {
+        (first[0] & (0xFF >> length)) as u64
+    };
+    let mut all_ones = value == (0xFF >> length) as u64;
+
+    for _ in 1..length {
+        reader.read_exact(&mut first).ok()?;
+
+        value = (value << 8) | first[0] as u64;
+        all_ones &= first[0] == 0xFF;
+    }
+
+    Some((value, all_ones))
+}
+
+/// Reads the id and the size of an element, the size is `None` if it is
+/// unknown.
+fn read_element(reader: &mut impl Read) -> Option<(u64, Option<u64>)> {
+    let (id, _) = read_vint(reader, true)?;
+    let (size, unknown) = read_vint(reader, false)?;
+
+    Some((id, (!unknown).then_some(size)))
+}
+
+fn read_uint(data: &[u8]) -> u64 {
+    data.iter().fold(0, |value, b| (value << 8) | *b as u64)
+}
+
+/// Iterates over the elements in a buffer.
+fn elements(mut data: &[u8]) -> impl Iterator<Item = (u64, &[u8])> {
+    std::iter::from_fn(move || {
+        let (id, size) = read_element(&mut data)?;
+        let size = (size.unwrap_or(data.len() as u64) as usize).min(data.len());
+        let (content, rest) = data.split_at(size);
+
+        data = rest;
+
+        Some((id, content))
+    })
+}
+
+/// Reads the segment info, the tracks and the attachments of a WebM or
+/// Matroska file until the first cluster.
+fn read_matroska(reader: &mut (impl Read + Seek)) -> Option<Video> {
+    let (id, size) = read_element(reader)?;
+
+    if id != ebml::HEADER {
+        return None;
+    }
+
+    reader.seek(SeekFrom::Current(size? as i64)).ok()?;
+
+    let (id, _) = read_element(reader)?;
+
+    if id != ebml::SEGMENT {
+        return None;
+    }
+
+    let mut info = VideoInfo::default();
+    let mut poster = None;
+
+    while let Some((id, size)) = read_element(reader) {
+        match (id, size) {
+            (ebml::CLUSTER, _) | (_, None) => break,
+            (ebml::INFO | ebml::TRACKS | ebml::ATTACHMENTS, Some(size)) if size <= MAX_BOX_SIZE => {
+                let mut data = vec![0u8; size as usize];
+                reader.read_exact(&mut data).ok()?;
+
+                match id {
+                    ebml::INFO => read_matroska_info(&data, &mut info),
+                    ebml::TRACKS => read_matroska_tracks(&data, &mut info),
+                    _ => poster = read_matroska_cover(&data),
+                }
+            }
+            (_, Some(size)) => {
+                reader.seek(SeekFrom::Current(size as i64)).ok()?;
+            }
+        }
+    }
+
+    Some(Video { info, poster })
+}
+
+fn read_matroska_info(data: &[u8], info: &mut VideoInfo) {
+    let mut scale = 1_000_000u64;
+    let mut duration = None;
+
+    for (id, content) in elements(data) {
+        match id {
+            ebml::TIMECODE_SCALE => scale = read_uint(content),
+            ebml::DURATION => {
+                duration = match content.len() {
+                    4 => Some(f32::from_be_bytes(content.try_into().unwrap()) as f64),
+                    8 => Some(f64::from_be_bytes(content.try_into().unwrap())),
+                    _ => None,
+                }
+            }
+            ebml::DATE_UTC if content.len() == 8 => {
+                let nanos = i64::from_be_bytes(content.try_into().unwrap());
+
+                info.created_at = Some(format_timestamp(
+                    nanos.div_euclid(1_000_000_000) + MATROSKA_EPOCH_OFFSET,
+                ));
+            }
+            _ => {}
+        }
+    }
+
+    info.duration = duration.map(|d| d * scale as f64 / 1e9);
+}
+
+fn read_matroska_tracks(data: &[u8], info: &mut VideoInfo) {
+    for (_, entry) in elements(data).filter(|(id, _)| *id == ebml::TRACK_ENTRY) {
+        let is_video = elements(entry).any(|(id, c)| id == ebml::TRACK_TYPE && read_uint(c) == 1);
+
+        if !is_video {
+            continue;
+        }
+
+        for (id, content) in elements(entry) {
+            match id {
+                ebml::CODEC_ID => {
+                    info.codec = Some(String::from_utf8_lossy(content).into_owned());
+                }
+                ebml::VIDEO => {
+                    for (id, content) in elements(content) {
+                        match id {
+                            ebml::PIXEL_WIDTH => info.width = read_uint(content) as u32,
+                            ebml::PIXEL_HEIGHT => info.height = read_uint(content) as u32,
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        return;
+    }
+}
+
+/// The first attached JPEG or PNG image, usually the cover art.
+fn read_matroska_cover(data: &[u8]) -> Option<Vec<u8>> {
+    elements(data)
+        .filter(|(id, _)| *id == ebml::ATTACHED_FILE)
+        .find_map(|(_, file)| {
+            let mime = elements(file)
+                .find(|(id, _)| *id == ebml::FILE_MIME_TYPE)?
+                .1;
+
+            if !matches!(mime, b"image/jpeg" | b"image/png") {
+                return None;
+            }
+
+            Some(
+                elements(file)
+                    .find(|(id, _)| *id == ebml::FILE_DATA)?
+                    .1
+                    .to_vec(),
+            )
+        })
+}